@@ -1,6 +1,6 @@
 use crate::types::StorePrefix;
 use alloy_primitives::{Address, U256};
-use alloy_rlp::{Decodable, Encodable, RlpDecodable, RlpEncodable};
+use alloy_rlp::{Encodable, Error, RlpDecodable, RlpEncodable};
 use iavl::KVStore;
 
 #[derive(Debug, Default, Clone, PartialEq, RlpEncodable, RlpDecodable)]
@@ -57,18 +57,40 @@ pub fn save_account(kv: &mut impl KVStore, address: &Address, value: &AccountVal
     kv.set(key, buf)
 }
 
-pub fn load_account(kv: &impl KVStore, address: &Address) -> Option<AccountValue> {
-    let key = store_key(address);
-    let mut bz = kv.get(&key)?;
-    AccountValue::decode(&mut bz).ok()
+// load_account returns `None` if the account don't exist, fails if the stored value is malformed.
+pub fn load_account(kv: &impl KVStore, address: &Address) -> Result<Option<AccountValue>, Error> {
+    kv.get(&store_key(address))
+        .map(alloy_rlp::decode_exact)
+        .transpose()
 }
 
-pub fn load_or_default(kv: &impl KVStore, address: Address) -> Account {
-    let value = load_account(kv, &address).unwrap_or_default();
-    Account {
+// load_accounts loads the accounts with one pass over the sorted keys, a tree descends once for
+// the keys sharing a path, the results are in the order of the addresses.
+pub fn load_accounts(
+    kv: &impl KVStore,
+    addresses: &[Address],
+) -> Result<Vec<Option<AccountValue>>, Error> {
+    let keys = addresses.iter().map(store_key).collect::<Vec<_>>();
+    let mut order = (0..keys.len()).collect::<Vec<_>>();
+    order.sort_by(|&a, &b| keys[a].cmp(&keys[b]));
+    let sorted = order
+        .iter()
+        .map(|&i| keys[i].as_slice())
+        .collect::<Vec<_>>();
+
+    let mut accounts = vec![None; keys.len()];
+    for (i, value) in order.into_iter().zip(kv.get_sorted(&sorted)) {
+        accounts[i] = value.map(alloy_rlp::decode_exact).transpose()?;
+    }
+    Ok(accounts)
+}
+
+pub fn load_or_default(kv: &impl KVStore, address: Address) -> Result<Account, Error> {
+    let value = load_account(kv, &address)?.unwrap_or_default();
+    Ok(Account {
         address,
         inner: value,
-    }
+    })
 }
 
 pub fn check_and_incr_nonce(
@@ -76,7 +98,7 @@ pub fn check_and_incr_nonce(
     address: &Address,
     exp_nonce: u64,
 ) -> Option<()> {
-    let mut account = load_account(kv, address).unwrap_or_default();
+    let mut account = load_account(kv, address).ok()?.unwrap_or_default();
     if account.nonce != exp_nonce {
         return None;
     }
@@ -91,7 +113,7 @@ pub fn modify_native_balance(
     address: &Address,
     mod_fn: impl FnOnce(U256) -> Option<U256>,
 ) -> Option<()> {
-    let mut account = load_account(kv, address).unwrap_or_default();
+    let mut account = load_account(kv, address).ok()?.unwrap_or_default();
     account.modify_balance(mod_fn)?;
     save_account(kv, address, &account);
     Some(())
@@ -120,10 +142,61 @@ mod test {
         let mut account = AccountValue::default();
 
         // test empty account
-        assert_eq!(load_account(&kv, &address).unwrap_or_default(), account);
+        assert_eq!(
+            load_account(&kv, &address).unwrap().unwrap_or_default(),
+            account
+        );
 
         account.nonce = 1;
         save_account(&mut kv, &address, &account);
-        assert_eq!(load_account(&kv, &address).unwrap_or_default(), account);
+        assert_eq!(
+            load_account(&kv, &address).unwrap().unwrap_or_default(),
+            account
+        );
+    }
+
+    #[test]
+    fn test_malformed_account() {
+        let mut kv = IAVLTree::default();
+        let address = Address::from(U160::from(0x1234));
+        kv.set(store_key(&address), b"garbage".to_vec());
+
+        assert!(load_account(&kv, &address).is_err());
+        assert!(load_or_default(&kv, address).is_err());
+        assert!(check_and_incr_nonce(&mut kv, &address, 0).is_none());
+        assert!(modify_native_balance(&mut kv, &address, |_| Some(U256::from(1))).is_none());
+        assert_eq!(kv.get(&store_key(&address)), Some(b"garbage" as &[u8]));
+    }
+
+    #[test]
+    fn test_load_accounts() {
+        let mut kv = IAVLTree::default();
+        let addresses = (0u64..100)
+            .map(|i| Address::from(U160::from(i * 7919 % 1000)))
+            .collect::<Vec<_>>();
+        for (i, address) in addresses.iter().enumerate().step_by(3) {
+            let account = AccountValue {
+                nonce: i as u64,
+                balance: U256::from(i),
+            };
+            save_account(&mut kv, address, &account);
+        }
+        // an account not requested in the middle of the range
+        save_account(
+            &mut kv,
+            &Address::from(U160::from(500)),
+            &AccountValue::default(),
+        );
+
+        // the duplicated addresses get the same result
+        let mut requested = addresses.clone();
+        requested.push(addresses[3]);
+        let accounts = load_accounts(&kv, &requested).unwrap();
+        assert_eq!(accounts.len(), requested.len());
+        for (address, account) in requested.iter().zip(&accounts) {
+            assert_eq!(account, &load_account(&kv, address).unwrap());
+        }
+        assert_eq!(accounts.iter().flatten().count(), 35);
+        assert!(load_accounts(&kv, &[]).unwrap().is_empty());
     }
 }
@@ -1,7 +1,7 @@
 use crate::types::StorePrefix;
-use alloy_primitives::{Address, U256};
-use alloy_rlp::{Decodable, Encodable};
-use iavl::KVStore;
+use alloy_primitives::{Address, I256, U256};
+use alloy_rlp::{Encodable, Error};
+use iavl::{KVStore, KVStoreExt, Overlay};
 
 pub fn store_key(address: &Address, denom: &str) -> Vec<u8> {
     let mut buf = Vec::new();
@@ -11,20 +11,90 @@ pub fn store_key(address: &Address, denom: &str) -> Vec<u8> {
     buf
 }
 
-pub fn get_balance(kv: &impl KVStore, address: &Address, denom: &str) -> U256 {
-    let key = store_key(address, denom);
-    if let Some(mut bz) = kv.get(&key) {
-        U256::decode(&mut bz).unwrap()
-    } else {
-        U256::from(0)
+// get_balance returns the balance of the denom, zero if not set, fails if the stored value is
+// malformed.
+pub fn get_balance(kv: &impl KVStore, address: &Address, denom: &str) -> Result<U256, Error> {
+    decode_amount(kv.get(&store_key(address, denom)))
+}
+
+// denoms_of returns the balances of all the denoms held by the address, sorted by denom,
+// the address is fixed length in the key, so the denom is the rest of it.
+pub fn denoms_of(kv: &impl KVStoreExt, address: &Address) -> Result<Vec<(String, U256)>, Error> {
+    let prefix = store_key(address, "");
+    kv.prefix_iter(&prefix)
+        .map(|(key, bz)| {
+            let denom = String::from_utf8(key[prefix.len()..].to_vec())
+                .map_err(|_| Error::Custom("invalid denom"))?;
+            Ok((denom, decode_amount(Some(bz))?))
+        })
+        .collect()
+}
+
+// set_balance updates the balance, and the total supply of the denom by the difference, fails if
+// the stored balance or supply is malformed, or the supply is less than the old balance, which is
+// the case for the balances written without updating the supply, or it overflows.
+pub fn set_balance(
+    kv: &mut impl KVStore,
+    address: &Address,
+    denom: &str,
+    amount: U256,
+) -> Result<(), Error> {
+    let old = get_balance(kv, address, denom)?;
+    let supply = total_supply(kv, denom)?
+        .checked_sub(old)
+        .ok_or(Error::Custom("total supply less than the balance"))?
+        .checked_add(amount)
+        .ok_or(Error::Custom("total supply overflow"))?;
+    kv.set(supply_key(denom), encode_amount(supply));
+    kv.set(store_key(address, denom), encode_amount(amount));
+    Ok(())
+}
+
+// decode_amount decodes the stored amount, `None` is zero, the trailing bytes are rejected.
+fn decode_amount(bz: Option<&[u8]>) -> Result<U256, Error> {
+    match bz {
+        Some(bz) => alloy_rlp::decode_exact(bz),
+        None => Ok(U256::ZERO),
     }
 }
 
-pub fn set_balance(kv: &mut impl KVStore, address: &Address, denom: &str, amount: U256) {
+fn encode_amount(amount: U256) -> Vec<u8> {
     let mut buf = Vec::new();
     amount.encode(&mut buf);
-    let key = store_key(address, denom);
-    kv.set(key, buf)
+    buf
+}
+
+pub fn supply_key(denom: &str) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.push(StorePrefix::Supply as u8);
+    buf.extend_from_slice(denom.as_bytes());
+    buf
+}
+
+// total_supply returns the sum of the balances of the denom across all the accounts.
+pub fn total_supply(kv: &impl KVStore, denom: &str) -> Result<U256, Error> {
+    decode_amount(kv.get(&supply_key(denom)))
+}
+
+// mint creates new coins in the account, fails if the total supply overflows.
+pub fn mint(kv: &mut impl KVStore, address: &Address, denom: &str, amount: U256) -> Option<()> {
+    total_supply(kv, denom).ok()?.checked_add(amount)?;
+    mod_balance(kv, address, denom, |balance| balance.checked_add(amount))
+}
+
+// burn destroys coins from the account, fails if the balance is insufficient.
+pub fn burn(kv: &mut impl KVStore, address: &Address, denom: &str, amount: U256) -> Option<()> {
+    mod_balance(kv, address, denom, |balance| balance.checked_sub(amount))
+}
+
+// add_delta applies the signed delta to the balance, fails if the balance would go negative, or
+// the total supply overflows.
+pub fn add_delta(kv: &mut impl KVStore, address: &Address, denom: &str, delta: I256) -> Option<()> {
+    if delta.is_negative() {
+        burn(kv, address, denom, delta.unsigned_abs())
+    } else {
+        mint(kv, address, denom, delta.unsigned_abs())
+    }
 }
 
 pub fn mod_balance(
@@ -33,12 +103,12 @@ pub fn mod_balance(
     denom: &str,
     mod_fn: impl FnOnce(U256) -> Option<U256>,
 ) -> Option<()> {
-    let balance = get_balance(kv, address, denom);
+    let balance = get_balance(kv, address, denom).ok()?;
     let balance = mod_fn(balance)?;
-    set_balance(kv, address, denom, balance);
-    Some(())
+    set_balance(kv, address, denom, balance).ok()
 }
 
+// transfer moves the amount between the accounts, nothing is changed if it fails.
 pub fn transfer(
     kv: &mut impl KVStore,
     from: &Address,
@@ -46,13 +116,27 @@ pub fn transfer(
     denom: &str,
     amount: U256,
 ) -> Option<()> {
-    let from_balance = get_balance(kv, from, denom);
-    if from_balance < amount {
-        return None;
+    let mut buffer = Overlay::new(kv);
+    let from_balance = get_balance(&buffer, from, denom).ok()?;
+    set_balance(&mut buffer, from, denom, from_balance.checked_sub(amount)?).ok()?;
+    let to_balance = get_balance(&buffer, to, denom).ok()?;
+    set_balance(&mut buffer, to, denom, to_balance.checked_add(amount)?).ok()?;
+    buffer.flush();
+    Some(())
+}
+
+// transfer_multi transfers multiple denoms atomically, nothing is changed if any of them fails.
+pub fn transfer_multi(
+    kv: &mut impl KVStore,
+    from: &Address,
+    to: &Address,
+    coins: &[(String, U256)],
+) -> Option<()> {
+    let mut buffer = Overlay::new(kv);
+    for (denom, amount) in coins {
+        transfer(&mut buffer, from, to, denom, *amount)?;
     }
-    set_balance(kv, from, denom, from_balance - amount);
-    let to_balance = get_balance(kv, to, denom);
-    set_balance(kv, to, denom, to_balance + amount);
+    buffer.flush();
     Some(())
 }
 
@@ -70,7 +154,158 @@ mod test {
         let denom = "atom";
         let amount = U256::from(100);
 
-        set_balance(&mut kv, &address, denom, amount);
-        assert_eq!(get_balance(&kv, &address, denom), amount);
+        set_balance(&mut kv, &address, denom, amount).unwrap();
+        assert_eq!(get_balance(&kv, &address, denom).unwrap(), amount);
+    }
+
+    #[test]
+    fn test_denoms_of() {
+        let mut kv = IAVLTree::default();
+        let address = Address::from(U160::from(0x1234));
+        let other = Address::from(U160::from(0x1235));
+        set_balance(&mut kv, &address, "eth", U256::from(1)).unwrap();
+        set_balance(&mut kv, &address, "atom", U256::from(2)).unwrap();
+        set_balance(&mut kv, &address, "atom2", U256::from(3)).unwrap();
+        set_balance(&mut kv, &other, "atom", U256::from(4)).unwrap();
+
+        assert_eq!(
+            denoms_of(&kv, &address).unwrap(),
+            vec![
+                ("atom".to_string(), U256::from(2)),
+                ("atom2".to_string(), U256::from(3)),
+                ("eth".to_string(), U256::from(1)),
+            ]
+        );
+        assert_eq!(
+            denoms_of(&kv, &other).unwrap(),
+            vec![("atom".to_string(), U256::from(4))]
+        );
+        assert!(denoms_of(&kv, &Address::ZERO).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_total_supply() {
+        let mut kv = IAVLTree::default();
+        let alice = Address::from(U160::from(0x1234));
+        let bob = Address::from(U160::from(0x5678));
+        assert_eq!(total_supply(&kv, "atom").unwrap(), U256::ZERO);
+
+        mint(&mut kv, &alice, "atom", U256::from(100)).unwrap();
+        mint(&mut kv, &bob, "atom", U256::from(50)).unwrap();
+        mint(&mut kv, &bob, "eth", U256::from(7)).unwrap();
+        assert_eq!(total_supply(&kv, "atom").unwrap(), U256::from(150));
+        assert_eq!(total_supply(&kv, "eth").unwrap(), U256::from(7));
+
+        transfer(&mut kv, &alice, &bob, "atom", U256::from(30)).unwrap();
+        assert_eq!(get_balance(&kv, &bob, "atom").unwrap(), U256::from(80));
+        assert_eq!(total_supply(&kv, "atom").unwrap(), U256::from(150));
+
+        burn(&mut kv, &bob, "atom", U256::from(20)).unwrap();
+        assert_eq!(total_supply(&kv, "atom").unwrap(), U256::from(130));
+
+        // insufficient balance
+        assert!(burn(&mut kv, &alice, "atom", U256::from(71)).is_none());
+        // supply overflow
+        assert!(mint(&mut kv, &alice, "atom", U256::MAX).is_none());
+        assert_eq!(total_supply(&kv, "atom").unwrap(), U256::from(130));
+        assert_eq!(total_supply(&kv, "eth").unwrap(), U256::from(7));
+
+        // the supply entries don't show up as balances
+        assert_eq!(denoms_of(&kv, &bob).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_add_delta() {
+        let mut kv = IAVLTree::default();
+        let address = Address::from(U160::from(0x1234));
+        let delta = |i: i64| I256::try_from(i).unwrap();
+
+        add_delta(&mut kv, &address, "atom", delta(100)).unwrap();
+        assert_eq!(get_balance(&kv, &address, "atom").unwrap(), U256::from(100));
+        assert_eq!(total_supply(&kv, "atom").unwrap(), U256::from(100));
+
+        // underflow leaves the balance unchanged
+        assert!(add_delta(&mut kv, &address, "atom", delta(-101)).is_none());
+        assert_eq!(get_balance(&kv, &address, "atom").unwrap(), U256::from(100));
+        assert!(add_delta(&mut kv, &address, "atom", I256::MIN).is_none());
+
+        add_delta(&mut kv, &address, "atom", delta(-100)).unwrap();
+        assert_eq!(get_balance(&kv, &address, "atom").unwrap(), U256::ZERO);
+        assert_eq!(total_supply(&kv, "atom").unwrap(), U256::ZERO);
+    }
+
+    #[test]
+    fn test_transfer_multi() {
+        let mut kv = IAVLTree::default();
+        let from = Address::from(U160::from(0x1234));
+        let to = Address::from(U160::from(0x5678));
+        set_balance(&mut kv, &from, "atom", U256::from(100)).unwrap();
+        set_balance(&mut kv, &from, "eth", U256::from(10)).unwrap();
+
+        // insufficient balance of the second denom
+        let coins = [
+            ("atom".to_string(), U256::from(50)),
+            ("eth".to_string(), U256::from(20)),
+        ];
+        assert!(transfer_multi(&mut kv, &from, &to, &coins).is_none());
+        assert_eq!(get_balance(&kv, &from, "atom").unwrap(), U256::from(100));
+        assert_eq!(get_balance(&kv, &to, "atom").unwrap(), U256::ZERO);
+        assert_eq!(get_balance(&kv, &from, "eth").unwrap(), U256::from(10));
+
+        let coins = [
+            ("atom".to_string(), U256::from(50)),
+            ("eth".to_string(), U256::from(10)),
+        ];
+        assert!(transfer_multi(&mut kv, &from, &to, &coins).is_some());
+        assert_eq!(get_balance(&kv, &from, "atom").unwrap(), U256::from(50));
+        assert_eq!(get_balance(&kv, &to, "atom").unwrap(), U256::from(50));
+        assert_eq!(get_balance(&kv, &from, "eth").unwrap(), U256::ZERO);
+        assert_eq!(get_balance(&kv, &to, "eth").unwrap(), U256::from(10));
+    }
+
+    #[test]
+    fn test_missing_supply() {
+        let mut kv = IAVLTree::default();
+        let alice = Address::from(U160::from(0x1234));
+        let bob = Address::from(U160::from(0x5678));
+        // the balance written without the supply index
+        kv.set(store_key(&alice, "atom"), encode_amount(U256::from(50)));
+        mint(&mut kv, &bob, "atom", U256::from(10)).unwrap();
+
+        assert!(set_balance(&mut kv, &alice, "atom", U256::from(10)).is_err());
+        assert!(transfer(&mut kv, &alice, &bob, "atom", U256::from(5)).is_none());
+        assert!(transfer(&mut kv, &bob, &alice, "atom", U256::from(5)).is_none());
+        assert_eq!(get_balance(&kv, &alice, "atom").unwrap(), U256::from(50));
+        assert_eq!(get_balance(&kv, &bob, "atom").unwrap(), U256::from(10));
+        assert_eq!(total_supply(&kv, "atom").unwrap(), U256::from(10));
+
+        // the supply overflow is an error
+        assert!(set_balance(&mut kv, &alice, "eth", U256::from(1)).is_ok());
+        assert!(set_balance(&mut kv, &bob, "eth", U256::MAX).is_err());
+        assert_eq!(get_balance(&kv, &bob, "eth").unwrap(), U256::ZERO);
+        assert_eq!(total_supply(&kv, "eth").unwrap(), U256::from(1));
+    }
+
+    #[test]
+    fn test_malformed_balance() {
+        let mut kv = IAVLTree::default();
+        let alice = Address::from(U160::from(0x1234));
+        let bob = Address::from(U160::from(0x5678));
+        mint(&mut kv, &alice, "atom", U256::from(100)).unwrap();
+        kv.set(store_key(&bob, "atom"), b"garbage".to_vec());
+
+        assert!(get_balance(&kv, &bob, "atom").is_err());
+        assert!(denoms_of(&kv, &bob).is_err());
+        assert!(set_balance(&mut kv, &bob, "atom", U256::from(1)).is_err());
+        assert!(mint(&mut kv, &bob, "atom", U256::from(1)).is_none());
+
+        // nothing is changed if the recipient's balance is malformed
+        assert!(transfer(&mut kv, &alice, &bob, "atom", U256::from(10)).is_none());
+        assert_eq!(get_balance(&kv, &alice, "atom").unwrap(), U256::from(100));
+        assert_eq!(total_supply(&kv, "atom").unwrap(), U256::from(100));
+
+        kv.set(supply_key("atom"), vec![]);
+        assert!(total_supply(&kv, "atom").is_err());
+        assert!(burn(&mut kv, &alice, "atom", U256::from(1)).is_none());
     }
 }
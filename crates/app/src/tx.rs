@@ -1,60 +1,336 @@
 use alloy_consensus::TxEnvelope;
-use alloy_primitives::{Address, U256};
-use iavl::{KVStore, Overlay};
+use alloy_primitives::{Address, Bytes, TxKind, U256};
+use crypto_common::Output;
+use iavl::{DynIterator, IAVLTree, KVStore, Overlay};
+use sha2::Sha256;
+use std::collections::BTreeMap;
+use std::ops::Bound;
 
-use crate::auth;
+use crate::auth::{self, AccountValue};
 
-const CHAIN_ID: u64 = 1;
+// ExecConfig is the chain parameters used in the execution.
+#[derive(Debug, Clone)]
+pub struct ExecConfig {
+    // the transactions signed for other chains are rejected
+    pub chain_id: u64,
+    // base fee per gas of the block, it's burned from the fee
+    pub base_fee: u128,
+}
+
+impl ExecConfig {
+    pub fn new(chain_id: u64) -> Self {
+        Self {
+            chain_id,
+            base_fee: 0,
+        }
+    }
+}
+
+// TxError is the reason of a failed transaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxError {
+    // only legacy and EIP-1559 transactions are supported
+    UnsupportedType,
+    BadSignature,
+    WrongChainId,
+    // the max fee is below the base fee, or below the max priority fee
+    FeeTooLow,
+    // the gas limit don't cover the intrinsic gas
+    IntrinsicGasTooLow,
+    BadNonce,
+    InsufficientBalance,
+    // transfer value to contract creation
+    InvalidRecipient,
+    Overflow,
+    // the stored account of the sender or the recipient can't be decoded
+    CorruptedAccount,
+}
+
+// Event is a state change made by a successful transaction, for the indexers to build the receipts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    NonceIncremented {
+        address: Address,
+        nonce: u64,
+    },
+    // the fee of the used gas paid by the sender, the base fee portion of it is burned
+    FeeDeducted {
+        payer: Address,
+        fee: U256,
+        burned: U256,
+    },
+    Transfer {
+        from: Address,
+        to: Address,
+        amount: U256,
+    },
+}
 
-// execute_tx returns deducted fee, which should be credits to the block miner
-pub fn execute_tx(kv: &mut impl KVStore, tx: &TxEnvelope) -> Option<U256> {
-    let legacy = tx.as_legacy()?;
-    let sender = legacy.recover_signer().ok()?;
-    let tx = legacy.tx();
+// TxResult is the outcome of a transaction, the fee credited to the miner on success.
+pub type TxResult = Result<U256, TxError>;
+
+// intrinsic gas of a transaction, the calldata costs are from EIP-2028
+const TX_GAS: u64 = 21000;
+const TX_DATA_ZERO_GAS: u64 = 4;
+const TX_DATA_NON_ZERO_GAS: u64 = 16;
+
+// TxFields are the fields of the supported transaction types used in the execution,
+// the gas price of a legacy transaction is both the max fee and the max priority fee.
+struct TxFields {
+    sender: Address,
+    chain_id: Option<u64>,
+    nonce: u64,
+    gas_limit: u64,
+    max_fee_per_gas: u128,
+    max_priority_fee_per_gas: u128,
+    to: TxKind,
+    value: U256,
+    input: Bytes,
+}
+
+fn tx_fields(tx: &TxEnvelope) -> Result<TxFields, TxError> {
+    match tx {
+        TxEnvelope::Legacy(signed) => {
+            let tx = signed.tx();
+            Ok(TxFields {
+                sender: signed.recover_signer().map_err(|_| TxError::BadSignature)?,
+                chain_id: tx.chain_id,
+                nonce: tx.nonce,
+                gas_limit: tx.gas_limit,
+                max_fee_per_gas: tx.gas_price,
+                max_priority_fee_per_gas: tx.gas_price,
+                to: tx.to,
+                value: tx.value,
+                input: tx.input.clone(),
+            })
+        }
+        TxEnvelope::Eip1559(signed) => {
+            let tx = signed.tx();
+            Ok(TxFields {
+                sender: signed.recover_signer().map_err(|_| TxError::BadSignature)?,
+                chain_id: Some(tx.chain_id),
+                nonce: tx.nonce,
+                gas_limit: tx.gas_limit,
+                max_fee_per_gas: tx.max_fee_per_gas,
+                max_priority_fee_per_gas: tx.max_priority_fee_per_gas,
+                to: tx.to,
+                value: tx.value,
+                input: tx.input.clone(),
+            })
+        }
+        _ => Err(TxError::UnsupportedType),
+    }
+}
+
+// intrinsic_gas returns the gas charged before execution, the base cost plus the calldata cost.
+pub fn intrinsic_gas(input: &[u8]) -> Option<u64> {
+    input.iter().try_fold(TX_GAS, |gas, byte| {
+        gas.checked_add(if *byte == 0 {
+            TX_DATA_ZERO_GAS
+        } else {
+            TX_DATA_NON_ZERO_GAS
+        })
+    })
+}
+
+// execute_tx returns the priority fee of the used gas, which should be credits to the block miner,
+// the base fee portion is burned, and the unused gas is refunded to the sender.
+// the events are appended to `events` only if the transaction succeeds.
+pub fn execute_tx(
+    kv: &mut impl KVStore,
+    tx: &TxEnvelope,
+    config: &ExecConfig,
+    events: &mut Vec<Event>,
+) -> TxResult {
+    let base_fee = config.base_fee;
+    let tx = tx_fields(tx)?;
 
     // check chain-id
-    if tx.chain_id? != CHAIN_ID {
-        return None;
+    if tx.chain_id != Some(config.chain_id) {
+        return Err(TxError::WrongChainId);
+    }
+
+    // the effective gas price is capped by the max fee
+    if tx.max_fee_per_gas < base_fee || tx.max_priority_fee_per_gas > tx.max_fee_per_gas {
+        return Err(TxError::FeeTooLow);
+    }
+    let gas_price = tx.max_fee_per_gas.min(
+        base_fee
+            .checked_add(tx.max_priority_fee_per_gas)
+            .ok_or(TxError::Overflow)?,
+    );
+
+    // only the intrinsic gas is used, there's no contract execution.
+    let gas_used = intrinsic_gas(&tx.input).ok_or(TxError::Overflow)?;
+    if tx.gas_limit < gas_used {
+        return Err(TxError::IntrinsicGasTooLow);
     }
 
-    let mut account = auth::load_account(kv, &sender).unwrap_or_default();
+    let mut account = auth::load_account(kv, &tx.sender)
+        .map_err(|_| TxError::CorruptedAccount)?
+        .unwrap_or_default();
 
     // check nonce
-    account.check_and_incr_nonce(tx.nonce)?;
+    account
+        .check_and_incr_nonce(tx.nonce)
+        .ok_or(TxError::BadNonce)?;
+
+    // deduct fee for the whole gas limit
+    let fee = U256::from(gas_price) * U256::from(tx.gas_limit);
+    account
+        .modify_balance(|balance| balance.checked_sub(fee))
+        .ok_or(TxError::InsufficientBalance)?;
 
-    // deduct fee
-    let fee = U256::from(tx.gas_price) * U256::from(tx.gas_limit);
-    account.modify_balance(|balance| balance.checked_sub(fee))?;
+    let mut tx_events = vec![Event::NonceIncremented {
+        address: tx.sender,
+        nonce: account.nonce,
+    }];
 
     // execute native transfer
     if tx.value > U256::ZERO {
-        let recipient_address = tx.to.to()?;
-        let mut recipient = auth::load_account(kv, recipient_address).unwrap_or_default();
-        account.modify_balance(|balance| balance.checked_sub(tx.value))?;
-        recipient.modify_balance(|balance| balance.checked_add(tx.value))?;
+        let recipient_address = tx.to.to().ok_or(TxError::InvalidRecipient)?;
+        let mut recipient = auth::load_account(kv, recipient_address)
+            .map_err(|_| TxError::CorruptedAccount)?
+            .unwrap_or_default();
+        account
+            .modify_balance(|balance| balance.checked_sub(tx.value))
+            .ok_or(TxError::InsufficientBalance)?;
+        recipient
+            .modify_balance(|balance| balance.checked_add(tx.value))
+            .ok_or(TxError::Overflow)?;
 
         auth::save_account(kv, recipient_address, &recipient);
+        tx_events.push(Event::Transfer {
+            from: tx.sender,
+            to: *recipient_address,
+            amount: tx.value,
+        });
+    }
+
+    // refund the unused gas
+    let refund = U256::from(gas_price) * U256::from(tx.gas_limit - gas_used);
+    account
+        .modify_balance(|balance| balance.checked_add(refund))
+        .ok_or(TxError::Overflow)?;
+    auth::save_account(kv, &tx.sender, &account);
+
+    tx_events.push(Event::FeeDeducted {
+        payer: tx.sender,
+        fee: fee - refund,
+        burned: U256::from(base_fee) * U256::from(gas_used),
+    });
+    events.append(&mut tx_events);
+    Ok(U256::from(gas_price - base_fee) * U256::from(gas_used))
+}
+
+// SimResult is the outcome of a simulated transaction, the changes are discarded.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimResult {
+    // the priority fee credited to the miner on success, or the reason of the failure
+    pub result: TxResult,
+    // the fee paid by the sender for the used gas, zero if failed
+    pub fee: U256,
+    // the accounts changed by the transaction, with the nonces and balances after the execution
+    pub accounts: BTreeMap<Address, AccountValue>,
+    pub events: Vec<Event>,
+}
+
+// simulate_tx runs `execute_tx` on a throwaway overlay of the store, for the wallets to estimate the
+// fee and detect the failures before broadcasting, the store is never modified.
+pub fn simulate_tx(kv: &impl KVStore, tx: &TxEnvelope, config: &ExecConfig) -> SimResult {
+    let mut buffer = Overlay::new(ReadOnly(kv));
+    let mut events = Vec::new();
+    let result = execute_tx(&mut buffer, tx, config, &mut events);
+
+    let mut fee = U256::ZERO;
+    let mut accounts = BTreeMap::new();
+    for event in &events {
+        let addresses = match event {
+            Event::NonceIncremented { address, .. } => [Some(address), None],
+            Event::FeeDeducted {
+                payer, fee: paid, ..
+            } => {
+                fee = *paid;
+                [Some(payer), None]
+            }
+            Event::Transfer { from, to, .. } => [Some(from), Some(to)],
+        };
+        for address in addresses.into_iter().flatten() {
+            // the accounts are just written by the transaction, so they decode.
+            if let Ok(Some(account)) = auth::load_account(&buffer, address) {
+                accounts.insert(*address, account);
+            }
+        }
+    }
+    SimResult {
+        result,
+        fee,
+        accounts,
+        events,
+    }
+}
+
+// ReadOnly borrows the store as the parent of an overlay which is never flushed.
+struct ReadOnly<'a, S>(&'a S);
+
+impl<S: KVStore> KVStore for ReadOnly<'_, S> {
+    fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        self.0.get(key)
+    }
+
+    fn set(&mut self, _key: Vec<u8>, _value: Vec<u8>) {
+        unreachable!("the simulation never writes to the store");
     }
-    auth::save_account(kv, &sender, &account);
 
-    Some(fee)
+    fn remove(&mut self, _key: &[u8]) {
+        unreachable!("the simulation never writes to the store");
+    }
+
+    fn range_dyn(&self, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>) -> DynIterator<'_> {
+        self.0.range_dyn(start, end)
+    }
 }
 
 // execute_block a batch of transactions, credits the collected fee to the block miner.
 // each transaction is executed in a atomic way, if fail, the transaction is skipped.
-pub fn execute_block(kv: &mut impl KVStore, miner: &Address, txs: &[TxEnvelope]) -> Option<()> {
+// returns the result of each transaction, `None` if the reward can't be credited to the miner.
+pub fn execute_block(
+    kv: &mut impl KVStore,
+    miner: &Address,
+    txs: &[TxEnvelope],
+    config: &ExecConfig,
+) -> Option<Vec<TxResult>> {
     let mut reward = U256::ZERO;
+    let mut results = Vec::with_capacity(txs.len());
 
     for tx in txs {
-        let mut buffer = Overlay::new(kv);
-        if let Some(fee) = execute_tx(&mut buffer, tx) {
-            reward = reward.checked_add(fee)?;
+        let mut buffer = Overlay::new(&mut *kv);
+        let result = execute_tx(&mut buffer, tx, config, &mut Vec::new()).and_then(|fee| {
+            reward = reward.checked_add(fee).ok_or(TxError::Overflow)?;
+            Ok(fee)
+        });
+        if result.is_ok() {
             buffer.flush();
         }
+        results.push(result);
     }
 
     // credit fees to the block miner
-    auth::modify_native_balance(kv, miner, |balance| balance.checked_add(reward))
+    auth::modify_native_balance(kv, miner, |balance| balance.checked_add(reward))?;
+    Some(results)
+}
+
+// commit_block executes the block and saves the state as a new version,
+// returns the new root hash and the reward credited to the miner.
+pub fn commit_block(
+    tree: &mut IAVLTree,
+    miner: &Address,
+    txs: &[TxEnvelope],
+    config: &ExecConfig,
+) -> Option<(Output<Sha256>, U256)> {
+    let results = execute_block(tree, miner, txs, config)?;
+    let reward = results.iter().flatten().sum();
+    Some((*tree.save_version(), reward))
 }
 
 #[cfg(test)]
@@ -62,13 +338,21 @@ mod tests {
     use super::*;
     use iavl::IAVLTree;
 
-    use alloy_consensus::{Signed, TxLegacy};
+    use alloy_consensus::{Signed, TxEip1559, TxLegacy};
     use alloy_network::TxSignerSync;
-    use alloy_primitives::{TxKind, U160};
+    use alloy_primitives::U160;
     use alloy_signer_local::PrivateKeySigner;
 
+    const CHAIN_ID: u64 = 1;
     const GAS_PRICE: u128 = 20e9 as u128;
 
+    fn config(base_fee: u128) -> ExecConfig {
+        ExecConfig {
+            chain_id: CHAIN_ID,
+            base_fee,
+        }
+    }
+
     fn legacy_tx(gas: u64, nonce: u64) -> TxLegacy {
         TxLegacy {
             nonce,
@@ -86,6 +370,228 @@ mod tests {
         TxEnvelope::Legacy(Signed::<_>::new_unhashed(tx, signature))
     }
 
+    fn eip1559_tx(nonce: u64, max_fee: u128, max_priority_fee: u128) -> TxEnvelope {
+        let signer = PrivateKeySigner::random();
+        let mut tx = TxEip1559 {
+            chain_id: CHAIN_ID,
+            nonce,
+            gas_limit: 21000,
+            max_fee_per_gas: max_fee,
+            max_priority_fee_per_gas: max_priority_fee,
+            to: TxKind::Call(Address::random()),
+            value: U256::from(100),
+            ..Default::default()
+        };
+        let signature = signer.sign_transaction_sync(&mut tx).unwrap();
+        TxEnvelope::Eip1559(Signed::<_>::new_unhashed(tx, signature))
+    }
+
+    #[test]
+    fn test_execute_eip1559_tx() {
+        const BASE_FEE: u128 = 10e9 as u128;
+        let mut kv = IAVLTree::default();
+
+        // effective gas price is base fee plus the priority fee
+        let tx = eip1559_tx(0, 3 * BASE_FEE, 2e9 as u128);
+        let sender = tx.recover_signer().unwrap();
+        let balance = U256::from(1e18 as u128);
+        auth::modify_native_balance(&mut kv, &sender, |_| Some(balance));
+
+        let reward = execute_tx(&mut kv, &tx, &config(BASE_FEE), &mut Vec::new()).unwrap();
+        assert_eq!(reward, U256::from(21000 * 2e9 as u128));
+        let account = auth::load_account(&kv, &sender).unwrap().unwrap();
+        assert_eq!(account.nonce, 1);
+        assert_eq!(
+            account.balance,
+            balance - U256::from(21000 * 12e9 as u128) - U256::from(100)
+        );
+
+        // the priority fee is capped by the max fee
+        let tx = eip1559_tx(0, BASE_FEE + 1, 2e9 as u128);
+        let sender = tx.recover_signer().unwrap();
+        auth::modify_native_balance(&mut kv, &sender, |_| Some(balance));
+        assert_eq!(
+            execute_tx(&mut kv, &tx, &config(BASE_FEE), &mut Vec::new()),
+            Ok(U256::from(21000))
+        );
+    }
+
+    #[test]
+    fn test_tx_events() {
+        const BASE_FEE: u128 = 10e9 as u128;
+        let mut kv = IAVLTree::default();
+        let signer = PrivateKeySigner::random();
+        let sender = signer.address();
+        auth::modify_native_balance(&mut kv, &sender, |_| Some(U256::from(1e18 as u128)));
+
+        let tx = legacy_tx(50000, 0);
+        let recipient = *tx.to.to().unwrap();
+        let mut events = Vec::new();
+        let reward = execute_tx(
+            &mut kv,
+            &sign(signer.clone(), tx),
+            &config(BASE_FEE),
+            &mut events,
+        )
+        .unwrap();
+        let burned = U256::from(21000 * BASE_FEE);
+        assert_eq!(
+            events,
+            vec![
+                Event::NonceIncremented {
+                    address: sender,
+                    nonce: 1
+                },
+                Event::Transfer {
+                    from: sender,
+                    to: recipient,
+                    amount: U256::from(100)
+                },
+                Event::FeeDeducted {
+                    payer: sender,
+                    fee: reward + burned,
+                    burned
+                },
+            ]
+        );
+
+        // no events for the failed transaction
+        let mut events = Vec::new();
+        let tx = sign(signer.clone(), legacy_tx(21000, 0));
+        assert_eq!(
+            execute_tx(&mut kv, &tx, &config(BASE_FEE), &mut events),
+            Err(TxError::BadNonce)
+        );
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn test_simulate_tx() {
+        let mut kv = IAVLTree::default();
+        let signer = PrivateKeySigner::random();
+        let sender = signer.address();
+        let balance = U256::from(1e18 as u128);
+        auth::modify_native_balance(&mut kv, &sender, |_| Some(balance));
+        let hash = *kv.save_version();
+
+        let tx = legacy_tx(50000, 0);
+        let recipient = *tx.to.to().unwrap();
+        let tx = sign(signer.clone(), tx);
+        let sim = simulate_tx(&kv, &tx, &config(0));
+        let fee = U256::from(21000 * GAS_PRICE);
+        assert_eq!(sim.result, Ok(fee));
+        assert_eq!(sim.fee, fee);
+        assert_eq!(sim.events.len(), 3);
+        assert_eq!(
+            sim.accounts[&sender],
+            AccountValue {
+                nonce: 1,
+                balance: balance - fee - U256::from(100)
+            }
+        );
+        assert_eq!(sim.accounts[&recipient].balance, U256::from(100));
+
+        // the store is unchanged
+        assert_eq!(*kv.root_hash(), hash);
+        assert_eq!(auth::load_account(&kv, &recipient).unwrap(), None);
+
+        // the same as the execution
+        let mut events = Vec::new();
+        assert_eq!(
+            execute_tx(&mut kv, &tx, &config(0), &mut events),
+            sim.result
+        );
+        assert_eq!(events, sim.events);
+        assert_eq!(
+            auth::load_account(&kv, &sender).unwrap().unwrap(),
+            sim.accounts[&sender]
+        );
+
+        // the failure is reported
+        let sim = simulate_tx(&kv, &tx, &config(0));
+        assert_eq!(sim.result, Err(TxError::BadNonce));
+        assert_eq!(sim.fee, U256::ZERO);
+        assert!(sim.accounts.is_empty());
+    }
+
+    #[test]
+    fn test_reject_max_fee_below_base_fee() {
+        const BASE_FEE: u128 = 10e9 as u128;
+        let mut kv = IAVLTree::default();
+
+        let tx = eip1559_tx(0, BASE_FEE - 1, 0);
+        let sender = tx.recover_signer().unwrap();
+        let balance = U256::from(1e18 as u128);
+        auth::modify_native_balance(&mut kv, &sender, |_| Some(balance));
+
+        assert_eq!(
+            execute_tx(&mut kv, &tx, &config(BASE_FEE), &mut Vec::new()),
+            Err(TxError::FeeTooLow)
+        );
+        assert_eq!(
+            auth::load_account(&kv, &sender).unwrap().unwrap(),
+            auth::AccountValue { nonce: 0, balance }
+        );
+
+        // legacy tx with gas price below base fee
+        let signer = PrivateKeySigner::random();
+        let tx = sign(signer.clone(), legacy_tx(21000, 0));
+        auth::modify_native_balance(&mut kv, &signer.address(), |_| Some(balance));
+        assert_eq!(
+            execute_tx(&mut kv, &tx, &config(GAS_PRICE + 1), &mut Vec::new()),
+            Err(TxError::FeeTooLow)
+        );
+    }
+
+    #[test]
+    fn test_gas_refund() {
+        let mut kv = IAVLTree::default();
+        let signer = PrivateKeySigner::random();
+        let balance = U256::from(1e18 as u128);
+        auth::modify_native_balance(&mut kv, &signer.address(), |_| Some(balance));
+
+        let mut tx = legacy_tx(50000, 0);
+        tx.input = Bytes::from(vec![0, 1, 2]);
+        let gas_used = 21000 + 4 + 16 * 2;
+        assert_eq!(intrinsic_gas(&tx.input), Some(gas_used));
+
+        let reward = execute_tx(
+            &mut kv,
+            &sign(signer.clone(), tx),
+            &config(0),
+            &mut Vec::new(),
+        )
+        .unwrap();
+        assert_eq!(reward, U256::from(gas_used as u128 * GAS_PRICE));
+        let account = auth::load_account(&kv, &signer.address()).unwrap().unwrap();
+        // the sender only pays for the used gas
+        let paid = balance - account.balance - U256::from(100);
+        assert_eq!(paid, reward);
+        let refund = U256::from(50000 * GAS_PRICE) - paid;
+        assert_eq!(refund, U256::from((50000 - gas_used) as u128 * GAS_PRICE));
+        assert_eq!(refund + reward, U256::from(50000 * GAS_PRICE));
+
+        // gas limit below the intrinsic gas
+        let mut tx = legacy_tx(21000, 1);
+        tx.input = Bytes::from(vec![1]);
+        assert_eq!(
+            execute_tx(
+                &mut kv,
+                &sign(signer.clone(), tx),
+                &config(0),
+                &mut Vec::new()
+            ),
+            Err(TxError::IntrinsicGasTooLow)
+        );
+        assert_eq!(
+            auth::load_account(&kv, &signer.address())
+                .unwrap()
+                .unwrap()
+                .nonce,
+            1
+        );
+    }
+
     #[test]
     fn test_execute_block() {
         let mut kv = IAVLTree::default();
@@ -106,17 +612,120 @@ mod tests {
             balance.checked_add(exp_total_value + exp_total_fee)
         });
 
-        assert!(execute_block(&mut kv, &miner, &txs).is_some());
+        assert!(execute_block(&mut kv, &miner, &txs, &config(0)).is_some());
 
         // check execution side effects
 
         // assert miner balance
-        let miner_account = auth::load_account(&kv, &miner).unwrap_or_default();
+        let miner_account = auth::load_account(&kv, &miner).unwrap().unwrap_or_default();
         assert_eq!(miner_account.balance, exp_total_fee);
 
         // assert sender nonce and balance
-        let sender_account = auth::load_account(&kv, &signer.address()).unwrap_or_default();
+        let sender_account = auth::load_account(&kv, &signer.address())
+            .unwrap()
+            .unwrap_or_default();
         assert_eq!(sender_account.nonce, 3);
         assert_eq!(sender_account.balance, U256::ZERO);
     }
+
+    #[test]
+    fn test_skip_other_chain() {
+        let mut kv = IAVLTree::default();
+        let signer = PrivateKeySigner::random();
+        let miner = Address::from(U160::from(0x1234));
+        let mut other_chain = legacy_tx(21000, 1);
+        other_chain.chain_id = Some(CHAIN_ID + 1);
+        let txs = vec![
+            sign(signer.clone(), legacy_tx(21000, 0)),
+            sign(signer.clone(), other_chain),
+        ];
+        auth::modify_native_balance(&mut kv, &signer.address(), |_| Some(U256::MAX));
+
+        assert_eq!(
+            execute_block(&mut kv, &miner, &txs, &config(0)).unwrap()[1],
+            Err(TxError::WrongChainId)
+        );
+        let sender_account = auth::load_account(&kv, &signer.address()).unwrap().unwrap();
+        assert_eq!(sender_account.nonce, 1);
+        let miner_account = auth::load_account(&kv, &miner).unwrap().unwrap();
+        assert_eq!(miner_account.balance, U256::from(21000 * GAS_PRICE));
+
+        // the tx is accepted by the chain it's signed for
+        let config = ExecConfig::new(CHAIN_ID + 1);
+        assert!(execute_tx(&mut kv, &txs[1], &config, &mut Vec::new()).is_ok());
+    }
+
+    #[test]
+    fn test_commit_block_deterministic() {
+        let signer = PrivateKeySigner::random();
+        let miner = Address::from(U160::from(0x1234));
+        let txs = vec![
+            sign(signer.clone(), legacy_tx(21000, 0)),
+            sign(signer.clone(), legacy_tx(21000, 1)),
+            // bad nonce
+            sign(signer.clone(), legacy_tx(21000, 3)),
+        ];
+
+        let run = || {
+            let mut tree = IAVLTree::default();
+            auth::modify_native_balance(&mut tree, &signer.address(), |_| Some(U256::MAX));
+            tree.save_version();
+            commit_block(&mut tree, &miner, &txs, &config(0)).unwrap()
+        };
+        let (root, reward) = run();
+        assert_eq!(reward, U256::from(2 * 21000 * GAS_PRICE));
+        assert_eq!(run(), (root, reward));
+    }
+
+    #[test]
+    fn test_execute_block_results() {
+        let mut kv = IAVLTree::default();
+        let signer = PrivateKeySigner::random();
+        let underfunded = PrivateKeySigner::random();
+        let miner = Address::from(U160::from(0x1234));
+        let txs = vec![
+            sign(signer.clone(), legacy_tx(21000, 0)),
+            sign(signer.clone(), legacy_tx(21000, 2)),
+            sign(underfunded.clone(), legacy_tx(21000, 0)),
+        ];
+        auth::modify_native_balance(&mut kv, &signer.address(), |_| Some(U256::MAX));
+        auth::modify_native_balance(&mut kv, &underfunded.address(), |_| Some(U256::from(1)));
+
+        let fee = U256::from(21000 * GAS_PRICE);
+        assert_eq!(
+            execute_block(&mut kv, &miner, &txs, &config(0)).unwrap(),
+            vec![
+                Ok(fee),
+                Err(TxError::BadNonce),
+                Err(TxError::InsufficientBalance)
+            ]
+        );
+
+        // the malformed account of the recipient fails the transaction, not the block
+        let mut tx = legacy_tx(21000, 1);
+        let recipient = Address::random();
+        tx.to = TxKind::Call(recipient);
+        kv.set(auth::store_key(&recipient), b"garbage".to_vec());
+        assert_eq!(
+            execute_block(&mut kv, &miner, &[sign(signer.clone(), tx)], &config(0)).unwrap(),
+            vec![Err(TxError::CorruptedAccount)]
+        );
+
+        // the failed transactions are not committed
+        assert_eq!(
+            auth::load_account(&kv, &signer.address())
+                .unwrap()
+                .unwrap()
+                .nonce,
+            1
+        );
+        let account = auth::load_account(&kv, &underfunded.address())
+            .unwrap()
+            .unwrap();
+        assert_eq!((account.nonce, account.balance), (0, U256::from(1)));
+        assert_eq!(
+            auth::load_account(&kv, &miner).unwrap().unwrap().balance,
+            fee
+        );
+    }
 }
@@ -1,4 +1,5 @@
 pub enum StorePrefix {
     Auth,
     Bank,
+    Supply,
 }
@@ -0,0 +1,207 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::ops::{Bound, RangeBounds};
+
+use super::types::{ChangeItem, DynIterator, KVStore, KVStoreExt};
+
+// CachingStore caches the results of `get_owned` of the inner store in a bounded LRU, including
+// the missing keys, the writes invalidate the cached keys. `get` and the ranges are delegated to
+// the inner store, since the borrowed values can't outlive their eviction from the cache.
+pub struct CachingStore<S> {
+    inner: S,
+    cache: RefCell<Lru>,
+}
+
+// the last access tick, and the cached value, `None` if the key don't exist.
+type Slot = (u64, Option<Vec<u8>>);
+
+struct Lru {
+    capacity: usize,
+    tick: u64,
+    entries: BTreeMap<Vec<u8>, Slot>,
+    // last access tick -> key
+    order: BTreeMap<u64, Vec<u8>>,
+}
+
+impl Lru {
+    // touch marks the key as the most recently used, returns the cached value if any.
+    fn touch(&mut self, key: &[u8]) -> Option<Option<Vec<u8>>> {
+        let (tick, value) = self.entries.get_mut(key)?;
+        self.tick += 1;
+        let key = self.order.remove(tick).unwrap();
+        *tick = self.tick;
+        self.order.insert(self.tick, key);
+        Some(value.clone())
+    }
+
+    fn insert(&mut self, key: Vec<u8>, value: Option<Vec<u8>>) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() >= self.capacity {
+            let (_, oldest) = self.order.pop_first().unwrap();
+            self.entries.remove(&oldest);
+        }
+        self.tick += 1;
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(key, (self.tick, value));
+    }
+
+    fn invalidate(&mut self, key: &[u8]) {
+        if let Some((tick, _)) = self.entries.remove(key) {
+            self.order.remove(&tick);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+}
+
+impl<S: KVStore> CachingStore<S> {
+    pub fn new(inner: S, capacity: usize) -> Self {
+        CachingStore {
+            inner,
+            cache: RefCell::new(Lru {
+                capacity,
+                tick: 0,
+                entries: BTreeMap::new(),
+                order: BTreeMap::new(),
+            }),
+        }
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    // inner_mut clears the cache, since the writes to the inner store bypass the invalidation.
+    pub fn inner_mut(&mut self) -> &mut S {
+        self.cache.get_mut().clear();
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    fn invalidate(&mut self, key: &[u8]) {
+        self.cache.get_mut().invalidate(key);
+    }
+}
+
+impl<S: KVStore> KVStore for CachingStore<S> {
+    fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        self.inner.get(key)
+    }
+
+    fn get_owned(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let mut cache = self.cache.borrow_mut();
+        if let Some(value) = cache.touch(key) {
+            return value;
+        }
+        let value = self.inner.get_owned(key);
+        cache.insert(key.to_vec(), value.clone());
+        value
+    }
+
+    fn set(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.invalidate(&key);
+        self.inner.set(key, value);
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        self.invalidate(key);
+        self.inner.remove(key);
+    }
+
+    fn range_dyn(&self, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>) -> DynIterator<'_> {
+        self.inner.range_dyn(start, end)
+    }
+
+    fn write_batch(&mut self, batch: impl IntoIterator<Item = ChangeItem>) {
+        let batch = batch.into_iter().collect::<Vec<_>>();
+        for (key, _) in &batch {
+            self.invalidate(key);
+        }
+        self.inner.write_batch(batch);
+    }
+}
+
+impl<S: KVStoreExt> KVStoreExt for CachingStore<S> {
+    fn range<R>(&self, bounds: R) -> impl DoubleEndedIterator<Item = (&[u8], &[u8])>
+    where
+        R: RangeBounds<Vec<u8>> + Clone,
+    {
+        self.inner.range(bounds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::CountingStore;
+
+    #[test]
+    fn test_caching_store() {
+        let mut store = CachingStore::new(CountingStore::default(), 2);
+        store.set(b"key1".to_vec(), b"value1".to_vec());
+        store.set(b"key2".to_vec(), b"value2".to_vec());
+        store.set(b"key3".to_vec(), b"value3".to_vec());
+
+        assert_eq!(store.get_owned(b"key1"), Some(b"value1".to_vec()));
+        assert_eq!(store.get_owned(b"key1"), Some(b"value1".to_vec()));
+        assert_eq!(store.inner().reads.get(), 1);
+
+        // missing keys are cached too
+        assert_eq!(store.get_owned(b"missing"), None);
+        assert_eq!(store.get_owned(b"missing"), None);
+        assert_eq!(store.inner().reads.get(), 2);
+
+        // key1 is evicted
+        assert_eq!(store.get_owned(b"key2"), Some(b"value2".to_vec()));
+        assert_eq!(store.get_owned(b"key3"), Some(b"value3".to_vec()));
+        assert_eq!(store.inner().reads.get(), 4);
+        assert_eq!(store.get_owned(b"key1"), Some(b"value1".to_vec()));
+        assert_eq!(store.inner().reads.get(), 5);
+
+        // the borrowed reads are not cached
+        assert_eq!(store.get(b"key1"), Some(b"value1".as_ref()));
+        assert_eq!(store.inner().reads.get(), 6);
+
+        // writes invalidate the cached values
+        store.set(b"key1".to_vec(), b"new".to_vec());
+        store.write_batch([(b"key3".to_vec(), None)]);
+        assert_eq!(store.get_owned(b"key1"), Some(b"new".to_vec()));
+        assert_eq!(store.get_owned(b"key3"), None);
+        assert_eq!(store.inner().reads.get(), 8);
+
+        assert_eq!(store.range(..).count(), 2);
+
+        // bypassing the cache drops it
+        store
+            .inner_mut()
+            .set(b"key1".to_vec(), b"bypassed".to_vec());
+        assert_eq!(store.get_owned(b"key1"), Some(b"bypassed".to_vec()));
+        assert_eq!(store.inner().reads.get(), 9);
+    }
+
+    #[test]
+    fn test_cache_bounded() {
+        let mut inner = CountingStore::default();
+        for i in 0u32..100 {
+            inner.set(i.to_be_bytes().to_vec(), i.to_be_bytes().to_vec());
+        }
+        let mut store = CachingStore::new(inner, 10);
+        for i in 0u32..100 {
+            assert_eq!(
+                store.get_owned(&i.to_be_bytes()),
+                Some(i.to_be_bytes().to_vec())
+            );
+        }
+        assert_eq!(store.cache.get_mut().entries.len(), 10);
+        assert_eq!(store.cache.get_mut().order.len(), 10);
+        assert_eq!(store.into_inner().reads.get(), 100);
+    }
+}
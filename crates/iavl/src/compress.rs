@@ -0,0 +1,184 @@
+use integer_encoding::VarInt;
+use std::mem;
+
+use super::error::DbError;
+
+// A leaf value is stored as a flag byte followed by the value, either raw or compressed, the
+// hashes are over the uncompressed values, so the compression is only a storage concern.
+//
+// The compressed value is the varint of the uncompressed length, followed by the tokens:
+// - `0b0xxxxxxx`: a run of `x + 1` literal bytes follows.
+// - `0b1xxxxxxx`: copy `x + MIN_MATCH` bytes from the varint offset back in the output, the
+//   copy may overlap with itself to repeat a short pattern.
+
+const RAW: u8 = 0;
+const COMPRESSED: u8 = 1;
+
+const MIN_MATCH: usize = 4;
+const MAX_MATCH: usize = 0x7f + MIN_MATCH;
+const MAX_LITERALS: usize = 0x80;
+const HASH_BITS: u32 = 12;
+// the shorter values are stored raw, the compression can't pay off.
+const MIN_COMPRESS_LEN: usize = 32;
+
+// encode_value compresses the value if it's worth it.
+pub fn encode_value(value: &[u8]) -> Vec<u8> {
+    if value.len() >= MIN_COMPRESS_LEN {
+        let compressed = compress(value);
+        if compressed.len() < value.len() {
+            let mut buf = Vec::with_capacity(compressed.len() + 1);
+            buf.push(COMPRESSED);
+            buf.extend_from_slice(&compressed);
+            return buf;
+        }
+    }
+    let mut buf = Vec::with_capacity(value.len() + 1);
+    buf.push(RAW);
+    buf.extend_from_slice(value);
+    buf
+}
+
+// decode_value restores the value encoded by `encode_value`.
+pub fn decode_value(bz: &[u8]) -> Result<Vec<u8>, DbError> {
+    match bz.split_first() {
+        Some((&RAW, value)) => Ok(value.to_vec()),
+        Some((&COMPRESSED, data)) => decompress(data),
+        _ => Err(DbError::Decode("invalid value encoding".to_string())),
+    }
+}
+
+// compress finds the matches greedily, through a hash table of the last position of each
+// prefix of `MIN_MATCH` bytes.
+fn compress(input: &[u8]) -> Vec<u8> {
+    let mut out = input.len().encode_var_vec();
+    let mut table = vec![usize::MAX; 1 << HASH_BITS];
+    // the start of the pending literals
+    let mut literals = 0;
+    let mut i = 0;
+    while i + MIN_MATCH <= input.len() {
+        let prefix = &input[i..i + MIN_MATCH];
+        let candidate = mem::replace(&mut table[hash(prefix)], i);
+        if candidate == usize::MAX || &input[candidate..candidate + MIN_MATCH] != prefix {
+            i += 1;
+            continue;
+        }
+        let len = MIN_MATCH
+            + input[candidate + MIN_MATCH..]
+                .iter()
+                .zip(&input[i + MIN_MATCH..])
+                .take(MAX_MATCH - MIN_MATCH)
+                .take_while(|(a, b)| a == b)
+                .count();
+        write_literals(&mut out, &input[literals..i]);
+        out.push(0x80 | (len - MIN_MATCH) as u8);
+        out.extend_from_slice(&(i - candidate).encode_var_vec());
+        i += len;
+        literals = i;
+    }
+    write_literals(&mut out, &input[literals..]);
+    out
+}
+
+fn write_literals(out: &mut Vec<u8>, literals: &[u8]) {
+    for chunk in literals.chunks(MAX_LITERALS) {
+        out.push((chunk.len() - 1) as u8);
+        out.extend_from_slice(chunk);
+    }
+}
+
+fn hash(prefix: &[u8]) -> usize {
+    let word = u32::from_le_bytes(prefix.try_into().unwrap());
+    (word.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize
+}
+
+// decompress fails on the corrupted input instead of panicking, the output never exceeds the
+// length declared upfront.
+fn decompress(data: &[u8]) -> Result<Vec<u8>, DbError> {
+    let invalid = || DbError::Decode("invalid compressed value".to_string());
+    let (len, n) = usize::decode_var(data).ok_or_else(invalid)?;
+    let mut data = &data[n..];
+    // each token of 2 bytes at least yields `MAX_MATCH` bytes at most, so a corrupted length
+    // can't make a huge allocation.
+    let mut out = Vec::with_capacity(len.min(data.len().saturating_mul(MAX_MATCH)));
+    while let Some((&token, rest)) = data.split_first() {
+        data = rest;
+        if token & 0x80 == 0 {
+            let n = token as usize + 1;
+            if n > data.len() {
+                return Err(invalid());
+            }
+            out.extend_from_slice(&data[..n]);
+            data = &data[n..];
+        } else {
+            let n = (token & 0x7f) as usize + MIN_MATCH;
+            let (offset, m) = usize::decode_var(data).ok_or_else(invalid)?;
+            data = &data[m..];
+            if offset == 0 || offset > out.len() {
+                return Err(invalid());
+            }
+            let start = out.len() - offset;
+            for i in start..start + n {
+                out.push(out[i]);
+            }
+        }
+        if out.len() > len {
+            return Err(invalid());
+        }
+    }
+    if out.len() != len {
+        return Err(invalid());
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_value_roundtrip() {
+        let mut pseudo_random = Vec::new();
+        let mut x = 1u32;
+        for _ in 0..1000 {
+            x = x.wrapping_mul(1103515245).wrapping_add(12345);
+            pseudo_random.push((x >> 16) as u8);
+        }
+        let cases = [
+            Vec::new(),
+            b"short".to_vec(),
+            vec![7u8; 10000],
+            b"abc".repeat(1000),
+            pseudo_random.clone(),
+            [
+                pseudo_random.as_slice(),
+                b"middle",
+                pseudo_random.as_slice(),
+            ]
+            .concat(),
+        ];
+        for value in cases {
+            let encoded = encode_value(&value);
+            assert_eq!(decode_value(&encoded).unwrap(), value);
+        }
+
+        // the repetitive values shrink, the others are stored raw
+        assert!(encode_value(&[7u8; 10000]).len() < 400);
+        assert_eq!(encode_value(&pseudo_random)[0], RAW);
+        assert_eq!(encode_value(b"short")[0], RAW);
+    }
+
+    #[test]
+    fn test_corrupted_value() {
+        let encoded = encode_value(&b"abcd".repeat(100));
+        assert_eq!(encoded[0], COMPRESSED);
+        assert!(decode_value(&[]).is_err());
+        assert!(decode_value(&[2, 1, 2]).is_err());
+        assert!(decode_value(&encoded[..encoded.len() - 1]).is_err());
+        // the offset points before the output
+        assert!(decode_value(&[COMPRESSED, 8, 0x80, 1]).is_err());
+        // the output exceeds the declared length
+        let mut longer = encoded.clone();
+        longer[1] -= 1;
+        assert!(decode_value(&longer).is_err());
+    }
+}
@@ -1,39 +1,297 @@
 use crypto_common::Output;
-use sha2::Sha256;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
 use std::mem;
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+#[cfg(feature = "async")]
+use std::{
+    future::Future,
+    panic::{self, AssertUnwindSafe},
+    pin::Pin,
+    sync::{mpsc, Mutex},
+    task::{Context, Poll, Waker},
+    thread,
+};
 
-use serde::{Deserialize, Serialize};
+use serde::{ser, Deserialize, Deserializer, Serialize, Serializer};
 use walcraft::Wal;
 
-use crate::{types::ChangeItem, IAVLTree, KVStore};
+use crate::error::DbError;
+use crate::node::NodeRef;
+use crate::nodedb::NodeDB;
+use crate::snapshot::{export_tree, import_tree};
+use crate::types::{ChangeItem, DynIterator};
+use crate::{IAVLTree, KVStore, KVStoreExt, TreeIterator};
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct Entry {
+// Changeset is the changes committed in a version.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Changeset {
     pub version: u64,
     pub changes: Vec<ChangeItem>,
+    // the root hash after the changes are applied, verified on replay, for the snapshot written
+    // by `compact` it's the hash of the tree rebuilt from it, which may differ in shape.
+    pub root_hash: Option<Vec<u8>>,
 }
 
+impl Changeset {
+    // checksum computes the CRC32 of the fields, the keys and values are length prefixed.
+    fn checksum(&self) -> u32 {
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&self.version.to_le_bytes());
+        for (key, value) in &self.changes {
+            hasher.update(&(key.len() as u64).to_le_bytes());
+            hasher.update(key);
+            match value {
+                Some(value) => {
+                    hasher.update(&[1]);
+                    hasher.update(&(value.len() as u64).to_le_bytes());
+                    hasher.update(value);
+                }
+                None => hasher.update(&[0]),
+            }
+        }
+        if let Some(root_hash) = &self.root_hash {
+            hasher.update(root_hash);
+        }
+        hasher.finalize()
+    }
+}
+
+// WalEntry is the record in the WAL, encoded as the checksum followed by the `Changeset`, it keeps
+// the decode error of a corrupted or torn entry, which is skipped silently by walcraft otherwise.
+#[derive(Clone)]
+struct WalEntry(Result<Changeset, String>);
+
+impl Serialize for WalEntry {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match &self.0 {
+            Ok(entry) => (entry.checksum(), entry).serialize(serializer),
+            Err(err) => Err(ser::Error::custom(err)),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for WalEntry {
+    fn deserialize<De: Deserializer<'de>>(deserializer: De) -> Result<Self, De::Error> {
+        let entry = <(u32, Changeset)>::deserialize(deserializer)
+            .map_err(|err| err.to_string())
+            .and_then(|(checksum, entry)| {
+                if checksum == entry.checksum() {
+                    Ok(entry)
+                } else {
+                    Err(format!("checksum mismatch at version {}", entry.version))
+                }
+            });
+        Ok(WalEntry(entry))
+    }
+}
+
+// FlushPolicy decides when the buffered WAL entries are written to the files.
+//
+// The nodes of each version are persisted on `save_version` regardless of the policy. The WAL
+// entries not flushed yet are flushed when the db is dropped, and lost on crash. After a crash,
+// the db is still recovered from the node db on reopen. But a gap is left in the WAL, so the node
+// db can't be rebuilt from the WAL anymore.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlushPolicy {
+    EveryVersion,
+    // flush after every N saved versions
+    EveryN(u32),
+    // only flush on `flush_wal` and drop
+    Manual,
+}
+
+type Subscriber = Box<dyn FnMut(&Changeset) + Send>;
+
+// the number of the historical roots cached for `get_at_version`.
+const HISTORY_CACHE_SIZE: usize = 8;
+
+// walcraft stores the length of a record as u16, larger entries are corrupted silently.
+const MAX_ENTRY_SIZE: usize = u16::MAX as usize;
+
+// IAVLDB persists the tree nodes in the node db, and the changesets in the WAL,
+// the changesets not yet persisted in the node db are replayed on open.
 pub struct IAVLDB {
+    path: PathBuf,
     tree: IAVLTree,
-    wal: Wal<Entry>,
+    wal: Wal<WalEntry>,
+    nodes: Arc<NodeDB>,
     pending_changes: Vec<ChangeItem>,
+    flush_policy: FlushPolicy,
+    // number of versions written to the WAL since the last flush
+    unflushed: u32,
+    // notified with the changeset of each saved version
+    subscribers: Vec<Subscriber>,
+    // the roots of the historical versions read recently, the most recent first
+    history: RefCell<VecDeque<(u64, Option<NodeRef>)>>,
+    // spawned on the first `save_version_async`
+    #[cfg(feature = "async")]
+    flusher: Option<Flusher>,
 }
 
 impl IAVLDB {
-    pub fn new(path: &str) -> Result<Self, String> {
-        let mut tree = IAVLTree::new();
-        let wal: Wal<Entry> = Wal::new(path, None);
+    pub fn new(path: &str) -> Result<Self, DbError> {
+        Self::with_flush_policy(path, FlushPolicy::EveryVersion)
+    }
 
-        for entry in wal.read()? {
-            tree.write_batch(entry.changes);
-            tree.save_version();
+    // open_verified opens the db like `new`, and checks the root hash of the latest version.
+    // the nodes are loaded lazily and a failed load panics, so the nodes of the latest version are
+    // all read and checked too, which costs a full scan of the tree.
+    pub fn open_verified(path: &str, expected_root: Output<Sha256>) -> Result<Self, DbError> {
+        let mut db = Self::new(path)?;
+        if let Some((_, Some(NodeRef::Stored(root)))) = db.nodes.latest_root::<Sha256>()? {
+            db.nodes
+                .check_subtree::<Sha256>(&root.hash, root.max_height)?;
         }
+        let root_hash = *db.tree.root_hash();
+        if root_hash != expected_root {
+            return Err(DbError::RootMismatch(format!(
+                "root hash mismatch at version {}: {:x}, expect: {:x}",
+                db.tree.version(),
+                root_hash,
+                expected_root
+            )));
+        }
+        Ok(db)
+    }
+
+    pub fn with_flush_policy(path: &str, flush_policy: FlushPolicy) -> Result<Self, DbError> {
+        Self::open(path, flush_policy, 0)
+    }
+
+    // with_initial_version opens the db like `new`, the first saved version of a new db is
+    // `initial_version`, it's ignored if the db has saved versions already.
+    pub fn with_initial_version(path: &str, initial_version: u64) -> Result<Self, DbError> {
+        Self::open(path, FlushPolicy::EveryVersion, initial_version)
+    }
+
+    fn open(path: &str, flush_policy: FlushPolicy, initial_version: u64) -> Result<Self, DbError> {
+        let nodes = NodeDB::open(Path::new(path).join("nodes"))?;
+        let latest = nodes.latest_root()?;
+        // no version is saved yet, the WAL may start with a snapshot written by `compact`.
+        let mut fresh = latest.is_none();
+        let mut tree = match latest {
+            Some((version, root)) => IAVLTree::load(version, root),
+            None => IAVLTree::with_initial_version(initial_version),
+        };
+        let wal: Wal<WalEntry> = Wal::new(path, None);
+
+        let mut entries = wal
+            .read()
+            .map_err(DbError::Io)?
+            .map(|entry| {
+                entry
+                    .0
+                    .map_err(|err| DbError::Decode(format!("invalid WAL entry: {}", err)))
+            })
+            .collect::<Vec<_>>();
+        // the trailing entries are torn if the process crashed while writing them, the replay
+        // stops at the last complete entry, it's still an error if no entry is complete, or the
+        // corruption is before the last complete entry.
+        let complete = entries.iter().rposition(Result::is_ok).map_or(0, |i| i + 1);
+        let torn = match entries.get(complete) {
+            Some(Err(err)) if complete > 0 => Some(err.clone()),
+            Some(Err(err)) => return Err(err.clone()),
+            _ => None,
+        };
+        entries.truncate(complete);
 
-        Ok(Self {
+        let mut entries = entries.into_iter().peekable();
+        while let Some(entry) = entries.next() {
+            let entry = entry?;
+            if entry.version <= tree.version() {
+                continue;
+            }
+            // the large versions and the snapshot written by `compact` are split into multiple
+            // entries of the same version.
+            let mut changes = entry.changes;
+            let mut root_hash = entry.root_hash;
+            while let Some(next) = entries.next_if(|next| {
+                next.as_ref()
+                    .is_ok_and(|next| next.version == entry.version)
+            }) {
+                let next = next?;
+                changes.extend(next.changes);
+                root_hash = next.root_hash;
+            }
+
+            if entry.version == tree.version() + 1 {
+                // the entries of the last version may be torn partially.
+                let last = (torn.is_some() && entries.peek().is_none()).then(|| tree.clone());
+                tree.write_batch(changes);
+                let actual = tree.save_version();
+                if root_hash.is_some_and(|expect| expect != actual.as_slice()) {
+                    if let Some(last) = last {
+                        tree = last;
+                        break;
+                    }
+                    return Err(DbError::RootMismatch(format!(
+                        "root hash mismatch on replay at version {}",
+                        entry.version
+                    )));
+                }
+                nodes.save_version(tree.version(), tree.root())?;
+            } else if fresh {
+                // the snapshot has no deletions, and the root hash of the tree rebuilt from it,
+                // the changesets of a later version are not mistaken for it.
+                let leaves = changes
+                    .into_iter()
+                    .map(|(key, value)| value.map(|value| (key, value)))
+                    .collect::<Option<Vec<_>>>()
+                    .ok_or_else(|| {
+                        DbError::WalCorruption(format!(
+                            "deletion in the snapshot at version {}",
+                            entry.version
+                        ))
+                    })?;
+                tree = IAVLTree::from_sorted(leaves, entry.version);
+                if root_hash.as_deref() != Some(tree.root_hash().as_slice()) {
+                    return Err(DbError::RootMismatch(format!(
+                        "root hash mismatch of the snapshot at version {}",
+                        entry.version
+                    )));
+                }
+                nodes.save_tree(tree.version(), tree.root())?;
+            } else {
+                return Err(DbError::WalCorruption(format!(
+                    "missing versions in WAL between {} and {}",
+                    tree.version(),
+                    entry.version
+                )));
+            }
+            fresh = false;
+            tree.prune_to(tree.version())
+                .map_err(DbError::VersionMismatch)?;
+        }
+        drop(entries);
+        // the new entries would be appended after the torn ones, and misread along with them.
+        if let Some(err) = torn {
+            log::warn!(
+                "{}, the WAL is recovered to version {}",
+                err,
+                tree.version()
+            );
+            truncate_torn_tail(Path::new(path), tree.version())?;
+        }
+
+        let db = Self {
+            path: PathBuf::from(path),
             tree,
             wal,
+            nodes,
             pending_changes: Vec::new(),
-        })
+            flush_policy,
+            unflushed: 0,
+            subscribers: Vec::new(),
+            history: RefCell::new(VecDeque::new()),
+            #[cfg(feature = "async")]
+            flusher: None,
+        };
+        Ok(db)
     }
 }
 
@@ -42,46 +300,497 @@ impl KVStore for IAVLDB {
         self.tree.get(key)
     }
 
-    fn set(&mut self, _key: Vec<u8>, _value: Vec<u8>) {
-        panic!("PersistedDB does not support set directly");
+    fn get_sorted(&self, keys: &[&[u8]]) -> Vec<Option<&[u8]>> {
+        self.tree.get_sorted(keys)
     }
 
-    fn remove(&mut self, _key: &[u8]) {
-        panic!("PersistedDB does not support remove directly");
+    // the changes are staged in the working tree, and committed by `save_version`.
+    fn set(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.pending_changes
+            .push((key.clone(), Some(value.clone())));
+        self.tree.set(key, value);
     }
 
-    fn range<R>(&self, bounds: R) -> impl DoubleEndedIterator<Item = (&[u8], &[u8])>
-    where
-        R: std::ops::RangeBounds<Vec<u8>> + Clone,
-    {
-        self.tree.range(bounds)
+    fn remove(&mut self, key: &[u8]) {
+        self.pending_changes.push((key.to_vec(), None));
+        self.tree.remove(key);
+    }
+
+    fn range_dyn(&self, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>) -> DynIterator<'_> {
+        self.tree.range_dyn(start, end)
     }
 
     fn write_batch(&mut self, batch: impl IntoIterator<Item = ChangeItem>) {
         let changes = batch.into_iter().collect::<Vec<_>>();
-        self.pending_changes = changes.clone();
+        self.pending_changes.extend(changes.iter().cloned());
         self.tree.write_batch(changes);
     }
 }
 
+impl KVStoreExt for IAVLDB {
+    fn range<R>(&self, bounds: R) -> impl DoubleEndedIterator<Item = (&[u8], &[u8])>
+    where
+        R: RangeBounds<Vec<u8>> + Clone,
+    {
+        self.tree.range(bounds)
+    }
+}
+
 impl IAVLDB {
-    pub fn save_version(&mut self) -> Output<Sha256> {
+    // save_version fails without saving the version if a change is too large for the WAL.
+    pub fn save_version(&mut self) -> Result<Output<Sha256>, DbError> {
+        let (result, _) = self.commit(|db| {
+            if db.flush_due() {
+                db.flush_wal();
+            }
+        })?;
+        Ok(result)
+    }
+
+    // save_version_async is like `save_version`, but the WAL is flushed on a separate thread,
+    // the returned future resolves once the version is durable, so it don't block the executor.
+    // the tree and the node db are updated before it returns, the future don't need to be polled
+    // to read the new version.
+    #[cfg(feature = "async")]
+    pub fn save_version_async(&mut self) -> impl Future<Output = Result<Output<Sha256>, DbError>> {
+        let saved = self.commit(|db| {
+            db.flush_due().then(|| {
+                db.unflushed = 0;
+                let wal = db.wal.clone();
+                db.flusher.get_or_insert_with(|| Flusher::new(wal)).flush()
+            })
+        });
+        async move {
+            let (result, flush) = saved?;
+            if let Some(flush) = flush {
+                flush.await?;
+            }
+            Ok(result)
+        }
+    }
+
+    // commit saves the version, the entries are written to the WAL before the node db, `flush`
+    // is called in between to flush the WAL as needed, its result is returned along.
+    fn commit<T>(
+        &mut self,
+        flush: impl FnOnce(&mut Self) -> T,
+    ) -> Result<(Output<Sha256>, T), DbError> {
+        check_changes(&self.pending_changes)?;
         let result = *self.tree.save_version();
-        let entry = Entry {
-            version: self.tree.version(),
-            changes: mem::take(&mut self.pending_changes),
-        };
-        self.wal.write(entry);
+        let version = self.tree.version();
+        let notify = (!self.subscribers.is_empty()).then(|| Changeset {
+            version,
+            changes: self.pending_changes.clone(),
+            root_hash: Some(result.to_vec()),
+        });
+        let changes = mem::take(&mut self.pending_changes);
+        for entry in split_changes(version, changes, Some(result.to_vec())) {
+            self.wal.write(WalEntry(Ok(entry)));
+        }
+        let flushed = flush(self);
+
+        self.nodes.save_version(version, self.tree.root())?;
+        // the old versions are not accessible through the db, release them.
+        self.tree
+            .prune_to(version)
+            .map_err(DbError::VersionMismatch)?;
+
+        if let Some(entry) = notify {
+            for f in &mut self.subscribers {
+                f(&entry);
+            }
+        }
+        Ok((result, flushed))
+    }
+
+    // flush_due counts the version written to the WAL, returns if it should be flushed.
+    fn flush_due(&mut self) -> bool {
+        self.unflushed += 1;
+        match self.flush_policy {
+            FlushPolicy::EveryVersion => true,
+            FlushPolicy::EveryN(n) => self.unflushed >= n,
+            FlushPolicy::Manual => false,
+        }
+    }
+
+    // flush_wal writes the buffered WAL entries to the files.
+    pub fn flush_wal(&mut self) {
         self.wal.flush();
-        result
+        self.unflushed = 0;
+    }
+
+    // subscribe registers a callback called with the changeset of each saved version after it's
+    // persisted, replaying the changesets with `write_batch` and `save_version` reproduces the tree.
+    pub fn subscribe(&mut self, f: impl FnMut(&Changeset) + Send + 'static) {
+        self.subscribers.push(Box::new(f));
+    }
+
+    // compact replaces the WAL with a snapshot of the latest saved version, so the replay on open
+    // starts from it, it's an error if there are uncommitted changes.
+    pub fn compact(&mut self) -> Result<(), DbError> {
+        if !self.pending_changes.is_empty() {
+            return Err(DbError::InvalidOperation(
+                "can't compact with uncommitted changes".to_string(),
+            ));
+        }
+        self.snapshot_wal()
+    }
+
+    // snapshot_wal replaces the WAL with a snapshot of the latest saved version, the tree is
+    // rebuilt from it on open if the node db is lost.
+    fn snapshot_wal(&mut self) -> Result<(), DbError> {
+        let version = self.tree.version();
+        if version == 0 {
+            // nothing is saved yet
+            return self.replace_wal(Vec::new());
+        }
+        // the root hash is the one of the tree rebuilt on replay, verified there.
+        let mut rebuilt = IAVLTree::<Sha256>::from_sorted(
+            self.tree
+                .range(..)
+                .map(|(key, value)| (key.to_vec(), value.to_vec())),
+            version,
+        );
+        let root_hash = rebuilt.root_hash().to_vec();
+        let changes = rebuilt
+            .range(..)
+            .map(|(key, value)| (key.to_vec(), Some(value.to_vec())))
+            .collect::<Vec<_>>();
+        check_changes(&changes)?;
+        let entries = split_changes(version, changes, Some(root_hash));
+
+        self.replace_wal(entries)
+    }
+
+    // replace_wal rewrites the WAL with the entries, the nodes of their versions must be
+    // persisted already.
+    fn replace_wal(&mut self, entries: Vec<Changeset>) -> Result<(), DbError> {
+        // walcraft's purge removes the whole directory which contains the node db,
+        // so the WAL files are removed manually.
+        self.flush_wal();
+        for file in std::fs::read_dir(&self.path)? {
+            let file = file?;
+            let name = file.file_name();
+            let name = name.to_string_lossy();
+            if name == "meta" || (name.starts_with("log_") && name.ends_with(".bin")) {
+                std::fs::remove_file(file.path())?;
+            }
+        }
+        self.wal = Wal::new(
+            self.path
+                .to_str()
+                .ok_or_else(|| DbError::Io("invalid db path".to_string()))?,
+            None,
+        );
+        for entry in entries {
+            self.wal.write(WalEntry(Ok(entry)));
+        }
+        self.wal.flush();
+        Ok(())
+    }
+
+    // get_at_version reads the value of the key at a saved version, the roots of all the saved
+    // versions are kept in the node db, and the recently read ones are cached along with the
+    // nodes loaded through them.
+    pub fn get_at_version(&self, version: u64, key: &[u8]) -> Result<Option<Vec<u8>>, DbError> {
+        if version == self.tree.version() {
+            let root = self
+                .tree
+                .saved_root(version)
+                .map_err(DbError::VersionMismatch)?;
+            return Ok(root.and_then(|root| root.get_with_index(key).0.map(<[u8]>::to_vec)));
+        }
+
+        let mut history = self.history.borrow_mut();
+        let root = match history.iter().position(|(v, _)| *v == version) {
+            Some(i) => history.remove(i).unwrap().1,
+            None => self.nodes.root(version)?.ok_or_else(|| {
+                DbError::VersionMismatch(format!("version {} is not saved", version))
+            })?,
+        };
+        let value = root
+            .as_ref()
+            .and_then(|root| root.get_with_index(key).0.map(<[u8]>::to_vec));
+        history.push_front((version, root));
+        history.truncate(HISTORY_CACHE_SIZE);
+        Ok(value)
+    }
+
+    // iter_committed iterates the latest saved version in order, the pending changes are not
+    // included, the pairs are copied one by one as they are iterated.
+    pub fn iter_committed(&self) -> impl Iterator<Item = (Vec<u8>, Vec<u8>)> + '_ {
+        let root = self.tree.saved_root(self.tree.version()).ok().flatten();
+        TreeIterator::new(root.map(|root| &**root), ..)
+            .map(|(key, value)| (key.to_vec(), value.to_vec()))
+    }
+
+    // export_snapshot writes the snapshot of the latest saved version,
+    // which can be imported to bootstrap another db.
+    pub fn export_snapshot(&self, w: &mut impl Write) -> io::Result<()> {
+        let version = self.tree.version();
+        let root = self
+            .tree
+            .saved_root(version)
+            .map_err(|err| io::Error::new(io::ErrorKind::NotFound, err))?;
+        let root_hash = match root {
+            Some(root) => *root.cached_hash().unwrap(),
+            None => Sha256::digest(b""),
+        };
+        export_tree(version, root, &root_hash, w)
+    }
+
+    // import_snapshot creates a db at `path` with the tree loaded from the snapshot,
+    // the db must be empty, and the root hash is verified against the exported one.
+    // the WAL starts with the snapshot too, the later changesets are deltas on top of it.
+    pub fn import_snapshot(path: &str, r: &mut impl Read) -> Result<Self, DbError> {
+        let mut db = Self::new(path)?;
+        if db.tree.version() != 0 || !db.tree.is_empty() {
+            return Err(DbError::VersionMismatch(format!(
+                "db is not empty: {}",
+                path
+            )));
+        }
+
+        let (version, root, expect_hash) = import_tree(r)?;
+        let mut tree = IAVLTree::load(version, root);
+        if tree.root_hash().as_slice() != expect_hash {
+            return Err(DbError::Decode(format!(
+                "root hash mismatch of snapshot at version {}",
+                version
+            )));
+        }
+        db.nodes.save_tree(version, tree.root())?;
+        db.tree = tree;
+        db.snapshot_wal()?;
+        Ok(db)
+    }
+}
+
+// the buffered WAL entries are flushed on drop, walcraft reports the write errors itself.
+impl Drop for IAVLDB {
+    fn drop(&mut self) {
+        if self.unflushed > 0 {
+            self.flush_wal();
+        }
+    }
+}
+
+// the overhead of an entry in bincode, the checksum, the version and the root hash included, and
+// the overhead of each change item.
+const ENTRY_OVERHEAD: usize = 80;
+const ITEM_OVERHEAD: usize = 32;
+
+// check_changes fails if a change item alone is too large for an entry of the WAL.
+fn check_changes(changes: &[ChangeItem]) -> Result<(), DbError> {
+    for (key, value) in changes {
+        let item_size = key.len() + value.as_ref().map_or(0, Vec::len) + ITEM_OVERHEAD;
+        if ENTRY_OVERHEAD + item_size > MAX_ENTRY_SIZE {
+            return Err(DbError::InvalidOperation(format!(
+                "key-value pair too large for WAL: {} bytes",
+                item_size
+            )));
+        }
+    }
+    Ok(())
+}
+
+// split_changes splits the changes of a version into the entries small enough for the WAL, the
+// changes are checked by `check_changes` already, there's one entry at least.
+fn split_changes(
+    version: u64,
+    changes: Vec<ChangeItem>,
+    root_hash: Option<Vec<u8>>,
+) -> Vec<Changeset> {
+    let mut entries = Vec::new();
+    let mut chunk = Vec::new();
+    let mut size = ENTRY_OVERHEAD;
+    for (key, value) in changes {
+        let item_size = key.len() + value.as_ref().map_or(0, Vec::len) + ITEM_OVERHEAD;
+        if size + item_size > MAX_ENTRY_SIZE {
+            entries.push(mem::take(&mut chunk));
+            size = ENTRY_OVERHEAD;
+        }
+        chunk.push((key, value));
+        size += item_size;
+    }
+    entries.push(chunk);
+    entries
+        .into_iter()
+        .map(|changes| Changeset {
+            version,
+            changes,
+            root_hash: root_hash.clone(),
+        })
+        .collect()
+}
+
+// truncate_torn_tail drops the torn entries at the end of the last WAL file, along with the
+// entries after `version` which belong to a partially written version, the complete entries are
+// never touched, it's an error if the torn entries are not in the last file.
+fn truncate_torn_tail(path: &Path, version: u64) -> Result<(), DbError> {
+    let meta = std::fs::read_to_string(path.join("meta"))?;
+    let current = meta
+        .split_whitespace()
+        .nth(1)
+        .and_then(|pointer| pointer.parse::<usize>().ok())
+        .ok_or_else(|| DbError::WalCorruption("invalid WAL meta".to_string()))?;
+    let file = path.join(format!("log_{}.bin", current));
+    let bytes = std::fs::read(&file)?;
+
+    // the records are the entries prefixed with their u16 lengths.
+    let mut pos = 0;
+    while pos < bytes.len() {
+        let complete = bytes
+            .get(pos..pos + 2)
+            .map(|len| pos + 2 + u16::from_ne_bytes([len[0], len[1]]) as usize)
+            .filter(|end| *end > pos + 2)
+            .and_then(|end| bytes.get(pos + 2..end))
+            .and_then(|record| bincode::deserialize::<WalEntry>(record).ok())
+            .is_some_and(|entry| entry.0.is_ok_and(|entry| entry.version <= version));
+        if !complete {
+            std::fs::File::options()
+                .write(true)
+                .open(&file)?
+                .set_len(pos as u64)?;
+            return Ok(());
+        }
+        pos += 2 + u16::from_ne_bytes([bytes[pos], bytes[pos + 1]]) as usize;
+    }
+    Err(DbError::WalCorruption(format!(
+        "the torn WAL entries are not found in {}",
+        file.display()
+    )))
+}
+
+// Flusher flushes the WAL for `save_version_async` on a thread spawned once and kept for the
+// lifetime of the db, the flushes are done in the order requested, dropping it waits for the
+// pending ones.
+#[cfg(feature = "async")]
+struct Flusher {
+    sender: Option<mpsc::Sender<Arc<Mutex<FlushState>>>>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+#[cfg(feature = "async")]
+impl Flusher {
+    fn new(wal: Wal<WalEntry>) -> Self {
+        let (sender, receiver) = mpsc::channel::<Arc<Mutex<FlushState>>>();
+        let handle = thread::spawn(move || {
+            for state in receiver {
+                // walcraft panics on the write errors, reported through the future instead.
+                let result = panic::catch_unwind(AssertUnwindSafe(|| wal.flush()))
+                    .map_err(|_| DbError::Io("failed to flush the WAL".to_string()));
+                state
+                    .lock()
+                    .unwrap_or_else(|err| err.into_inner())
+                    .resolve(result);
+            }
+        });
+        Flusher {
+            sender: Some(sender),
+            handle: Some(handle),
+        }
+    }
+
+    // flush requests a flush of the WAL entries written so far.
+    fn flush(&self) -> FlushFuture {
+        let state = Arc::new(Mutex::new(FlushState::default()));
+        let sent = self.sender.as_ref().unwrap().send(state.clone());
+        if sent.is_err() {
+            state
+                .lock()
+                .unwrap_or_else(|err| err.into_inner())
+                .resolve(Err(DbError::Io("the WAL flusher exited".to_string())));
+        }
+        FlushFuture(state)
+    }
+}
+
+#[cfg(feature = "async")]
+impl Drop for Flusher {
+    fn drop(&mut self) {
+        // closing the channel stops the thread after the pending flushes.
+        self.sender.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+// FlushFuture resolves when the WAL flush requested to the flusher completes.
+#[cfg(feature = "async")]
+struct FlushFuture(Arc<Mutex<FlushState>>);
+
+#[cfg(feature = "async")]
+#[derive(Default)]
+struct FlushState {
+    result: Option<Result<(), DbError>>,
+    waker: Option<Waker>,
+}
+
+#[cfg(feature = "async")]
+impl FlushState {
+    fn resolve(&mut self, result: Result<(), DbError>) {
+        self.result = Some(result);
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl Future for FlushFuture {
+    type Output = Result<(), DbError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.0.lock().unwrap_or_else(|err| err.into_inner());
+        match state.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::node::Node;
     use crate::overlay::Overlay;
 
+    #[test]
+    fn test_open_over_tall_node() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let nodes = NodeDB::open(dir.path().join("nodes")).unwrap();
+            let node = |i: u8, height, children: Option<(NodeRef, NodeRef)>| {
+                let (left, right) = children.unzip();
+                NodeRef::new(Node {
+                    height,
+                    size: 2,
+                    version: 1,
+                    key: vec![i],
+                    value: vec![i],
+                    left,
+                    right,
+                    hash: Some(Output::<Sha256>::clone_from_slice(&[i; 32])),
+                })
+            };
+            // the inner node deep in the tree is as high as the root.
+            let inner = node(1, 2, Some((node(2, 0, None), node(3, 0, None))));
+            let root = node(4, 2, Some((node(5, 0, None), inner)));
+            nodes.save_tree(1, Some(&root)).unwrap();
+        }
+
+        // opened lazily, the corrupted node is only read by the verification.
+        let path = dir.path().to_str().unwrap();
+        let root = *IAVLDB::new(path).unwrap().tree.root_hash();
+        let err = IAVLDB::open_verified(path, root).err().unwrap();
+        assert_eq!(err, DbError::Decode("node height 2 exceeds 1".to_string()));
+    }
+
     #[test]
     fn test_persisted_db() {
         let dir = tempfile::tempdir().unwrap();
@@ -101,12 +810,719 @@ mod tests {
             overlay.flush();
         }
 
-        db.save_version();
+        db.save_version().unwrap();
 
         // reload db
+        drop(db);
         let db = IAVLDB::new(dir.path().to_str().unwrap()).unwrap();
         assert_eq!(db.get(b"key1"), None);
         assert_eq!(db.get(b"key2"), Some(b"value2".as_ref()));
         assert_eq!(db.get(b"removed"), None);
     }
+
+    #[test]
+    fn test_set_remove() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        let mut db = IAVLDB::new(path).unwrap();
+        db.set(b"key1".to_vec(), b"value1".to_vec());
+        db.set(b"key2".to_vec(), b"value2".to_vec());
+        db.write_batch([(b"key3".to_vec(), Some(b"value3".to_vec()))]);
+        db.remove(b"key1");
+        assert_eq!(db.get(b"key1"), None);
+        assert_eq!(db.get(b"key2"), Some(b"value2".as_ref()));
+        let root = db.save_version().unwrap();
+
+        db.set(b"key1".to_vec(), b"uncommitted".to_vec());
+        drop(db);
+
+        // only the saved changes are replayed from the WAL
+        std::fs::remove_dir_all(dir.path().join("nodes")).unwrap();
+        let mut db = IAVLDB::new(path).unwrap();
+        assert_eq!(db.get(b"key1"), None);
+        assert_eq!(db.get(b"key2"), Some(b"value2".as_ref()));
+        assert_eq!(db.get(b"key3"), Some(b"value3".as_ref()));
+        assert_eq!(*db.tree.root_hash(), root);
+    }
+
+    #[test]
+    fn test_subscribe() {
+        use std::sync::mpsc;
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut db = IAVLDB::new(dir.path().to_str().unwrap()).unwrap();
+        let (tx, rx) = mpsc::channel();
+        db.subscribe(move |entry| tx.send(entry.clone()).unwrap());
+
+        db.write_batch([
+            (b"key1".to_vec(), Some(b"value1".to_vec())),
+            (b"key2".to_vec(), Some(b"value2".to_vec())),
+        ]);
+        db.save_version().unwrap();
+        db.set(b"key3".to_vec(), b"value3".to_vec());
+        db.remove(b"key1");
+        db.save_version().unwrap();
+        let root = db.save_version().unwrap();
+
+        let entries = rx.try_iter().collect::<Vec<_>>();
+        assert_eq!(
+            entries
+                .iter()
+                .map(|entry| entry.version)
+                .collect::<Vec<_>>(),
+            vec![1, 2, 3]
+        );
+        assert!(entries[2].changes.is_empty());
+
+        let mut replica = IAVLTree::new();
+        for entry in entries {
+            replica.write_batch(entry.changes);
+            replica.save_version();
+        }
+        assert_eq!(*replica.root_hash(), root);
+    }
+
+    #[test]
+    fn test_flush_policy() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        let mut db = IAVLDB::with_flush_policy(path, FlushPolicy::EveryN(5)).unwrap();
+        for i in 0u32..7 {
+            db.set(i.to_be_bytes().to_vec(), i.to_be_bytes().to_vec());
+            db.save_version().unwrap();
+        }
+        // the last two versions are only buffered, they'd be lost on crash
+        assert_eq!(wal_entries(path).len(), 5);
+
+        db.flush_wal();
+        assert_eq!(wal_entries(path).len(), 7);
+        let root = db.save_version().unwrap();
+        db.flush_wal();
+        drop(db);
+
+        // the WAL alone recovers all the versions
+        std::fs::remove_dir_all(dir.path().join("nodes")).unwrap();
+        let mut db = IAVLDB::with_flush_policy(path, FlushPolicy::Manual).unwrap();
+        assert_eq!(db.tree.version(), 8);
+        assert_eq!(*db.tree.root_hash(), root);
+
+        db.set(b"key".to_vec(), b"value".to_vec());
+        db.save_version().unwrap();
+        assert_eq!(wal_entries(path).len(), 8);
+    }
+
+    #[test]
+    fn test_load_nodes_without_wal() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        let mut db = IAVLDB::new(path).unwrap();
+
+        for version in 0u32..100 {
+            db.write_batch((0u32..100).map(|i| {
+                let key = (version * 100 + i).to_be_bytes().to_vec();
+                (key.clone(), Some(key))
+            }));
+            db.save_version().unwrap();
+        }
+        db.write_batch([(0u32.to_be_bytes().to_vec(), None)]);
+        let root = db.save_version().unwrap();
+        drop(db);
+
+        // the nodes are loaded lazily from the node db, the WAL is not needed.
+        std::fs::remove_file(dir.path().join("meta")).unwrap();
+        let mut db = IAVLDB::new(path).unwrap();
+        assert_eq!(db.tree.version(), 101);
+        assert_eq!(db.get(&0u32.to_be_bytes()), None);
+        for i in [1u32, 4321, 9999] {
+            assert_eq!(db.get(&i.to_be_bytes()), Some(i.to_be_bytes().as_ref()));
+        }
+        assert_eq!(db.get(&10000u32.to_be_bytes()), None);
+        assert_eq!(db.range(..).count(), 9999);
+
+        assert_eq!(*db.tree.root_hash(), root);
+    }
+
+    #[test]
+    fn test_iter_committed() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut db = IAVLDB::new(dir.path().to_str().unwrap()).unwrap();
+        assert_eq!(db.iter_committed().count(), 0);
+
+        db.write_batch([
+            (b"key1".to_vec(), Some(b"value1".to_vec())),
+            (b"key2".to_vec(), Some(b"value2".to_vec())),
+        ]);
+        assert_eq!(db.iter_committed().count(), 0);
+        db.save_version().unwrap();
+
+        db.write_batch([
+            (b"key1".to_vec(), None),
+            (b"key2".to_vec(), Some(b"new".to_vec())),
+            (b"key3".to_vec(), Some(b"value3".to_vec())),
+        ]);
+        assert_eq!(
+            db.iter_committed().collect::<Vec<_>>(),
+            vec![
+                (b"key1".to_vec(), b"value1".to_vec()),
+                (b"key2".to_vec(), b"value2".to_vec()),
+            ]
+        );
+
+        // loaded lazily from the node db after reopen
+        db.save_version().unwrap();
+        drop(db);
+        let db = IAVLDB::new(dir.path().to_str().unwrap()).unwrap();
+        assert_eq!(
+            db.iter_committed().collect::<Vec<_>>(),
+            vec![
+                (b"key2".to_vec(), b"new".to_vec()),
+                (b"key3".to_vec(), b"value3".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut db = IAVLDB::new(dir.path().to_str().unwrap()).unwrap();
+        for version in 0u32..10 {
+            db.write_batch((0u32..500).map(|i| {
+                let key = ((version * 300 + i) % 4000).to_be_bytes().to_vec();
+                let value = if i % 7 == 0 {
+                    None
+                } else {
+                    Some(version.to_be_bytes().to_vec())
+                };
+                (key, value)
+            }));
+            db.save_version().unwrap();
+        }
+        let root = db.save_version().unwrap();
+
+        let mut snapshot = Vec::new();
+        db.export_snapshot(&mut snapshot).unwrap();
+
+        // corrupted snapshot
+        let target = tempfile::tempdir().unwrap();
+        let mut corrupted = snapshot.clone();
+        let n = corrupted.len();
+        corrupted[n - 1] ^= 1;
+        assert!(
+            IAVLDB::import_snapshot(target.path().to_str().unwrap(), &mut &corrupted[..]).is_err()
+        );
+
+        let target = tempfile::tempdir().unwrap();
+        let path = target.path().to_str().unwrap();
+        let mut imported = IAVLDB::import_snapshot(path, &mut &snapshot[..]).unwrap();
+        assert_eq!(imported.tree.version(), 11);
+        assert!(imported.range(..).eq(db.range(..)));
+        assert_eq!(imported.save_version().unwrap(), root);
+
+        // importing into a non-empty db
+        assert!(IAVLDB::import_snapshot(path, &mut &snapshot[..]).is_err());
+
+        // the imported tree is persisted
+        drop(imported);
+        let imported = IAVLDB::new(path).unwrap();
+        assert_eq!(imported.tree.version(), 12);
+        assert!(imported.range(..).eq(db.range(..)));
+    }
+
+    #[test]
+    fn test_import_snapshot_wal() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut db = IAVLDB::new(dir.path().to_str().unwrap()).unwrap();
+        db.write_batch((0u32..50).map(|i| (i.to_be_bytes().to_vec(), Some(b"value".to_vec()))));
+        db.save_version().unwrap();
+        let mut snapshot = Vec::new();
+        db.export_snapshot(&mut snapshot).unwrap();
+
+        let target = tempfile::tempdir().unwrap();
+        let path = target.path().to_str().unwrap();
+        let mut imported = IAVLDB::import_snapshot(path, &mut &snapshot[..]).unwrap();
+        assert!(wal_entries(path).iter().all(|entry| entry.version == 1));
+        imported.set(b"key".to_vec(), b"value".to_vec());
+        imported.save_version().unwrap();
+        drop(imported);
+
+        // the WAL alone recovers the imported keys along with the later versions
+        std::fs::remove_dir_all(target.path().join("nodes")).unwrap();
+        let imported = IAVLDB::new(path).unwrap();
+        assert_eq!(imported.tree.version(), 2);
+        assert_eq!(imported.range(..).count(), 51);
+        assert!(db
+            .range(..)
+            .all(|(key, value)| imported.get(key) == Some(value)));
+    }
+
+    #[test]
+    fn test_replay_invalid_snapshot() {
+        let entry = |changes: Vec<ChangeItem>, root_hash: Vec<u8>| Changeset {
+            version: 5,
+            changes,
+            root_hash: Some(root_hash),
+        };
+        let mut tree = IAVLTree::<Sha256>::from_sorted([(b"a".to_vec(), b"1".to_vec())], 5);
+        let valid = tree.root_hash().to_vec();
+        let cases = [
+            // the delta of a version missing its base
+            (
+                entry(vec![(b"a".to_vec(), Some(b"2".to_vec()))], valid.clone()),
+                DbError::RootMismatch(
+                    "root hash mismatch of the snapshot at version 5".to_string(),
+                ),
+            ),
+            (
+                entry(
+                    vec![(b"a".to_vec(), Some(b"1".to_vec())), (b"b".to_vec(), None)],
+                    valid.clone(),
+                ),
+                DbError::WalCorruption("deletion in the snapshot at version 5".to_string()),
+            ),
+        ];
+        for (changeset, expect) in cases {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().to_str().unwrap();
+            let wal = Wal::<WalEntry>::new(path, None);
+            wal.write(WalEntry(Ok(changeset)));
+            wal.flush();
+            drop(wal);
+            assert_eq!(IAVLDB::new(path).err(), Some(expect));
+        }
+
+        // the valid snapshot
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        let wal = Wal::<WalEntry>::new(path, None);
+        wal.write(WalEntry(Ok(entry(
+            vec![(b"a".to_vec(), Some(b"1".to_vec()))],
+            valid,
+        ))));
+        wal.flush();
+        drop(wal);
+        let db = IAVLDB::new(path).unwrap();
+        assert_eq!(db.tree.version(), 5);
+        assert_eq!(db.get(b"a"), Some(b"1".as_ref()));
+    }
+
+    // wal_entries reads the WAL with a separate instance, the db's one is locked in write mode.
+    fn wal_entries(path: &str) -> Vec<Changeset> {
+        Wal::<WalEntry>::new(path, None)
+            .read()
+            .unwrap()
+            .map(|entry| entry.0.unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_compact() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        let mut db = IAVLDB::new(path).unwrap();
+        for version in 0u32..20 {
+            db.write_batch((0u32..200).map(|i| {
+                let key = ((version * 150 + i) % 3000).to_be_bytes().to_vec();
+                let value = if i % 5 == 0 {
+                    None
+                } else {
+                    Some(version.to_be_bytes().to_vec())
+                };
+                (key, value)
+            }));
+            db.save_version().unwrap();
+        }
+        let expect = db
+            .range(..)
+            .map(|(key, value)| (key.to_vec(), value.to_vec()))
+            .collect::<Vec<_>>();
+        assert_eq!(wal_entries(path).len(), 20);
+
+        db.write_batch([(b"uncommitted".to_vec(), Some(b"value".to_vec()))]);
+        assert!(db.compact().is_err());
+        db.save_version().unwrap();
+        db.write_batch([(b"uncommitted".to_vec(), None)]);
+        db.save_version().unwrap();
+
+        db.compact().unwrap();
+        let entries = wal_entries(path);
+        assert!(entries.len() > 1 && entries.len() < 22);
+        assert!(entries.iter().all(|entry| entry.version == 22));
+        assert!(entries.iter().all(|entry| entry.root_hash.is_some()));
+
+        // the WAL alone reproduces the state after the node db is lost.
+        drop(db);
+        std::fs::remove_dir_all(dir.path().join("nodes")).unwrap();
+        let mut db = IAVLDB::new(path).unwrap();
+        assert_eq!(db.tree.version(), 22);
+        for (key, value) in &expect {
+            assert_eq!(db.get(key), Some(value.as_slice()));
+        }
+        assert_eq!(db.range(..).count(), expect.len());
+
+        // new changesets are appended after the snapshot.
+        db.write_batch([(b"new".to_vec(), Some(b"value".to_vec()))]);
+        db.save_version().unwrap();
+        drop(db);
+        let db = IAVLDB::new(path).unwrap();
+        assert_eq!(db.tree.version(), 23);
+        assert_eq!(db.get(b"new"), Some(b"value".as_ref()));
+        assert_eq!(db.range(..).count(), expect.len() + 1);
+    }
+
+    #[test]
+    fn test_open_verified() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        let mut db = IAVLDB::new(path).unwrap();
+        db.write_batch([(b"key1".to_vec(), Some(b"value1".to_vec()))]);
+        let root = db.save_version().unwrap();
+        drop(db);
+
+        assert!(IAVLDB::open_verified(path, root).is_ok());
+        let err = IAVLDB::open_verified(path, Sha256::digest(b""))
+            .err()
+            .unwrap();
+        assert!(matches!(err, DbError::RootMismatch(_)), "{}", err);
+
+        // tamper the value in the WAL with a valid checksum, and replay it without the node db.
+        for file in ["meta", "log_0.bin"] {
+            std::fs::remove_file(dir.path().join(file)).unwrap();
+        }
+        let wal: Wal<WalEntry> = Wal::new(path, None);
+        wal.write(WalEntry(Ok(Changeset {
+            version: 1,
+            changes: vec![(b"key1".to_vec(), Some(b"Value1".to_vec()))],
+            root_hash: Some(root.to_vec()),
+        })));
+        wal.flush();
+        drop(wal);
+        std::fs::remove_dir_all(dir.path().join("nodes")).unwrap();
+        let err = IAVLDB::open_verified(path, root).err().unwrap();
+        assert_eq!(
+            err,
+            DbError::RootMismatch("root hash mismatch on replay at version 1".to_string())
+        );
+    }
+
+    #[test]
+    fn test_get_at_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        let mut db = IAVLDB::new(path).unwrap();
+        for version in 1u64..=20 {
+            db.set(b"key".to_vec(), version.to_be_bytes().to_vec());
+            db.set(version.to_be_bytes().to_vec(), vec![]);
+            db.save_version().unwrap();
+        }
+        db.remove(b"key");
+        db.save_version().unwrap();
+
+        for version in (1u64..=20).chain([3, 3, 20]) {
+            assert_eq!(
+                db.get_at_version(version, b"key").unwrap(),
+                Some(version.to_be_bytes().to_vec())
+            );
+            assert_eq!(
+                db.get_at_version(version, &(version + 1).to_be_bytes())
+                    .unwrap(),
+                None
+            );
+        }
+        assert_eq!(db.history.borrow().len(), HISTORY_CACHE_SIZE);
+        assert_eq!(db.history.borrow()[0].0, 20);
+        assert_eq!(db.get_at_version(21, b"key").unwrap(), None);
+        assert!(db.get_at_version(22, b"key").is_err());
+        assert!(db.get_at_version(0, b"key").is_err());
+
+        // the historical versions are still readable after reopen
+        drop(db);
+        let db = IAVLDB::new(path).unwrap();
+        assert_eq!(
+            db.get_at_version(5, b"key").unwrap(),
+            Some(5u64.to_be_bytes().to_vec())
+        );
+    }
+
+    #[test]
+    fn test_corrupted_wal() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        let mut db = IAVLDB::new(path).unwrap();
+        db.write_batch([(b"key1".to_vec(), Some(b"value1".to_vec()))]);
+        db.save_version().unwrap();
+        db.write_batch([(b"key2".to_vec(), Some(b"value2".to_vec()))]);
+        db.save_version().unwrap();
+        drop(db);
+
+        // the entry is prefixed by the u16 length and the checksum, followed by the version, the
+        // number of changes, the length of the key and the key, then the tag of the option, the
+        // corrupted entry is not the last one, so it's not considered as torn.
+        let file = dir.path().join("log_0.bin");
+        let mut bz = std::fs::read(&file).unwrap();
+        assert_eq!(bz[34], 1);
+        bz[34] = 2;
+        std::fs::write(&file, bz).unwrap();
+
+        // the WAL is decoded even if the version is persisted in the node db.
+        let err = IAVLDB::new(path).err().unwrap();
+        assert!(matches!(err, DbError::Decode(_)), "{}", err);
+        assert!(err.to_string().starts_with("invalid WAL entry"));
+    }
+
+    #[test]
+    fn test_torn_wal() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        let file = dir.path().join("log_0.bin");
+        let mut db = IAVLDB::new(path).unwrap();
+        for i in 1u8..=2 {
+            db.set(vec![i], vec![i; 100]);
+            db.save_version().unwrap();
+        }
+        let len = std::fs::metadata(&file).unwrap().len();
+        db.set(vec![3], vec![3; 100]);
+        db.save_version().unwrap();
+        drop(db);
+
+        // crash in the middle of writing the entry of version 3, before the nodes are saved.
+        let torn_len = (len + std::fs::metadata(&file).unwrap().len()) / 2;
+        std::fs::File::options()
+            .write(true)
+            .open(&file)
+            .unwrap()
+            .set_len(torn_len)
+            .unwrap();
+        std::fs::remove_dir_all(dir.path().join("nodes")).unwrap();
+
+        let mut db = IAVLDB::new(path).unwrap();
+        assert_eq!(db.tree.version(), 2);
+        assert_eq!(db.get(&[2]), Some([2; 100].as_ref()));
+        assert_eq!(db.get(&[3]), None);
+
+        // the torn entry is dropped from the WAL, the new entries are replayed after it.
+        db.set(vec![3], vec![4; 100]);
+        db.save_version().unwrap();
+        drop(db);
+        std::fs::remove_dir_all(dir.path().join("nodes")).unwrap();
+        let db = IAVLDB::new(path).unwrap();
+        assert_eq!(db.tree.version(), 3);
+        assert_eq!(db.get(&[3]), Some([4; 100].as_ref()));
+    }
+
+    #[test]
+    fn test_large_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        let mut db = IAVLDB::new(path).unwrap();
+        for i in 0u32..100 {
+            db.set(i.to_be_bytes().to_vec(), vec![1; 1000]);
+        }
+        db.save_version().unwrap();
+        db.set(b"key".to_vec(), b"value".to_vec());
+        let root = db.save_version().unwrap();
+        drop(db);
+
+        // the large version is split into multiple entries
+        let entries = wal_entries(path);
+        assert!(entries.len() > 2);
+        assert!(entries[..entries.len() - 1].iter().all(|e| e.version == 1));
+        let len = std::fs::metadata(dir.path().join("log_0.bin"))
+            .unwrap()
+            .len();
+        assert!(len > 100_000);
+
+        drop(IAVLDB::new(path).unwrap());
+        assert_eq!(
+            std::fs::metadata(dir.path().join("log_0.bin"))
+                .unwrap()
+                .len(),
+            len
+        );
+        std::fs::remove_dir_all(dir.path().join("nodes")).unwrap();
+        let mut db = IAVLDB::new(path).unwrap();
+        assert_eq!(db.tree.version(), 2);
+        assert_eq!(*db.tree.root_hash(), root);
+
+        // a single change too large for the WAL is rejected, the version is not saved
+        db.set(b"large".to_vec(), vec![1; MAX_ENTRY_SIZE]);
+        assert!(matches!(
+            db.save_version(),
+            Err(DbError::InvalidOperation(_))
+        ));
+        assert_eq!(db.tree.version(), 2);
+    }
+
+    #[test]
+    fn test_torn_large_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        let file = dir.path().join("log_0.bin");
+        let mut db = IAVLDB::new(path).unwrap();
+        db.set(b"key".to_vec(), b"value".to_vec());
+        let root = db.save_version().unwrap();
+        let len = std::fs::metadata(&file).unwrap().len();
+        for i in 0u32..100 {
+            db.set(i.to_be_bytes().to_vec(), vec![1; 1000]);
+        }
+        db.save_version().unwrap();
+        drop(db);
+
+        // crash in the middle of the last entry of version 2, the complete entries of it are
+        // dropped along with the torn one.
+        let full_len = std::fs::metadata(&file).unwrap().len();
+        std::fs::File::options()
+            .write(true)
+            .open(&file)
+            .unwrap()
+            .set_len(full_len - 100)
+            .unwrap();
+        std::fs::remove_dir_all(dir.path().join("nodes")).unwrap();
+        let mut db = IAVLDB::new(path).unwrap();
+        assert_eq!(db.tree.version(), 1);
+        assert_eq!(*db.tree.root_hash(), root);
+        assert_eq!(std::fs::metadata(&file).unwrap().len(), len);
+
+        db.set(b"key".to_vec(), b"value1".to_vec());
+        let root = db.save_version().unwrap();
+        drop(db);
+        std::fs::remove_dir_all(dir.path().join("nodes")).unwrap();
+        let mut db = IAVLDB::new(path).unwrap();
+        assert_eq!(db.tree.version(), 2);
+        assert_eq!(*db.tree.root_hash(), root);
+    }
+
+    #[test]
+    fn test_torn_first_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        let file = dir.path().join("log_0.bin");
+        let mut db = IAVLDB::new(path).unwrap();
+        db.set(b"key".to_vec(), vec![1; 100]);
+        db.save_version().unwrap();
+        drop(db);
+
+        // no complete entry before the corruption, it's not recovered as torn
+        let len = std::fs::metadata(&file).unwrap().len();
+        std::fs::File::options()
+            .write(true)
+            .open(&file)
+            .unwrap()
+            .set_len(len / 2)
+            .unwrap();
+        std::fs::remove_dir_all(dir.path().join("nodes")).unwrap();
+        assert!(IAVLDB::new(path).is_err());
+        assert_eq!(std::fs::metadata(&file).unwrap().len(), len / 2);
+    }
+
+    #[test]
+    fn test_flush_on_drop() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        let mut db = IAVLDB::with_flush_policy(path, FlushPolicy::Manual).unwrap();
+        for i in 0u32..3 {
+            db.set(i.to_be_bytes().to_vec(), i.to_be_bytes().to_vec());
+            db.save_version().unwrap();
+        }
+        let root = db.save_version().unwrap();
+        assert!(wal_entries(path).is_empty());
+        drop(db);
+        assert_eq!(wal_entries(path).len(), 4);
+
+        // the WAL alone recovers all the versions
+        std::fs::remove_dir_all(dir.path().join("nodes")).unwrap();
+        let mut db = IAVLDB::with_flush_policy(path, FlushPolicy::Manual).unwrap();
+        assert_eq!(db.tree.version(), 4);
+        assert_eq!(*db.tree.root_hash(), root);
+
+        // nothing to flush after an explicit flush
+        db.set(b"key".to_vec(), b"value".to_vec());
+        db.save_version().unwrap();
+        db.flush_wal();
+        assert_eq!(db.unflushed, 0);
+        drop(db);
+        assert_eq!(wal_entries(path).len(), 5);
+    }
+
+    #[test]
+    fn test_initial_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        let mut db = IAVLDB::with_initial_version(path, 100).unwrap();
+        let mut tree = IAVLTree::<Sha256>::with_initial_version(100);
+        for i in 0u32..3 {
+            db.set(i.to_be_bytes().to_vec(), b"value".to_vec());
+            tree.set(i.to_be_bytes().to_vec(), b"value".to_vec());
+            assert_eq!(db.save_version().unwrap(), *tree.save_version());
+            assert_eq!(db.tree.version(), 100 + i as u64);
+        }
+        drop(db);
+
+        // the initial version is ignored once a version is saved
+        let mut db = IAVLDB::new(path).unwrap();
+        assert_eq!(db.tree.version(), 102);
+        assert_eq!(db.tree.root_hash(), tree.root_hash());
+
+        // the WAL alone recovers the versions
+        drop(db);
+        std::fs::remove_dir_all(dir.path().join("nodes")).unwrap();
+        let mut db = IAVLDB::with_initial_version(path, 100).unwrap();
+        assert_eq!(db.tree.version(), 102);
+        assert_eq!(db.tree.root_hash(), tree.root_hash());
+    }
+
+    // block_on polls the future on the current thread, parking until it's woken.
+    #[cfg(feature = "async")]
+    fn block_on<F: Future>(future: F) -> F::Output {
+        struct ThreadWaker(thread::Thread);
+
+        impl std::task::Wake for ThreadWaker {
+            fn wake(self: Arc<Self>) {
+                self.0.unpark();
+            }
+        }
+
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        let mut future = std::pin::pin!(future);
+        loop {
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(output) => return output,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_save_version_async() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        let mut db = IAVLDB::new(path).unwrap();
+        let mut roots = Vec::new();
+        for i in 0u32..3 {
+            db.set(i.to_be_bytes().to_vec(), i.to_be_bytes().to_vec());
+            let future = db.save_version_async();
+            // the new version is readable before the future is polled
+            assert_eq!(db.tree.version(), i as u64 + 1);
+            roots.push(block_on(future).unwrap());
+        }
+        assert_eq!(wal_entries(path).len(), 3);
+        assert_eq!(*db.tree.root_hash(), roots[2]);
+
+        // dropping the db waits for the pending flush, even if the future is dropped
+        db.set(b"key".to_vec(), b"value".to_vec());
+        drop(db.save_version_async());
+        drop(db);
+        assert_eq!(wal_entries(path).len(), 4);
+
+        // the WAL alone recovers the versions
+        std::fs::remove_dir_all(dir.path().join("nodes")).unwrap();
+        let db = IAVLDB::new(path).unwrap();
+        assert_eq!(db.tree.version(), 4);
+        assert_eq!(db.get(b"key"), Some(b"value".as_ref()));
+        assert_eq!(
+            db.get(&2u32.to_be_bytes()),
+            Some(2u32.to_be_bytes().as_ref())
+        );
+    }
 }
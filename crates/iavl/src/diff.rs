@@ -0,0 +1,204 @@
+use sha2::{Digest, Sha256};
+use std::cmp::Ordering;
+use std::ops::Bound;
+
+use super::node::Node;
+use super::types::KVStore;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    Inserted(Vec<u8>),
+    Updated(Vec<u8>, Vec<u8>),
+    Deleted(Vec<u8>),
+}
+
+// DiffIter walks the leaves of two trees in order and yields the changed keys, the stacks hold the
+// subtrees not visited yet, the smallest keys on the top, two subtrees with the same hash on the
+// top are skipped together without visiting the nodes within.
+// The hashes of both trees must be computed beforehand.
+pub struct DiffIter<'a, D: Digest = Sha256> {
+    from: Vec<&'a Node<D>>,
+    to: Vec<&'a Node<D>>,
+    // number of leaf pairs compared by key
+    #[cfg(test)]
+    compared: usize,
+}
+
+impl<'a, D: Digest> DiffIter<'a, D> {
+    pub fn new(from: Option<&'a Node<D>>, to: Option<&'a Node<D>>) -> Self {
+        DiffIter {
+            from: from.into_iter().collect(),
+            to: to.into_iter().collect(),
+            #[cfg(test)]
+            compared: 0,
+        }
+    }
+}
+
+// expand replaces the inner node on the top of the stack with its children.
+fn expand<D: Digest>(stack: &mut Vec<&Node<D>>) {
+    let node = stack.pop().unwrap();
+    stack.push(node.right.as_ref().unwrap());
+    stack.push(node.left.as_ref().unwrap());
+}
+
+impl<D: Digest> Iterator for DiffIter<'_, D> {
+    type Item = (Vec<u8>, Change);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match (self.from.last().copied(), self.to.last().copied()) {
+                (None, None) => return None,
+                (Some(from), None) => {
+                    if !from.is_leaf() {
+                        expand(&mut self.from);
+                        continue;
+                    }
+                    self.from.pop();
+                    return Some((from.key.clone(), Change::Deleted(from.value.clone())));
+                }
+                (None, Some(to)) => {
+                    if !to.is_leaf() {
+                        expand(&mut self.to);
+                        continue;
+                    }
+                    self.to.pop();
+                    return Some((to.key.clone(), Change::Inserted(to.value.clone())));
+                }
+                (Some(from), Some(to)) => {
+                    if from.hash.is_some() && from.hash == to.hash {
+                        self.from.pop();
+                        self.to.pop();
+                        continue;
+                    }
+                    // subtrees can only be identical if they have the same height
+                    if !from.is_leaf() && from.height >= to.height {
+                        expand(&mut self.from);
+                        continue;
+                    }
+                    if !to.is_leaf() {
+                        expand(&mut self.to);
+                        continue;
+                    }
+                    #[cfg(test)]
+                    {
+                        self.compared += 1;
+                    }
+                    match from.key.cmp(&to.key) {
+                        Ordering::Less => {
+                            self.from.pop();
+                            return Some((from.key.clone(), Change::Deleted(from.value.clone())));
+                        }
+                        Ordering::Greater => {
+                            self.to.pop();
+                            return Some((to.key.clone(), Change::Inserted(to.value.clone())));
+                        }
+                        Ordering::Equal => {
+                            self.from.pop();
+                            self.to.pop();
+                            // the leaf is rewritten with the same value
+                            if from.value == to.value {
+                                continue;
+                            }
+                            return Some((
+                                from.key.clone(),
+                                Change::Updated(from.value.clone(), to.value.clone()),
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Divergence is the key and the values in the two stores, `None` if it's absent in the store.
+pub type Divergence = (Vec<u8>, Option<Vec<u8>>, Option<Vec<u8>>);
+
+// first_divergence walks both stores in order, returns the first key whose value differs, for
+// debugging the root hash mismatches between the nodes.
+pub fn first_divergence(a: &impl KVStore, b: &impl KVStore) -> Option<Divergence> {
+    let mut a = a.range_dyn(Bound::Unbounded, Bound::Unbounded).peekable();
+    let mut b = b.range_dyn(Bound::Unbounded, Bound::Unbounded).peekable();
+    loop {
+        let order = match (a.peek(), b.peek()) {
+            (None, None) => return None,
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some((key_a, _)), Some((key_b, _))) => key_a.cmp(key_b),
+        };
+        match order {
+            Ordering::Less => {
+                let (key, value) = a.next().unwrap();
+                return Some((key, Some(value), None));
+            }
+            Ordering::Greater => {
+                let (key, value) = b.next().unwrap();
+                return Some((key, None, Some(value)));
+            }
+            Ordering::Equal => {
+                let ((key, value_a), (_, value_b)) = (a.next().unwrap(), b.next().unwrap());
+                if value_a != value_b {
+                    return Some((key, Some(value_a), Some(value_b)));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::first_divergence;
+    use crate::{IAVLTree, KVStore, MemTree};
+
+    #[test]
+    fn test_diff_skip_shared() {
+        let mut tree = IAVLTree::new();
+        for i in 0u32..10000 {
+            tree.set(i.to_be_bytes().to_vec(), i.to_be_bytes().to_vec());
+        }
+        tree.save_version();
+        tree.set(5000u32.to_be_bytes().to_vec(), b"updated".to_vec());
+        tree.save_version();
+
+        let mut diff = tree.diff(1, 2).unwrap();
+        let changes = diff.by_ref().collect::<Vec<_>>();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].0, 5000u32.to_be_bytes());
+        // only the updated leaf is compared, the subtrees along the path are shared
+        assert_eq!(diff.compared, 1);
+    }
+
+    #[test]
+    fn test_first_divergence() {
+        let items = (0u32..100).map(|i| (i.to_be_bytes().to_vec(), i.to_le_bytes().to_vec()));
+        let mut a = items.clone().collect::<MemTree>();
+        let mut b = items.collect::<MemTree>();
+        assert_eq!(first_divergence(&a, &b), None);
+
+        let key = 42u32.to_be_bytes().to_vec();
+        b.set(key.clone(), b"other".to_vec());
+        assert_eq!(
+            first_divergence(&a, &b),
+            Some((
+                key.clone(),
+                Some(42u32.to_le_bytes().to_vec()),
+                Some(b"other".to_vec())
+            ))
+        );
+
+        // the missing key in either store
+        b.remove(&key);
+        assert_eq!(
+            first_divergence(&a, &b),
+            Some((key.clone(), Some(42u32.to_le_bytes().to_vec()), None))
+        );
+        a.remove(&key);
+        a.set(b"z".to_vec(), b"z".to_vec());
+        assert_eq!(
+            first_divergence(&b, &a),
+            Some((b"z".to_vec(), None, Some(b"z".to_vec())))
+        );
+        assert_eq!(first_divergence(&MemTree::new(), &IAVLTree::new()), None);
+    }
+}
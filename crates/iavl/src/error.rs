@@ -0,0 +1,46 @@
+use std::fmt;
+use std::io;
+
+// DbError is the error returned by `IAVLDB`, the variants carry the messages for logging.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DbError {
+    // failed to access the files, the node db or the WAL
+    Io(String),
+    // the WAL entries are missing or out of order
+    WalCorruption(String),
+    // the version of the db don't match the operation
+    VersionMismatch(String),
+    // failed to decode the WAL entries, the persisted nodes, or the snapshot
+    Decode(String),
+    // the operation is not allowed in the current state of the db
+    InvalidOperation(String),
+    // the root hash of the replayed or loaded tree don't match the expected one
+    RootMismatch(String),
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DbError::Io(msg)
+            | DbError::WalCorruption(msg)
+            | DbError::VersionMismatch(msg)
+            | DbError::Decode(msg)
+            | DbError::InvalidOperation(msg)
+            | DbError::RootMismatch(msg) => f.write_str(msg),
+        }
+    }
+}
+
+impl std::error::Error for DbError {}
+
+impl From<io::Error> for DbError {
+    fn from(err: io::Error) -> Self {
+        DbError::Io(err.to_string())
+    }
+}
+
+impl From<sled::Error> for DbError {
+    fn from(err: sled::Error) -> Self {
+        DbError::Io(err.to_string())
+    }
+}
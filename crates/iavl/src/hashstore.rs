@@ -0,0 +1,132 @@
+use std::collections::HashMap;
+use std::ops::{Bound, RangeBounds};
+
+use super::types::{DynIterator, KVStore, KVStoreExt};
+
+// HashStore keeps the entries in a hash map, for the workloads which don't iterate, `get` and
+// `set` are O(1), the ranges collect and sort the entries within the bounds on every call.
+#[derive(Default)]
+pub struct HashStore {
+    pub map: HashMap<Vec<u8>, Vec<u8>>,
+}
+
+impl HashStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.map.clear();
+    }
+}
+
+impl FromIterator<(Vec<u8>, Vec<u8>)> for HashStore {
+    fn from_iter<I: IntoIterator<Item = (Vec<u8>, Vec<u8>)>>(iter: I) -> Self {
+        HashStore {
+            map: HashMap::from_iter(iter),
+        }
+    }
+}
+
+impl KVStore for HashStore {
+    fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        self.map.get(key).map(|v| v.as_slice())
+    }
+
+    fn set(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.map.insert(key, value);
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        self.map.remove(key);
+    }
+
+    fn remove_taking(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        self.map.remove(key)
+    }
+
+    fn range_dyn(&self, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>) -> DynIterator<'_> {
+        Box::new(
+            self.range((start, end))
+                .map(|(k, v)| (k.to_vec(), v.to_vec())),
+        )
+    }
+}
+
+impl KVStoreExt for HashStore {
+    // range scans the whole map and sorts the entries within the bounds, O(n log n) for each call,
+    // use `MemTree` if the iteration is frequent.
+    fn range<R>(&self, bounds: R) -> impl DoubleEndedIterator<Item = (&[u8], &[u8])>
+    where
+        R: RangeBounds<Vec<u8>>,
+    {
+        let mut items = self
+            .map
+            .iter()
+            .filter(|(k, _)| bounds.contains(*k))
+            .map(|(k, v)| (k.as_slice(), v.as_slice()))
+            .collect::<Vec<_>>();
+        items.sort_unstable_by_key(|(k, _)| *k);
+        items.into_iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemTree;
+
+    #[test]
+    fn test_hash_store() {
+        let mut store = HashStore::new();
+        store.set(b"key1".to_vec(), b"value1".to_vec());
+        store.set(b"key2".to_vec(), b"value2".to_vec());
+        store.set(b"key1".to_vec(), b"value3".to_vec());
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.get(b"key1"), Some(b"value3".as_ref()));
+        assert_eq!(store.get(b"key3"), None);
+
+        store.remove(b"key1");
+        assert_eq!(store.get(b"key1"), None);
+        assert_eq!(store.remove_taking(b"key2"), Some(b"value2".to_vec()));
+        assert!(store.is_empty());
+
+        store.write_batch([
+            (b"key1".to_vec(), Some(b"value1".to_vec())),
+            (b"key1".to_vec(), None),
+            (b"key2".to_vec(), Some(b"value2".to_vec())),
+        ]);
+        assert_eq!(store.get(b"key1"), None);
+        assert_eq!(store.get(b"key2"), Some(b"value2".as_ref()));
+    }
+
+    #[test]
+    fn test_hash_store_range() {
+        let items = (0u32..100)
+            .map(|i| (i.to_be_bytes().to_vec(), i.to_le_bytes().to_vec()))
+            .collect::<Vec<_>>();
+        let store = items.iter().cloned().collect::<HashStore>();
+        let tree = items.into_iter().collect::<MemTree>();
+
+        // the ranges are sorted like the ordered stores
+        let start = 10u32.to_be_bytes().to_vec();
+        let end = 50u32.to_be_bytes().to_vec();
+        assert!(store.range(..).eq(tree.range(..)));
+        assert!(store
+            .range(start.clone()..end.clone())
+            .rev()
+            .eq(tree.range(start.clone()..end.clone()).rev()));
+        assert!(store
+            .range_dyn(Bound::Excluded(start.clone()), Bound::Unbounded)
+            .eq(tree.range_dyn(Bound::Excluded(start), Bound::Unbounded)));
+        assert_eq!(store.prefix_iter(&[0, 0, 0]).count(), 100);
+    }
+}
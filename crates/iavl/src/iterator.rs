@@ -1,46 +1,53 @@
 use super::node::Node;
+use sha2::{Digest, Sha256};
 use std::ops::{Bound, RangeBounds};
 
-pub struct TreeIterator<'a, R>
+pub struct TreeIterator<'a, R, D = Sha256>
 where
     R: RangeBounds<Vec<u8>>,
+    D: Digest,
 {
-    stack: Vec<&'a Node>,
+    // the subtrees out of the bounds are never pushed, so a bounded range only visits the nodes
+    // along the paths to the bounds and the ones within, O(log n + results).
+    stack: Vec<&'a Node<D>>,
     bounds: R,
+    // number of nodes popped from the stack
+    #[cfg(test)]
+    visited: usize,
 }
 
-impl<R> TreeIterator<'_, R>
+impl<R, D> TreeIterator<'_, R, D>
 where
     R: RangeBounds<Vec<u8>>,
+    D: Digest,
 {
-    pub fn new(root: Option<&Node>, bounds: R) -> TreeIterator<'_, R> {
-        if let Some(root) = root {
-            TreeIterator {
-                stack: vec![root],
-                bounds,
-            }
-        } else {
-            TreeIterator {
-                stack: Vec::new(),
-                bounds,
-            }
+    pub fn new(root: Option<&Node<D>>, bounds: R) -> TreeIterator<'_, R, D> {
+        TreeIterator {
+            stack: root.into_iter().collect(),
+            bounds,
+            #[cfg(test)]
+            visited: 0,
         }
     }
 }
 
-impl<'a, R> Iterator for TreeIterator<'a, R>
+impl<'a, R, D> TreeIterator<'a, R, D>
 where
     R: RangeBounds<Vec<u8>>,
+    D: Digest,
 {
-    type Item = (&'a [u8], &'a [u8]);
-
-    fn next(&mut self) -> Option<Self::Item> {
+    // next_leaf returns the next leaf within the bounds in order.
+    fn next_leaf(&mut self) -> Option<&'a Node<D>> {
         while let Some(node) = self.stack.pop() {
+            #[cfg(test)]
+            {
+                self.visited += 1;
+            }
             if node.is_leaf() {
                 if start_bound_contains(self.bounds.start_bound(), &node.key)
                     && end_bound_contains(self.bounds.end_bound(), &node.key)
                 {
-                    return Some((&node.key, &node.value));
+                    return Some(node);
                 }
             } else {
                 if end_bound_contains(self.bounds.end_bound(), &node.key) {
@@ -53,19 +60,19 @@ where
         }
         None
     }
-}
 
-impl<R> DoubleEndedIterator for TreeIterator<'_, R>
-where
-    R: RangeBounds<Vec<u8>>,
-{
-    fn next_back(&mut self) -> Option<Self::Item> {
+    // next_back_leaf returns the next leaf within the bounds in reverse order.
+    fn next_back_leaf(&mut self) -> Option<&'a Node<D>> {
         while let Some(node) = self.stack.pop() {
+            #[cfg(test)]
+            {
+                self.visited += 1;
+            }
             if node.is_leaf() {
                 if start_bound_contains(self.bounds.start_bound(), &node.key)
                     && end_bound_contains(self.bounds.end_bound(), &node.key)
                 {
-                    return Some((&node.key, &node.value));
+                    return Some(node);
                 }
             } else {
                 if start_bound_contains_exclusive(self.bounds.start_bound(), &node.key) {
@@ -80,7 +87,113 @@ where
     }
 }
 
-fn start_bound_contains<T: Ord>(bound: Bound<T>, key: T) -> bool {
+impl<'a, R, D> Iterator for TreeIterator<'a, R, D>
+where
+    R: RangeBounds<Vec<u8>>,
+    D: Digest,
+{
+    type Item = (&'a [u8], &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_leaf()
+            .map(|node| (node.key.as_slice(), node.value.as_slice()))
+    }
+}
+
+impl<R, D> DoubleEndedIterator for TreeIterator<'_, R, D>
+where
+    R: RangeBounds<Vec<u8>>,
+    D: Digest,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.next_back_leaf()
+            .map(|node| (node.key.as_slice(), node.value.as_slice()))
+    }
+}
+
+// VersionedIterator is a `TreeIterator` which also yields the version each leaf is written at.
+pub struct VersionedIterator<'a, R, D = Sha256>(pub(crate) TreeIterator<'a, R, D>)
+where
+    R: RangeBounds<Vec<u8>>,
+    D: Digest;
+
+impl<'a, R, D> Iterator for VersionedIterator<'a, R, D>
+where
+    R: RangeBounds<Vec<u8>>,
+    D: Digest,
+{
+    type Item = (&'a [u8], &'a [u8], u64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0
+            .next_leaf()
+            .map(|node| (node.key.as_slice(), node.value.as_slice(), node.version))
+    }
+}
+
+impl<R, D> DoubleEndedIterator for VersionedIterator<'_, R, D>
+where
+    R: RangeBounds<Vec<u8>>,
+    D: Digest,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.0
+            .next_back_leaf()
+            .map(|node| (node.key.as_slice(), node.value.as_slice(), node.version))
+    }
+}
+
+// NodeInfo describes a node visited by `NodeIter`, the key of an inner node is the smallest key of
+// its right subtree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NodeInfo<'a> {
+    pub height: u8,
+    pub size: u64,
+    pub version: u64,
+    pub key: &'a [u8],
+    pub is_leaf: bool,
+    // the distance from the root, which is 0
+    pub depth: usize,
+}
+
+// NodeIter visits all the nodes including the inner ones in pre-order, the parent before the
+// children and the left before the right, the stack is bounded by the height of the tree.
+pub struct NodeIter<'a, D = Sha256>
+where
+    D: Digest,
+{
+    stack: Vec<(&'a Node<D>, usize)>,
+}
+
+impl<D: Digest> NodeIter<'_, D> {
+    pub fn new(root: Option<&Node<D>>) -> NodeIter<'_, D> {
+        NodeIter {
+            stack: root.into_iter().map(|node| (node, 0)).collect(),
+        }
+    }
+}
+
+impl<'a, D: Digest> Iterator for NodeIter<'a, D> {
+    type Item = NodeInfo<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (node, depth) = self.stack.pop()?;
+        if !node.is_leaf() {
+            self.stack.push((node.right.as_ref().unwrap(), depth + 1));
+            self.stack.push((node.left.as_ref().unwrap(), depth + 1));
+        }
+        Some(NodeInfo {
+            height: node.height,
+            size: node.size,
+            version: node.version,
+            key: &node.key,
+            is_leaf: node.is_leaf(),
+            depth,
+        })
+    }
+}
+
+pub fn start_bound_contains<T: Ord>(bound: Bound<T>, key: T) -> bool {
     match bound {
         Bound::Included(b) => key >= b,
         Bound::Excluded(b) => key > b,
@@ -94,10 +207,85 @@ fn start_bound_contains_exclusive<T: Ord>(bound: Bound<T>, key: T) -> bool {
         Bound::Unbounded => true,
     }
 }
-fn end_bound_contains<T: Ord>(bound: Bound<T>, key: T) -> bool {
+pub fn end_bound_contains<T: Ord>(bound: Bound<T>, key: T) -> bool {
     match bound {
         Bound::Included(b) => key <= b,
         Bound::Excluded(b) => key < b,
         Bound::Unbounded => true,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{IAVLTree, KVStore};
+
+    #[test]
+    fn test_range_visits() {
+        let mut tree = IAVLTree::new();
+        for i in 0u32..10000 {
+            tree.set(i.to_be_bytes().to_vec(), i.to_be_bytes().to_vec());
+        }
+        let height = tree.range(..).stack[0].height as usize;
+
+        let key = |i: u32| i.to_be_bytes().to_vec();
+        let cases = [
+            (Bound::Included(key(5000)), Bound::Excluded(key(5010))),
+            (Bound::Excluded(key(9990)), Bound::Unbounded),
+            (Bound::Unbounded, Bound::Included(key(3))),
+            (Bound::Included(key(7777)), Bound::Included(key(7777))),
+            (Bound::Included(key(20000)), Bound::Unbounded),
+        ];
+        for bounds in cases {
+            let expected = (0u32..10000)
+                .map(key)
+                .filter(|k| bounds.contains(k))
+                .collect::<Vec<_>>();
+
+            let mut iter = tree.range(bounds.clone());
+            let keys = iter.by_ref().map(|(k, _)| k.to_vec()).collect::<Vec<_>>();
+            assert_eq!(keys, expected);
+            // every result is a leaf plus an inner node, and the two paths to the bounds
+            assert!(
+                iter.visited <= 2 * expected.len() + 4 * (height + 1),
+                "visited {} nodes for {} results",
+                iter.visited,
+                expected.len()
+            );
+
+            let mut iter = tree.range(bounds.clone());
+            let keys = iter
+                .by_ref()
+                .rev()
+                .map(|(k, _)| k.to_vec())
+                .collect::<Vec<_>>();
+            assert!(keys.iter().rev().eq(expected.iter()));
+            assert!(iter.visited <= 2 * expected.len() + 4 * (height + 1));
+        }
+    }
+
+    #[test]
+    fn test_node_iter() {
+        let mut tree = IAVLTree::new();
+        assert_eq!(tree.node_iter().count(), 0);
+        for i in 0u32..100 {
+            tree.set(i.to_be_bytes().to_vec(), i.to_be_bytes().to_vec());
+        }
+        tree.save_version();
+
+        let nodes = tree.node_iter().collect::<Vec<_>>();
+        assert_eq!(nodes.len(), 2 * 100 - 1);
+        assert_eq!(nodes[0].depth, 0);
+        assert_eq!(nodes[0].size, 100);
+
+        // the leaves are visited in order
+        let leaves = nodes.iter().filter(|node| node.is_leaf);
+        assert!(leaves
+            .map(|node| node.key)
+            .eq(tree.range(..).map(|(k, _)| k)));
+        for node in &nodes {
+            assert_eq!(node.is_leaf, node.height == 0);
+            assert!(node.depth + node.height as usize <= nodes[0].height as usize);
+        }
+    }
+}
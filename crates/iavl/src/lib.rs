@@ -1,15 +1,39 @@
+mod cache;
+#[cfg(feature = "compression")]
+mod compress;
 mod db;
+mod diff;
+mod error;
+mod hashstore;
 mod iterator;
 mod mem;
 mod mergeiter;
+mod metered;
 mod node;
+mod nodedb;
 mod overlay;
+mod proof;
+mod snapshot;
+#[cfg(test)]
+mod testing;
 mod tree;
 mod types;
 
-pub use db::IAVLDB;
+pub use cache::CachingStore;
+pub use db::{Changeset, FlushPolicy, IAVLDB};
+pub use diff::{first_divergence, Change, DiffIter, Divergence};
+pub use error::DbError;
+pub use hashstore::HashStore;
+pub use iterator::{NodeInfo, NodeIter, TreeIterator, VersionedIterator};
 pub use mem::MemTree;
-pub use mergeiter::MergeIter;
-pub use overlay::Overlay;
+pub use mergeiter::{Layer, LayersIter, MergeIter};
+pub use metered::{GasCosts, MeteredStore};
+pub use node::{hash_leaf_reader, hash_leaf_with_value_hash, HashScheme};
+pub use overlay::{Entry, Overlay, VacantEntry};
+pub use proof::{
+    verify_membership, verify_multi_proof, ExistenceProof, KeyProof, MultiProof, MultiProofNode,
+    NonExistenceProof, ProofInnerNode, RangeProof,
+};
+pub use snapshot::Snapshot;
 pub use tree::IAVLTree;
-pub use types::KVStore;
+pub use types::{DynIterator, KVStore, KVStoreExt};
@@ -1,7 +1,7 @@
-use std::collections::BTreeMap;
-use std::ops::RangeBounds;
+use std::collections::btree_map::{BTreeMap, Entry};
+use std::ops::{Bound, RangeBounds};
 
-use super::types::KVStore;
+use super::types::{DynIterator, KVStore, KVStoreExt};
 
 #[derive(Default)]
 pub struct MemTree {
@@ -12,6 +12,29 @@ impl MemTree {
     pub fn new() -> Self {
         Self::default()
     }
+
+    pub fn clear(&mut self) {
+        self.tree.clear();
+    }
+
+    // entry gets the entry of the key for in-place read-modify-write.
+    pub fn entry(&mut self, key: Vec<u8>) -> Entry<'_, Vec<u8>, Vec<u8>> {
+        self.tree.entry(key)
+    }
+}
+
+impl FromIterator<(Vec<u8>, Vec<u8>)> for MemTree {
+    fn from_iter<I: IntoIterator<Item = (Vec<u8>, Vec<u8>)>>(iter: I) -> Self {
+        MemTree {
+            tree: BTreeMap::from_iter(iter),
+        }
+    }
+}
+
+impl Extend<(Vec<u8>, Vec<u8>)> for MemTree {
+    fn extend<I: IntoIterator<Item = (Vec<u8>, Vec<u8>)>>(&mut self, iter: I) {
+        self.tree.extend(iter);
+    }
 }
 
 impl KVStore for MemTree {
@@ -27,6 +50,19 @@ impl KVStore for MemTree {
         self.tree.remove(key);
     }
 
+    fn remove_taking(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        self.tree.remove(key)
+    }
+
+    fn range_dyn(&self, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>) -> DynIterator<'_> {
+        Box::new(
+            self.range((start, end))
+                .map(|(k, v)| (k.to_vec(), v.to_vec())),
+        )
+    }
+}
+
+impl KVStoreExt for MemTree {
     fn range<R>(&self, bounds: R) -> impl DoubleEndedIterator<Item = (&[u8], &[u8])>
     where
         R: RangeBounds<Vec<u8>>,
@@ -60,8 +96,8 @@ mod tests {
 
     #[test]
     fn test_iterator() {
-        use super::KVStore;
         use super::MemTree;
+        use super::{KVStore, KVStoreExt};
 
         let mut tree = MemTree::new();
         tree.set(b"key1".to_vec(), b"value1".to_vec());
@@ -96,4 +132,183 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn test_keys_values() {
+        use super::MemTree;
+        use super::{KVStore, KVStoreExt};
+
+        let mut tree = MemTree::new();
+        tree.set(b"key3".to_vec(), b"value3".to_vec());
+        tree.set(b"key1".to_vec(), b"value1".to_vec());
+        tree.set(b"key2".to_vec(), b"value2".to_vec());
+
+        assert_eq!(
+            tree.keys(..).collect::<Vec<_>>(),
+            vec![b"key1" as &[u8], b"key2", b"key3"]
+        );
+        assert_eq!(
+            tree.values(b"key2".to_vec()..).rev().collect::<Vec<_>>(),
+            vec![b"value3" as &[u8], b"value2"]
+        );
+    }
+
+    #[test]
+    fn test_prefix_iter() {
+        use super::MemTree;
+        use super::{KVStore, KVStoreExt};
+
+        let mut tree = MemTree::new();
+        for key in [
+            b"" as &[u8],
+            b"\x00",
+            b"a",
+            b"a\x00",
+            b"ab",
+            b"a\xff",
+            b"b",
+            b"\xfe\xff",
+            b"\xff",
+            b"\xff\xff",
+        ] {
+            tree.set(key.to_vec(), key.to_vec());
+        }
+        let keys = |prefix: &[u8]| tree.prefix_iter(prefix).map(|(k, _)| k).collect::<Vec<_>>();
+
+        assert_eq!(keys(b"a"), vec![b"a" as &[u8], b"a\x00", b"ab", b"a\xff"]);
+        assert_eq!(keys(b"\xff"), vec![b"\xff" as &[u8], b"\xff\xff"]);
+        assert_eq!(keys(b"\xfe"), vec![b"\xfe\xff" as &[u8]]);
+        assert_eq!(keys(b"c"), Vec::<&[u8]>::new());
+        assert_eq!(keys(b"").len(), 10);
+        assert_eq!(
+            tree.prefix_iter(b"a").next_back(),
+            Some((b"a\xff" as &[u8], b"a\xff" as &[u8]))
+        );
+    }
+
+    #[test]
+    fn test_entry() {
+        use super::{KVStore, MemTree};
+
+        let mut tree = MemTree::new();
+        tree.set(b"key1".to_vec(), b"value1".to_vec());
+        for key in [b"key1", b"key2"] {
+            tree.entry(key.to_vec())
+                .and_modify(|v| v.push(b'!'))
+                .or_insert(b"new".to_vec());
+        }
+        assert_eq!(tree.get(b"key1"), Some(b"value1!".as_ref()));
+        assert_eq!(tree.get(b"key2"), Some(b"new".as_ref()));
+    }
+
+    #[test]
+    fn test_get_owned() {
+        use super::{KVStore, MemTree};
+
+        let mut tree = MemTree::new();
+        tree.set(b"key1".to_vec(), b"value1".to_vec());
+        for key in [b"key1", b"key2"] {
+            assert_eq!(tree.get_owned(key).as_deref(), tree.get(key));
+        }
+
+        let store: &dyn KVStore = &tree;
+        let value = store.get_owned(b"key1").unwrap();
+        drop(tree);
+        assert_eq!(value, b"value1");
+    }
+
+    #[test]
+    fn test_collect() {
+        use super::{KVStore, MemTree};
+
+        let pairs = vec![
+            (b"key1".to_vec(), b"value1".to_vec()),
+            (b"key2".to_vec(), b"value2".to_vec()),
+        ];
+        let mut tree: MemTree = pairs.into_iter().collect();
+        assert_eq!(tree.get(b"key1"), Some(b"value1".as_ref()));
+        assert_eq!(tree.get(b"key3"), None);
+
+        tree.extend([
+            (b"key2".to_vec(), b"new".to_vec()),
+            (b"key3".to_vec(), b"value3".to_vec()),
+        ]);
+        assert_eq!(tree.get(b"key2"), Some(b"new".as_ref()));
+        assert_eq!(tree.get(b"key3"), Some(b"value3".as_ref()));
+    }
+
+    #[test]
+    fn test_clear() {
+        use super::{KVStore, KVStoreExt, MemTree};
+
+        let mut tree: MemTree = [(b"key1".to_vec(), b"value1".to_vec())]
+            .into_iter()
+            .collect();
+        tree.clear();
+        assert_eq!(tree.get(b"key1"), None);
+        assert_eq!(tree.range(..).count(), 0);
+    }
+
+    #[test]
+    fn test_remove_taking() {
+        use super::{KVStore, MemTree};
+
+        let mut tree: MemTree = [(b"key1".to_vec(), b"value1".to_vec())]
+            .into_iter()
+            .collect();
+        assert_eq!(tree.remove_taking(b"key1"), Some(b"value1".to_vec()));
+        assert_eq!(tree.get(b"key1"), None);
+        assert_eq!(tree.remove_taking(b"key1"), None);
+    }
+
+    #[test]
+    fn test_mut_ref_store() {
+        use super::{KVStore, KVStoreExt, MemTree};
+
+        fn write(mut kv: impl KVStore) -> Option<Vec<u8>> {
+            kv.write_batch([
+                (b"key1".to_vec(), Some(b"value1".to_vec())),
+                (b"key2".to_vec(), Some(b"value2".to_vec())),
+            ]);
+            kv.remove_taking(b"key1")
+        }
+
+        let mut tree = MemTree::new();
+        assert_eq!(write(&mut tree), Some(b"value1".to_vec()));
+        let kv = &mut tree;
+        assert_eq!(kv.get_owned(b"key2"), Some(b"value2".to_vec()));
+        assert_eq!(kv.keys(..).collect::<Vec<_>>(), vec![b"key2" as &[u8]]);
+        assert_eq!(tree.get(b"key1"), None);
+    }
+
+    #[test]
+    fn test_dyn_store() {
+        use super::{KVStore, MemTree};
+        use std::ops::Bound;
+
+        let mut store: Box<dyn KVStore> = Box::new(MemTree::new());
+        store.set(b"key1".to_vec(), b"value1".to_vec());
+        store.set(b"key2".to_vec(), b"value2".to_vec());
+        store.set(b"key3".to_vec(), b"value3".to_vec());
+        store.remove(b"key1");
+        assert_eq!(store.get(b"key2"), Some(b"value2".as_ref()));
+
+        assert_eq!(
+            store
+                .range_dyn(Bound::Unbounded, Bound::Unbounded)
+                .collect::<Vec<_>>(),
+            vec![
+                (b"key2".to_vec(), b"value2".to_vec()),
+                (b"key3".to_vec(), b"value3".to_vec()),
+            ]
+        );
+
+        assert_eq!(
+            store
+                .range_dyn(Bound::Excluded(b"key2".to_vec()), Bound::Unbounded)
+                .rev()
+                .collect::<Vec<_>>(),
+            vec![(b"key3".to_vec(), b"value3".to_vec())]
+        );
+    }
 }
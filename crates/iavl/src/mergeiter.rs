@@ -34,31 +34,28 @@ where
 {
     type Item = (K, V);
 
+    // the tombstones are skipped in a loop, a long run of them, e.g. after `Overlay::clear`,
+    // would overflow the stack if skipped recursively.
     fn next(&mut self) -> Option<Self::Item> {
-        match (self.i1.peek(), self.i2.peek()) {
-            (Some((ref k1, _)), Some((ref k2, _))) => match k1.cmp(k2) {
-                Ordering::Less => match self.i1.next() {
-                    Some((_, None)) => self.next(),
-                    Some((k, Some(v))) => Some((k, v)),
-                    None => None,
-                },
-                Ordering::Equal => {
-                    self.i2.next();
-                    match self.i1.next() {
-                        Some((_, None)) => self.next(),
-                        Some((k, Some(v))) => Some((k, v)),
-                        None => None,
+        loop {
+            let item = match (self.i1.peek(), self.i2.peek()) {
+                (Some((ref k1, _)), Some((ref k2, _))) => match k1.cmp(k2) {
+                    Ordering::Less => self.i1.next(),
+                    Ordering::Equal => {
+                        self.i2.next();
+                        self.i1.next()
                     }
-                }
-                Ordering::Greater => self.i2.next(),
-            },
-            (Some(_), None) => match self.i1.next() {
-                Some((_, None)) => self.next(),
-                Some((k, Some(v))) => Some((k, v)),
-                None => None,
-            },
-            (None, Some(_)) => self.i2.next(),
-            (None, None) => None,
+                    Ordering::Greater => return self.i2.next(),
+                },
+                (Some(_), None) => self.i1.next(),
+                (None, Some(_)) => return self.i2.next(),
+                (None, None) => return None,
+            };
+            match item {
+                Some((_, None)) => continue,
+                Some((k, Some(v))) => return Some((k, v)),
+                None => return None,
+            }
         }
     }
 }
@@ -70,31 +67,99 @@ where
     I2: DoubleEndedIterator<Item = (K, V)>,
 {
     fn next_back(&mut self) -> Option<Self::Item> {
-        match (self.i1.peek_back(), self.i2.peek_back()) {
-            (Some((ref k1, _)), Some((ref k2, _))) => match k1.cmp(k2) {
-                Ordering::Greater => match self.i1.next_back() {
-                    Some((_, None)) => self.next_back(),
-                    Some((k, Some(v))) => Some((k, v)),
-                    None => None,
-                },
-                Ordering::Equal => {
-                    self.i2.next_back();
-                    match self.i1.next_back() {
-                        Some((_, None)) => self.next_back(),
-                        Some((k, Some(v))) => Some((k, v)),
-                        None => None,
+        loop {
+            let item = match (self.i1.peek_back(), self.i2.peek_back()) {
+                (Some((ref k1, _)), Some((ref k2, _))) => match k1.cmp(k2) {
+                    Ordering::Greater => self.i1.next_back(),
+                    Ordering::Equal => {
+                        self.i2.next_back();
+                        self.i1.next_back()
                     }
-                }
-                Ordering::Less => self.i2.next_back(),
-            },
-            (Some(_), None) => match self.i1.next_back() {
-                Some((_, None)) => self.next_back(),
-                Some((k, Some(v))) => Some((k, v)),
-                None => None,
+                    Ordering::Less => return self.i2.next_back(),
+                },
+                (Some(_), None) => self.i1.next_back(),
+                (None, Some(_)) => return self.i2.next_back(),
+                (None, None) => return None,
+            };
+            match item {
+                Some((_, None)) => continue,
+                Some((k, Some(v))) => return Some((k, v)),
+                None => return None,
+            }
+        }
+    }
+}
+
+// Layer is a tombstone-capable iterator sorted by key, a `None` value represents deletion.
+pub type Layer<'a, K, V> = Box<dyn DoubleEndedIterator<Item = (K, Option<V>)> + 'a>;
+
+// LayersIter merges multiple layers in a single pass, only the item from the layer with the
+// highest priority is yielded for each key, the tombstones are kept to shadow the base.
+pub struct LayersIter<'a, K, V> {
+    // ordered by priority, highest first
+    layers: Vec<DoubleEndedPeekable<Layer<'a, K, V>>>,
+}
+
+impl<'a, K: Ord, V, I2> MergeIter<LayersIter<'a, K, V>, I2, K, V>
+where
+    I2: Iterator<Item = (K, V)>,
+{
+    // from_layers merges the layers, highest priority first, against the base iterator,
+    // a tombstone in any layer hides the key from the lower layers and the base.
+    pub fn from_layers(layers: Vec<Layer<'a, K, V>>, base: I2) -> Self {
+        MergeIter::new(
+            LayersIter {
+                layers: layers
+                    .into_iter()
+                    .map(|layer| layer.double_ended_peekable())
+                    .collect(),
             },
-            (None, Some(_)) => self.i2.next_back(),
-            (None, None) => None,
+            base,
+        )
+    }
+}
+
+impl<K: Ord, V> Iterator for LayersIter<'_, K, V> {
+    type Item = (K, Option<V>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // the first layer with the smallest key wins
+        let mut min: Option<(usize, &K)> = None;
+        for (i, layer) in self.layers.iter_mut().enumerate() {
+            if let Some((key, _)) = layer.peek() {
+                if min.is_none_or(|(_, min_key)| key < min_key) {
+                    min = Some((i, key));
+                }
+            }
+        }
+        let (i, _) = min?;
+
+        let item = self.layers[i].next()?;
+        for layer in &mut self.layers {
+            layer.next_if(|(key, _)| *key == item.0);
+        }
+        Some(item)
+    }
+}
+
+impl<K: Ord, V> DoubleEndedIterator for LayersIter<'_, K, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        // the first layer with the largest key wins
+        let mut max: Option<(usize, &K)> = None;
+        for (i, layer) in self.layers.iter_mut().enumerate() {
+            if let Some((key, _)) = layer.peek_back() {
+                if max.is_none_or(|(_, max_key)| key > max_key) {
+                    max = Some((i, key));
+                }
+            }
         }
+        let (i, _) = max?;
+
+        let item = self.layers[i].next_back()?;
+        for layer in &mut self.layers {
+            layer.next_back_if(|(key, _)| *key == item.0);
+        }
+        Some(item)
     }
 }
 
@@ -119,4 +184,49 @@ mod tests {
             vec![(4, "D"), (3, "c"), (1, "a")]
         );
     }
+
+    #[test]
+    fn test_merge_layers() {
+        let top: Vec<(i32, Option<&str>)> = vec![(2, Some("top2")), (5, None)];
+        let middle = vec![
+            (1, Some("middle1")),
+            (2, None),
+            (3, None),
+            (5, Some("middle5")),
+        ];
+        let bottom = vec![(3, Some("bottom3")), (4, Some("bottom4")), (6, None)];
+        let base = [
+            (1, "base1"),
+            (2, "base2"),
+            (3, "base3"),
+            (5, "base5"),
+            (6, "base6"),
+        ];
+        let layers = || -> Vec<Layer<'_, i32, &str>> {
+            vec![
+                Box::new(top.clone().into_iter()),
+                Box::new(middle.clone().into_iter()),
+                Box::new(bottom.clone().into_iter()),
+            ]
+        };
+
+        // key 2 is deleted by the middle layer and re-added by the top one.
+        let expected = vec![(1, "middle1"), (2, "top2"), (4, "bottom4")];
+        assert_eq!(
+            MergeIter::from_layers(layers(), base.iter().cloned()).collect::<Vec<_>>(),
+            expected
+        );
+        assert_eq!(
+            MergeIter::from_layers(layers(), base.iter().cloned())
+                .rev()
+                .collect::<Vec<_>>(),
+            expected.iter().cloned().rev().collect::<Vec<_>>()
+        );
+
+        // no layers
+        assert_eq!(
+            MergeIter::from_layers(Vec::new(), base.iter().cloned()).count(),
+            base.len()
+        );
+    }
 }
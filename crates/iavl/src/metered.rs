@@ -0,0 +1,281 @@
+use std::cell::Cell;
+use std::ops::{Bound, RangeBounds};
+
+use super::types::{ChangeItem, DynIterator, KVStore, KVStoreExt};
+
+// GasCosts is the gas charged for each operation, plus the bytes of the keys and values touched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GasCosts {
+    pub read: u64,
+    pub write: u64,
+    pub remove: u64,
+    // charged for each item yielded by the ranges
+    pub iter_next: u64,
+    pub per_byte: u64,
+}
+
+impl Default for GasCosts {
+    fn default() -> Self {
+        GasCosts {
+            read: 1000,
+            write: 2000,
+            remove: 1000,
+            iter_next: 30,
+            per_byte: 3,
+        }
+    }
+}
+
+// MeteredStore charges the operations on the inner store against a gas limit, the operation
+// which exceeds the limit is not done, and the store is out of gas since then: the reads return
+// nothing, the ranges end, and the writes are dropped, the caller should check `out_of_gas` and
+// discard the changes.
+pub struct MeteredStore<S> {
+    pub inner: S,
+    costs: GasCosts,
+    limit: u64,
+    used: Cell<u64>,
+    out_of_gas: Cell<bool>,
+}
+
+impl<S: KVStore> MeteredStore<S> {
+    pub fn new(inner: S, limit: u64, costs: GasCosts) -> Self {
+        MeteredStore {
+            inner,
+            costs,
+            limit,
+            used: Cell::new(0),
+            out_of_gas: Cell::new(false),
+        }
+    }
+
+    pub fn gas_used(&self) -> u64 {
+        self.used.get()
+    }
+
+    pub fn gas_remaining(&self) -> u64 {
+        self.limit - self.used.get()
+    }
+
+    pub fn out_of_gas(&self) -> bool {
+        self.out_of_gas.get()
+    }
+
+    // charge consumes the gas, returns false if the store is out of gas.
+    fn charge(&self, gas: u64) -> bool {
+        if self.out_of_gas.get() {
+            return false;
+        }
+        match self.used.get().checked_add(gas) {
+            Some(used) if used <= self.limit => {
+                self.used.set(used);
+                true
+            }
+            _ => {
+                self.out_of_gas.set(true);
+                false
+            }
+        }
+    }
+
+    fn bytes_cost(&self, len: usize) -> u64 {
+        self.costs.per_byte.saturating_mul(len as u64)
+    }
+
+    fn write_cost(&self, key: &[u8], value: Option<&[u8]>) -> u64 {
+        match value {
+            Some(value) => self
+                .costs
+                .write
+                .saturating_add(self.bytes_cost(key.len() + value.len())),
+            None => self.costs.remove.saturating_add(self.bytes_cost(key.len())),
+        }
+    }
+
+    fn iter_cost(&self, key: &[u8], value: &[u8]) -> u64 {
+        self.costs
+            .iter_next
+            .saturating_add(self.bytes_cost(key.len() + value.len()))
+    }
+}
+
+impl<S: KVStore> KVStore for MeteredStore<S> {
+    // the value is charged after it's read, the read is charged even if the key don't exist.
+    fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        if !self.charge(self.costs.read.saturating_add(self.bytes_cost(key.len()))) {
+            return None;
+        }
+        let value = self.inner.get(key)?;
+        self.charge(self.bytes_cost(value.len())).then_some(value)
+    }
+
+    fn set(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        if self.charge(self.write_cost(&key, Some(&value))) {
+            self.inner.set(key, value);
+        }
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        if self.charge(self.write_cost(key, None)) {
+            self.inner.remove(key);
+        }
+    }
+
+    fn range_dyn(&self, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>) -> DynIterator<'_> {
+        Box::new(MeteredIter {
+            store: self,
+            inner: self.inner.range_dyn(start, end),
+        })
+    }
+
+    // the batch is charged as a whole, it's dropped entirely if it exceeds the limit.
+    fn write_batch(&mut self, batch: impl IntoIterator<Item = ChangeItem>) {
+        let batch = batch.into_iter().collect::<Vec<_>>();
+        let cost = batch.iter().fold(0u64, |cost, (key, value)| {
+            cost.saturating_add(self.write_cost(key, value.as_deref()))
+        });
+        if self.charge(cost) {
+            self.inner.write_batch(batch);
+        }
+    }
+}
+
+impl<S: KVStoreExt> KVStoreExt for MeteredStore<S> {
+    // the items are charged as they are yielded, the range ends when out of gas.
+    fn range<R>(&self, bounds: R) -> impl DoubleEndedIterator<Item = (&[u8], &[u8])>
+    where
+        R: RangeBounds<Vec<u8>> + Clone,
+    {
+        MeteredIter {
+            store: self,
+            inner: self.inner.range(bounds),
+        }
+    }
+}
+
+// MeteredIter charges the items as they are yielded, it ends without advancing the inner
+// iterator once the store is out of gas, so a large range is not read to the end for nothing.
+struct MeteredIter<'a, S, I> {
+    store: &'a MeteredStore<S>,
+    inner: I,
+}
+
+impl<S, I, K, V> Iterator for MeteredIter<'_, S, I>
+where
+    S: KVStore,
+    I: Iterator<Item = (K, V)>,
+    K: AsRef<[u8]>,
+    V: AsRef<[u8]>,
+{
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.store.out_of_gas() {
+            return None;
+        }
+        let (key, value) = self.inner.next()?;
+        self.store
+            .charge(self.store.iter_cost(key.as_ref(), value.as_ref()))
+            .then_some((key, value))
+    }
+}
+
+impl<S, I, K, V> DoubleEndedIterator for MeteredIter<'_, S, I>
+where
+    S: KVStore,
+    I: DoubleEndedIterator<Item = (K, V)>,
+    K: AsRef<[u8]>,
+    V: AsRef<[u8]>,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.store.out_of_gas() {
+            return None;
+        }
+        let (key, value) = self.inner.next_back()?;
+        self.store
+            .charge(self.store.iter_cost(key.as_ref(), value.as_ref()))
+            .then_some((key, value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::CountingStore;
+    use crate::MemTree;
+
+    #[test]
+    fn test_metered_store() {
+        let costs = GasCosts {
+            read: 10,
+            write: 20,
+            remove: 10,
+            iter_next: 1,
+            per_byte: 1,
+        };
+        let mut store = MeteredStore::new(MemTree::new(), 100, costs);
+
+        // 20 + 4 + 6 for each set
+        store.set(b"key1".to_vec(), b"value1".to_vec());
+        store.set(b"key2".to_vec(), b"value2".to_vec());
+        assert_eq!(store.gas_used(), 60);
+        assert_eq!(store.range(..).count(), 2);
+        assert_eq!(store.gas_used(), 82);
+        assert!(!store.out_of_gas());
+
+        // exceeds the limit, not written
+        store.set(b"key3".to_vec(), b"value3".to_vec());
+        assert!(store.out_of_gas());
+        assert_eq!(store.gas_remaining(), 18);
+        assert_eq!(store.inner.get(b"key3"), None);
+
+        // the reads report nothing after out of gas
+        assert_eq!(store.get(b"key1"), None);
+        assert_eq!(store.range(..).count(), 0);
+        store.remove(b"key1");
+        assert_eq!(store.inner.get(b"key1"), Some(b"value1" as &[u8]));
+        assert_eq!(store.gas_used(), 82);
+    }
+
+    #[test]
+    fn test_metered_range_out_of_gas() {
+        let mut inner = CountingStore::default();
+        for i in 0u32..10000 {
+            inner.set(i.to_be_bytes().to_vec(), i.to_be_bytes().to_vec());
+        }
+        let costs = GasCosts {
+            iter_next: 1,
+            per_byte: 0,
+            ..GasCosts::default()
+        };
+
+        // the item which runs out of gas is the last one pulled
+        let store = MeteredStore::new(inner, 10, costs);
+        assert_eq!(store.range(..).count(), 10);
+        assert!(store.out_of_gas());
+        assert_eq!(store.inner.pulled.get(), 11);
+        assert_eq!(store.range(..).rev().count(), 0);
+        assert_eq!(store.inner.pulled.get(), 11);
+
+        let store = MeteredStore::new(store.inner, 10, costs);
+        store.inner.pulled.set(0);
+        assert_eq!(
+            store
+                .range_dyn(Bound::Unbounded, Bound::Unbounded)
+                .rev()
+                .count(),
+            10
+        );
+        assert!(store.out_of_gas());
+        assert_eq!(store.inner.pulled.get(), 11);
+    }
+
+    #[test]
+    fn test_metered_write_batch() {
+        let mut store = MeteredStore::new(MemTree::new(), 100, GasCosts::default());
+        store.write_batch([(b"key1".to_vec(), Some(b"value1".to_vec()))]);
+        assert!(store.out_of_gas());
+        assert_eq!(store.gas_used(), 0);
+        assert_eq!(store.inner.range(..).count(), 0);
+    }
+}
@@ -2,20 +2,161 @@ use crypto_common::Output;
 use integer_encoding::VarInt;
 use sha2::{Digest, Sha256};
 use std::cmp::{self, Ordering};
+use std::fmt;
+use std::io::{self, Read};
+use std::mem;
+use std::ops::Deref;
+use std::sync::{Arc, OnceLock};
 
-#[derive(Debug, Clone)]
-pub struct Node {
+#[cfg(feature = "compression")]
+use super::compress;
+use super::error::DbError;
+use super::nodedb::NodeDB;
+
+// NodeRef is a shared reference to an immutable node, nodes are copied on write
+// so unchanged subtrees are shared between versions.
+// A persisted node is referenced by its hash, it's loaded from the node db on first access.
+pub enum NodeRef<D: Digest = Sha256> {
+    Loaded(Arc<Node<D>>),
+    Stored(Arc<StoredNode<D>>),
+}
+
+pub struct StoredNode<D: Digest> {
+    pub hash: Output<D>,
+    pub db: Arc<NodeDB>,
+    // the node must be lower than its parent, so a corrupted node db can't make the recursions
+    // over the tree unbounded.
+    pub max_height: u8,
+    pub node: OnceLock<Arc<Node<D>>>,
+}
+
+impl<D: Digest> StoredNode<D> {
+    fn get(&self) -> &Arc<Node<D>> {
+        self.node.get_or_init(|| {
+            Arc::new(
+                self.db
+                    .load(&self.hash, self.max_height)
+                    .unwrap_or_else(|err| panic!("failed to load node: {}", err)),
+            )
+        })
+    }
+}
+
+impl<D: Digest> NodeRef<D> {
+    pub fn new(node: Node<D>) -> Self {
+        NodeRef::Loaded(Arc::new(node))
+    }
+
+    pub fn stored(hash: Output<D>, db: Arc<NodeDB>, max_height: u8) -> Self {
+        NodeRef::Stored(Arc::new(StoredNode {
+            hash,
+            db,
+            max_height,
+            node: OnceLock::new(),
+        }))
+    }
+
+    // make_mut returns a mutable reference to the node, which is loaded if persisted,
+    // and copied if shared.
+    pub fn make_mut(this: &mut Self) -> &mut Node<D> {
+        if let NodeRef::Stored(stored) = this {
+            *this = NodeRef::Loaded(stored.get().clone());
+        }
+        match this {
+            NodeRef::Loaded(node) => Arc::make_mut(node),
+            NodeRef::Stored(_) => unreachable!(),
+        }
+    }
+
+    // heap_size estimates the memory allocated for the node and the loaded nodes below it,
+    // the persisted nodes not loaded yet only count the reference.
+    pub fn heap_size(&self) -> usize {
+        // the strong and weak counters of `Arc`
+        let counters = 2 * mem::size_of::<usize>();
+        match self {
+            NodeRef::Loaded(node) => counters + node.heap_size(),
+            NodeRef::Stored(stored) => {
+                counters
+                    + mem::size_of::<StoredNode<D>>()
+                    + stored
+                        .node
+                        .get()
+                        .map_or(0, |node| counters + node.heap_size())
+            }
+        }
+    }
+
+    // cached_hash returns the hash of the node if computed, without loading a persisted node.
+    pub fn cached_hash(&self) -> Option<&Output<D>> {
+        match self {
+            NodeRef::Loaded(node) => node.hash.as_ref(),
+            NodeRef::Stored(stored) => Some(&stored.hash),
+        }
+    }
+}
+
+impl<D: Digest> Deref for NodeRef<D> {
+    type Target = Node<D>;
+
+    fn deref(&self) -> &Node<D> {
+        match self {
+            NodeRef::Loaded(node) => node,
+            NodeRef::Stored(stored) => stored.get(),
+        }
+    }
+}
+
+impl<D: Digest> Clone for NodeRef<D> {
+    fn clone(&self) -> Self {
+        match self {
+            NodeRef::Loaded(node) => NodeRef::Loaded(node.clone()),
+            NodeRef::Stored(stored) => NodeRef::Stored(stored.clone()),
+        }
+    }
+}
+
+impl<D: Digest + fmt::Debug> fmt::Debug for NodeRef<D> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NodeRef::Loaded(node) => node.fmt(f),
+            NodeRef::Stored(stored) => f
+                .debug_tuple("Stored")
+                .field(&stored.hash.as_slice())
+                .finish(),
+        }
+    }
+}
+
+// Node is generic over the digest used to compute the merkle hashes.
+#[derive(Debug)]
+pub struct Node<D: Digest = Sha256> {
     pub height: u8,
     pub size: u64,
     pub version: u64,
     pub key: Vec<u8>,
     pub value: Vec<u8>,
-    pub left: Option<Box<Node>>,
-    pub right: Option<Box<Node>>,
-    pub hash: Option<Output<Sha256>>,
+    pub left: Option<NodeRef<D>>,
+    pub right: Option<NodeRef<D>>,
+    pub hash: Option<Output<D>>,
 }
 
-impl Node {
+// implemented manually so the digest itself is not required to be `Clone`.
+impl<D: Digest> Clone for Node<D> {
+    fn clone(&self) -> Self {
+        Node {
+            height: self.height,
+            size: self.size,
+            version: self.version,
+            key: self.key.clone(),
+            value: self.value.clone(),
+            left: self.left.clone(),
+            right: self.right.clone(),
+            hash: self.hash.clone(),
+        }
+    }
+}
+
+impl<D: Digest> Node<D> {
     // leaf create a leaf node
     pub fn leaf(key: Vec<u8>, value: Vec<u8>, version: u64) -> Self {
         Node {
@@ -31,7 +172,7 @@ impl Node {
     }
 
     // branch_bottom creates a height 1 node with two leafs as children
-    pub fn branch_bottom(left: Box<Node>, right: Box<Node>, version: u64) -> Self {
+    pub fn branch_bottom(left: NodeRef<D>, right: NodeRef<D>, version: u64) -> Self {
         Node {
             height: 1,
             size: 2,
@@ -44,6 +185,22 @@ impl Node {
         }
     }
 
+    // branch creates an inner node, the key should be the smallest key in the right subtree.
+    pub fn branch(key: Vec<u8>, left: NodeRef<D>, right: NodeRef<D>, version: u64) -> Self {
+        let mut node = Node {
+            height: 0,
+            size: 0,
+            version,
+            key,
+            left: Some(left),
+            right: Some(right),
+            value: Vec::new(),
+            hash: None,
+        };
+        node.update_height_size();
+        node
+    }
+
     pub fn update_height_size(&mut self) {
         let left = self.left.as_ref().unwrap();
         let right = self.right.as_ref().unwrap();
@@ -51,6 +208,16 @@ impl Node {
         self.size = left.size + right.size;
     }
 
+    // heap_size estimates the memory used by the node, the key and value buffers and the loaded
+    // children recursively, the cached hash is stored inline.
+    pub fn heap_size(&self) -> usize {
+        mem::size_of::<Self>()
+            + self.key.capacity()
+            + self.value.capacity()
+            + self.left.as_ref().map_or(0, NodeRef::heap_size)
+            + self.right.as_ref().map_or(0, NodeRef::heap_size)
+    }
+
     pub fn is_leaf(&self) -> bool {
         self.height == 0
     }
@@ -67,9 +234,9 @@ impl Node {
         self.hash = None;
     }
 
-    pub fn update_hash(&mut self) -> &Output<Sha256> {
+    pub fn update_hash(&mut self, scheme: HashScheme) -> &Output<D> {
         if self.hash.is_none() {
-            self.hash = Some(hash_node(self));
+            self.hash = Some(hash_node(self, scheme));
         };
 
         // SAFETY: a `None` variant for `self` would have been replaced by a `Some`
@@ -77,6 +244,94 @@ impl Node {
         unsafe { self.hash.as_ref().unwrap_unchecked() }
     }
 
+    // encode serializes the node as:
+    // - varint height, size, version
+    // - length prefixed key
+    // - length prefixed value for leaf node, length prefixed left and right hashes for inner node.
+    //   the value is compressed with the `compression` feature, the hash is over the original one.
+    // the hashes of the children must be computed beforehand.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&(self.height as i64).encode_var_vec());
+        buf.extend_from_slice(&(self.size as i64).encode_var_vec());
+        buf.extend_from_slice(&(self.version as i64).encode_var_vec());
+        encode_bytes(&mut buf, &self.key);
+        if self.is_leaf() {
+            #[cfg(feature = "compression")]
+            encode_bytes(&mut buf, &compress::encode_value(&self.value));
+            #[cfg(not(feature = "compression"))]
+            encode_bytes(&mut buf, &self.value);
+        } else {
+            encode_bytes(&mut buf, self.left.as_ref().unwrap().cached_hash().unwrap());
+            encode_bytes(
+                &mut buf,
+                self.right.as_ref().unwrap().cached_hash().unwrap(),
+            );
+        }
+        buf
+    }
+
+    // decode parses the node encoded by `encode`, the children are resolved from their hashes
+    // and the max height of them by `child`, the hash of the node itself is not set.
+    pub fn decode(
+        mut bz: &[u8],
+        mut child: impl FnMut(Output<D>, u8) -> NodeRef<D>,
+    ) -> Result<Self, DbError> {
+        let height = decode_varint(&mut bz)?;
+        let height = u8::try_from(height)
+            .map_err(|_| DbError::Decode(format!("invalid node height: {}", height)))?;
+        let size = decode_varint(&mut bz)?;
+        let version = decode_varint(&mut bz)?;
+        let key = decode_bytes(&mut bz)?.to_vec();
+        let (value, left, right) = if height == 0 {
+            #[cfg(feature = "compression")]
+            let value = compress::decode_value(decode_bytes(&mut bz)?)?;
+            #[cfg(not(feature = "compression"))]
+            let value = decode_bytes(&mut bz)?.to_vec();
+            (value, None, None)
+        } else {
+            let left = decode_hash::<D>(decode_bytes(&mut bz)?)?;
+            let right = decode_hash::<D>(decode_bytes(&mut bz)?)?;
+            (
+                Vec::new(),
+                Some(child(left, height - 1)),
+                Some(child(right, height - 1)),
+            )
+        };
+        if !bz.is_empty() {
+            return Err(DbError::Decode("trailing bytes in node".to_string()));
+        }
+        Ok(Node {
+            height,
+            size: size as u64,
+            version: version as u64,
+            key,
+            value,
+            left,
+            right,
+            hash: None,
+        })
+    }
+
+    // compute_hash returns the cached hash, or computes it without caching, the uncached
+    // descendants are hashed again on every call.
+    pub fn compute_hash(&self, scheme: HashScheme) -> Output<D> {
+        if let Some(hash) = &self.hash {
+            return hash.clone();
+        }
+        if self.is_leaf() {
+            return hash_leaf::<D>(scheme, self.version, &self.key, &self.value);
+        }
+        hash_inner::<D>(
+            scheme,
+            self.height,
+            self.size,
+            self.version,
+            &self.left.as_ref().unwrap().compute_hash(scheme),
+            &self.right.as_ref().unwrap().compute_hash(scheme),
+        )
+    }
+
     // get_with_index returns the value and the index of the key in the tree.
     pub fn get_with_index(&self, key: &[u8]) -> (Option<&[u8]>, u64) {
         if self.is_leaf() {
@@ -112,40 +367,197 @@ impl Node {
     }
 }
 
-fn hash_node(node: &mut Node) -> Output<Sha256> {
-    let mut buf = [0u8; 8];
-    let mut hasher = Sha256::new();
+fn encode_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&bytes.len().encode_var_vec());
+    buf.extend_from_slice(bytes);
+}
+
+fn decode_varint(bz: &mut &[u8]) -> Result<i64, DbError> {
+    let (value, n) =
+        i64::decode_var(bz).ok_or_else(|| DbError::Decode("invalid varint in node".to_string()))?;
+    *bz = &bz[n..];
+    Ok(value)
+}
+
+fn decode_bytes<'a>(bz: &mut &'a [u8]) -> Result<&'a [u8], DbError> {
+    let (len, n) = usize::decode_var(bz)
+        .ok_or_else(|| DbError::Decode("invalid length in node".to_string()))?;
+    let end = n
+        .checked_add(len)
+        .filter(|end| *end <= bz.len())
+        .ok_or_else(|| DbError::Decode("unexpected end of node".to_string()))?;
+    let bytes = &bz[n..end];
+    *bz = &bz[end..];
+    Ok(bytes)
+}
+
+pub fn decode_hash<D: Digest>(bz: &[u8]) -> Result<Output<D>, DbError> {
+    if bz.len() != <D as Digest>::output_size() {
+        return Err(DbError::Decode(format!(
+            "invalid hash length: {}",
+            bz.len()
+        )));
+    }
+    Ok(Output::<D>::clone_from_slice(bz))
+}
 
+fn hash_node<D: Digest>(node: &mut Node<D>, scheme: HashScheme) -> Output<D> {
+    if node.is_leaf() {
+        hash_leaf::<D>(scheme, node.version, &node.key, &node.value)
+    } else {
+        let (left_hash, right_hash) = children_hashes(node, scheme);
+        hash_inner::<D>(
+            scheme,
+            node.height,
+            node.size,
+            node.version,
+            &left_hash,
+            &right_hash,
+        )
+    }
+}
+
+#[cfg(not(feature = "rayon"))]
+fn children_hashes<D: Digest>(node: &mut Node<D>, scheme: HashScheme) -> (Output<D>, Output<D>) {
+    (
+        child_hash(node.left.as_mut().unwrap(), scheme),
+        child_hash(node.right.as_mut().unwrap(), scheme),
+    )
+}
+
+// subtrees larger than this are hashed in parallel
+#[cfg(feature = "rayon")]
+const PARALLEL_HASH_THRESHOLD: u64 = 1024;
+
+// children_hashes hashes the children in parallel if both of them are dirty and the subtree is
+// large enough, the result is identical to the serial one.
+#[cfg(feature = "rayon")]
+fn children_hashes<D: Digest>(node: &mut Node<D>, scheme: HashScheme) -> (Output<D>, Output<D>) {
+    let size = node.size;
+    let left = node.left.as_mut().unwrap();
+    let right = node.right.as_mut().unwrap();
+    if size > PARALLEL_HASH_THRESHOLD
+        && left.cached_hash().is_none()
+        && right.cached_hash().is_none()
     {
-        let n = (node.height as i64).encode_var(&mut buf);
-        hasher.update(&buf[..n]);
+        rayon::join(|| child_hash(left, scheme), || child_hash(right, scheme))
+    } else {
+        (child_hash(left, scheme), child_hash(right, scheme))
+    }
+}
+
+// child_hash returns the cached hash of the child, or compute it,
+// the child is only copied if it's shared and not hashed yet.
+fn child_hash<D: Digest>(node: &mut NodeRef<D>, scheme: HashScheme) -> Output<D> {
+    match node.cached_hash() {
+        Some(hash) => hash.clone(),
+        None => NodeRef::make_mut(node).update_hash(scheme).clone(),
+    }
+}
+
+// HashScheme is the domain separation of the node hashes, the prefix bytes are hashed first for
+// the leaf and the inner nodes respectively, so a leaf can't be forged from an inner node, the
+// default has no prefix, which is the scheme of the Cosmos IAVL.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HashScheme {
+    pub leaf_prefix: Option<u8>,
+    pub inner_prefix: Option<u8>,
+}
+
+// hash_leaf computes the hash of a leaf node, which always has height 0 and size 1.
+pub fn hash_leaf<D: Digest>(
+    scheme: HashScheme,
+    version: u64,
+    key: &[u8],
+    value: &[u8],
+) -> Output<D> {
+    hash_leaf_with_value_hash::<D>(scheme, version, key, &D::digest(value))
+}
+
+// hash_leaf_reader computes the same hash as `hash_leaf`, but the value is streamed from the
+// reader in chunks of `chunk_size`, so the value don't need to be held in memory as a whole.
+pub fn hash_leaf_reader<D: Digest>(
+    scheme: HashScheme,
+    version: u64,
+    key: &[u8],
+    mut value: impl Read,
+    chunk_size: usize,
+) -> io::Result<Output<D>> {
+    let mut value_hasher = D::new();
+    let mut buf = vec![0u8; chunk_size.max(1)];
+    loop {
+        match value.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => value_hasher.update(&buf[..n]),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        }
     }
+    Ok(hash_leaf_with_value_hash::<D>(
+        scheme,
+        version,
+        key,
+        &value_hasher.finalize(),
+    ))
+}
+
+// hash_leaf_with_value_hash computes the leaf hash from the digest of the value, the leaf only
+// commits to the value hash, so it's the same no matter how the value hash is computed.
+pub fn hash_leaf_with_value_hash<D: Digest>(
+    scheme: HashScheme,
+    version: u64,
+    key: &[u8],
+    value_hash: &[u8],
+) -> Output<D> {
+    let mut hasher = D::new();
+    if let Some(prefix) = scheme.leaf_prefix {
+        hasher.update([prefix]);
+    }
+    hash_header(&mut hasher, 0, 1, version);
+    hash_bytes(&mut hasher, key);
+    hash_bytes(&mut hasher, value_hash);
+    hasher.finalize()
+}
+
+// hash_inner computes the hash of an inner node from the hashes of its children.
+pub fn hash_inner<D: Digest>(
+    scheme: HashScheme,
+    height: u8,
+    size: u64,
+    version: u64,
+    left_hash: &[u8],
+    right_hash: &[u8],
+) -> Output<D> {
+    let mut hasher = D::new();
+    if let Some(prefix) = scheme.inner_prefix {
+        hasher.update([prefix]);
+    }
+    hash_header(&mut hasher, height, size, version);
+    hash_bytes(&mut hasher, left_hash);
+    hash_bytes(&mut hasher, right_hash);
+    hasher.finalize()
+}
+
+fn hash_header<D: Digest>(hasher: &mut D, height: u8, size: u64, version: u64) {
+    let mut buf = [0u8; 8];
 
     {
-        let n = (node.size as i64).encode_var(&mut buf);
+        let n = (height as i64).encode_var(&mut buf);
         hasher.update(&buf[..n]);
     }
 
     {
-        let n = (node.version as i64).encode_var(&mut buf);
+        let n = (size as i64).encode_var(&mut buf);
         hasher.update(&buf[..n]);
     }
 
-    if node.is_leaf() {
-        hash_bytes(&mut hasher, &node.key);
-        hash_bytes(&mut hasher, &Sha256::digest(&node.value));
-    } else {
-        let left_hash = node.left.as_mut().unwrap().update_hash();
-        hash_bytes(&mut hasher, left_hash);
-
-        let right_hash = node.right.as_mut().unwrap().update_hash();
-        hash_bytes(&mut hasher, right_hash);
+    {
+        let n = (version as i64).encode_var(&mut buf);
+        hasher.update(&buf[..n]);
     }
-
-    hasher.finalize()
 }
 
-fn hash_bytes(hasher: &mut Sha256, bytes: &[u8]) {
+fn hash_bytes<D: Digest>(hasher: &mut D, bytes: &[u8]) {
     let mut buf = [0u8; 8];
     let n = bytes.len().encode_var(&mut buf);
     hasher.update(&buf[..n]);
@@ -157,12 +569,67 @@ mod tests {
     use super::*;
     use hexhex::hex_literal;
 
+    #[test]
+    fn test_heap_size() {
+        let leaf = Node::<Sha256>::leaf(b"key1".to_vec(), b"value1".to_vec(), 0);
+        let leaf_size = leaf.heap_size();
+        assert!(leaf_size >= mem::size_of::<Node<Sha256>>() + 10);
+
+        let node = Node::branch_bottom(
+            NodeRef::new(leaf),
+            NodeRef::new(Node::leaf(b"key2".to_vec(), b"value2".to_vec(), 0)),
+            1,
+        );
+        assert!(node.heap_size() > 3 * leaf_size);
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let mut leaf1 = Node::<Sha256>::leaf(b"key1".to_vec(), b"value1".to_vec(), 1);
+        let mut leaf2 = Node::leaf(b"key2".to_vec(), b"value2".to_vec(), 2);
+        leaf2.update_hash(HashScheme::default());
+        let mut decoded = Node::<Sha256>::decode(&leaf1.encode(), |_, _| unreachable!()).unwrap();
+        assert_eq!(
+            decoded.update_hash(HashScheme::default()),
+            leaf1.update_hash(HashScheme::default())
+        );
+
+        let leaves = [NodeRef::new(leaf1), NodeRef::new(leaf2)];
+        let mut node = Node::branch_bottom(leaves[0].clone(), leaves[1].clone(), 3);
+        let hash = *node.update_hash(HashScheme::default());
+        let bz = node.encode();
+
+        let mut decoded = Node::decode(&bz, |hash, _| {
+            leaves
+                .iter()
+                .find(|leaf| leaf.hash.as_ref() == Some(&hash))
+                .unwrap()
+                .clone()
+        })
+        .unwrap();
+        assert_eq!((decoded.height, decoded.size, decoded.version), (1, 2, 3));
+        assert_eq!(decoded.key, b"key2");
+        assert_eq!(decoded.update_hash(HashScheme::default()), &hash);
+        assert_eq!(decoded.encode(), bz);
+
+        // truncated or trailing bytes
+        let bz = decoded.left.unwrap().encode();
+        assert!(Node::<Sha256>::decode(&bz[..bz.len() - 1], |_, _| unreachable!()).is_err());
+        let mut bz = bz;
+        bz.push(0);
+        assert!(Node::<Sha256>::decode(&bz, |_, _| unreachable!()).is_err());
+    }
+
     #[test]
     fn test_hash() {
-        let node1 = Box::new(Node::leaf(b"key1".to_vec(), b"value1".to_vec(), 0));
-        let node2 = Box::new(Node::leaf(b"key2".to_vec(), b"value2".to_vec(), 0));
+        let node1 = NodeRef::new(Node::<Sha256>::leaf(
+            b"key1".to_vec(),
+            b"value1".to_vec(),
+            0,
+        ));
+        let node2 = NodeRef::new(Node::leaf(b"key2".to_vec(), b"value2".to_vec(), 0));
         let mut node3 = Node::branch_bottom(node1.clone(), node2.clone(), 1);
-        node3.update_hash();
+        node3.update_hash(HashScheme::default());
 
         assert_eq!(
             node3.left.unwrap().hash.as_deref().expect(""),
@@ -177,4 +644,34 @@ mod tests {
             hex_literal!("d315e38c4e0093b72123fe70733a733a3fc185dfbce72357595738672ba984f2")
         );
     }
+
+    #[test]
+    fn test_hash_leaf_reader() {
+        const SIZE: u64 = 10 * 1024 * 1024;
+        let value = (0..SIZE).map(|i| i as u8).collect::<Vec<_>>();
+        let expected = hash_leaf::<Sha256>(HashScheme::default(), 1, b"key", &value);
+
+        // streamed in 64KB chunks, the value is generated on the fly
+        let reader = ChunkReader((0..SIZE).map(|i| i as u8));
+        let hash = hash_leaf_reader::<Sha256>(HashScheme::default(), 1, b"key", reader, 64 * 1024)
+            .unwrap();
+        assert_eq!(hash, expected);
+
+        // same as the hash of the leaf node
+        let mut leaf = Node::<Sha256>::leaf(b"key".to_vec(), value, 1);
+        assert_eq!(leaf.update_hash(HashScheme::default()).clone(), expected);
+    }
+
+    struct ChunkReader<I>(I);
+
+    impl<I: Iterator<Item = u8>> Read for ChunkReader<I> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let mut n = 0;
+            for (dst, src) in buf.iter_mut().zip(&mut self.0) {
+                *dst = src;
+                n += 1;
+            }
+            Ok(n)
+        }
+    }
 }
@@ -0,0 +1,240 @@
+use crypto_common::Output;
+use sha2::Digest;
+use std::path::Path;
+use std::sync::Arc;
+
+use super::error::DbError;
+use super::node::{decode_hash, Node, NodeRef};
+
+// NodeDB persists the tree nodes keyed by their hashes, and the root hash of each version,
+// so the tree can be loaded lazily from the root instead of replaying all the changes.
+pub struct NodeDB {
+    db: sled::Db,
+    nodes: sled::Tree,
+    roots: sled::Tree,
+}
+
+impl NodeDB {
+    pub fn open(path: impl AsRef<Path>) -> Result<Arc<Self>, DbError> {
+        // the background flusher is disabled, the db is flushed on each saved version,
+        // the flusher thread may also hold the file lock for a while after the db is dropped.
+        let db = sled::Config::new().path(path).flush_every_ms(None).open()?;
+        let nodes = db.open_tree("nodes")?;
+        let roots = db.open_tree("roots")?;
+        Ok(Arc::new(NodeDB { db, nodes, roots }))
+    }
+
+    // latest_root returns the latest saved version and the root node of it, `None` if nothing is saved.
+    #[allow(clippy::type_complexity)]
+    pub fn latest_root<D: Digest>(
+        self: &Arc<Self>,
+    ) -> Result<Option<(u64, Option<NodeRef<D>>)>, DbError> {
+        let Some((version, hash)) = self.roots.last()? else {
+            return Ok(None);
+        };
+        let version = u64::from_be_bytes(
+            version
+                .as_ref()
+                .try_into()
+                .map_err(|_| DbError::Decode("invalid version in node db".to_string()))?,
+        );
+        let root = if hash.is_empty() {
+            None
+        } else {
+            Some(NodeRef::stored(
+                decode_hash::<D>(&hash)?,
+                self.clone(),
+                u8::MAX,
+            ))
+        };
+        Ok(Some((version, root)))
+    }
+
+    // root returns the root node of the saved version, `None` if the version is not saved.
+    #[allow(clippy::type_complexity)]
+    pub fn root<D: Digest>(
+        self: &Arc<Self>,
+        version: u64,
+    ) -> Result<Option<Option<NodeRef<D>>>, DbError> {
+        let Some(hash) = self.roots.get(version.to_be_bytes())? else {
+            return Ok(None);
+        };
+        if hash.is_empty() {
+            return Ok(Some(None));
+        }
+        Ok(Some(Some(NodeRef::stored(
+            decode_hash::<D>(&hash)?,
+            self.clone(),
+            u8::MAX,
+        ))))
+    }
+
+    // load reads the node from the db, the children are referenced by hash and loaded on demand,
+    // fails if the node is higher than `max_height`.
+    pub fn load<D: Digest>(
+        self: &Arc<Self>,
+        hash: &Output<D>,
+        max_height: u8,
+    ) -> Result<Node<D>, DbError> {
+        let bz = self
+            .nodes
+            .get(hash)?
+            .ok_or_else(|| DbError::Decode(format!("node not found: {:x?}", hash.as_slice())))?;
+        let mut node = Node::decode(&bz, |hash, max_height| {
+            NodeRef::stored(hash, self.clone(), max_height)
+        })?;
+        if node.height > max_height {
+            return Err(DbError::Decode(format!(
+                "node height {} exceeds {}",
+                node.height, max_height
+            )));
+        }
+        node.hash = Some(hash.clone());
+        Ok(node)
+    }
+
+    // check_subtree loads all the nodes reachable from the hash and checks their heights, so
+    // a corrupted subtree fails here instead of panicking on a lazy load later, the nodes are
+    // dropped after the check, it reads the whole subtree once without keeping it in memory.
+    pub fn check_subtree<D: Digest>(
+        self: &Arc<Self>,
+        hash: &Output<D>,
+        max_height: u8,
+    ) -> Result<(), DbError> {
+        let node = self.load::<D>(hash, max_height)?;
+        for child in [&node.left, &node.right].into_iter().flatten() {
+            if let NodeRef::Stored(stored) = child {
+                self.check_subtree::<D>(&stored.hash, stored.max_height)?;
+            }
+        }
+        Ok(())
+    }
+
+    // save_version persists the nodes created in the version and the root hash of it,
+    // the hashes of the tree must be computed beforehand.
+    pub fn save_version<D: Digest>(
+        &self,
+        version: u64,
+        root: Option<&NodeRef<D>>,
+    ) -> Result<(), DbError> {
+        self.save_nodes(version, root, version)
+    }
+
+    // save_tree persists all the loaded nodes of the tree as the version, used to write
+    // a tree not built on top of the node db, like the one imported from a snapshot.
+    pub fn save_tree<D: Digest>(
+        &self,
+        version: u64,
+        root: Option<&NodeRef<D>>,
+    ) -> Result<(), DbError> {
+        self.save_nodes(version, root, 0)
+    }
+
+    fn save_nodes<D: Digest>(
+        &self,
+        version: u64,
+        root: Option<&NodeRef<D>>,
+        since: u64,
+    ) -> Result<(), DbError> {
+        let mut batch = sled::Batch::default();
+        let mut root_hash = Vec::new();
+        if let Some(root) = root {
+            collect_nodes(root, since, &mut batch);
+            root_hash = root.cached_hash().unwrap().to_vec();
+        }
+        self.nodes.apply_batch(batch)?;
+        self.roots.insert(version.to_be_bytes(), root_hash)?;
+        self.db.flush()?;
+        Ok(())
+    }
+}
+
+// collect_nodes collects the loaded nodes created since the version, the older nodes are persisted already.
+fn collect_nodes<D: Digest>(node: &NodeRef<D>, since: u64, batch: &mut sled::Batch) {
+    let NodeRef::Loaded(node) = node else {
+        return;
+    };
+    if node.version < since {
+        return;
+    }
+
+    batch.insert(node.hash.as_ref().unwrap().as_slice(), node.encode());
+    if !node.is_leaf() {
+        collect_nodes(node.left.as_ref().unwrap(), since, batch);
+        collect_nodes(node.right.as_ref().unwrap(), since, batch);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::Sha256;
+
+    #[test]
+    fn test_load_max_height() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = NodeDB::open(dir.path()).unwrap();
+
+        // a corrupted chain of inner nodes which don't get lower, unbounded to recurse over.
+        let hash = |i: u8| Output::<Sha256>::clone_from_slice(&[i; 32]);
+        for i in 0u8..100 {
+            let node = Node::<Sha256> {
+                height: 5,
+                size: 2,
+                version: 1,
+                key: vec![i],
+                value: vec![],
+                left: Some(NodeRef::stored(hash(i + 1), db.clone(), 0)),
+                right: Some(NodeRef::stored(hash(200), db.clone(), 0)),
+                hash: None,
+            };
+            db.nodes.insert(hash(i), node.encode()).unwrap();
+        }
+
+        let root = db.load::<Sha256>(&hash(0), u8::MAX).unwrap();
+        let NodeRef::Stored(child) = root.left.as_ref().unwrap() else {
+            panic!("child is not stored");
+        };
+        assert_eq!(child.max_height, 4);
+        let err = db
+            .load::<Sha256>(&child.hash, child.max_height)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "node height 5 exceeds 4");
+
+        // the height must fit in u8
+        let mut bz = Vec::new();
+        integer_encoding::VarIntWriter::write_varint(&mut bz, 256u64).unwrap();
+        db.nodes.insert(hash(255), bz).unwrap();
+        assert!(db.load::<Sha256>(&hash(255), u8::MAX).is_err());
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn test_compressed_values() {
+        use crate::{IAVLTree, KVStore};
+
+        let dir = tempfile::tempdir().unwrap();
+        let db = NodeDB::open(dir.path()).unwrap();
+        let mut tree = IAVLTree::new();
+        for i in 0u32..100 {
+            tree.set(i.to_be_bytes().to_vec(), i.to_be_bytes().repeat(1000));
+        }
+        let hash = *tree.save_version();
+        db.save_version(1, tree.root()).unwrap();
+
+        // the values are stored compressed
+        let stored = db
+            .nodes
+            .iter()
+            .map(|item| item.unwrap().1.len())
+            .sum::<usize>();
+        assert!(stored < 100 * 4000 / 10);
+
+        // the loaded values match the hashes computed over the uncompressed ones
+        let (_, root) = db.latest_root::<Sha256>().unwrap().unwrap();
+        let mut loaded = IAVLTree::load(1, root);
+        assert_eq!(*loaded.root_hash(), hash);
+        assert!(loaded.range(..).eq(tree.range(..)));
+        loaded.check_invariants().unwrap();
+    }
+}
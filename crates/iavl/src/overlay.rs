@@ -1,20 +1,84 @@
-use std::collections::BTreeMap;
-use std::ops::RangeBounds;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::{Bound, RangeBounds};
 
-use super::{KVStore, MergeIter};
+use super::types::prefix_end;
+use super::{DynIterator, KVStore, KVStoreExt, MergeIter};
 
-pub struct Overlay<'a, S> {
-    pub parent: &'a mut S,
+// Overlay buffers the writes on top of the parent store, which can be owned or borrowed as
+// `&mut S`.
+pub struct Overlay<S> {
+    pub parent: S,
 
     // use `Option` as value to represent deletion(tomestone).
     pub tree: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+
+    // the keys accessed by `get` and `range`, `None` if read tracking is disabled.
+    reads: Option<RefCell<BTreeSet<Vec<u8>>>>,
 }
 
-impl<'a, S: KVStore> Overlay<'a, S> {
-    pub fn new(parent: &'a mut S) -> Self {
+impl<S: KVStore> Overlay<S> {
+    pub fn new(parent: S) -> Self {
         Self {
             parent,
             tree: BTreeMap::new(),
+            reads: None,
+        }
+    }
+
+    // track_reads enables or disables the read tracking, the recorded keys are cleared.
+    pub fn track_reads(&mut self, enable: bool) {
+        self.reads = enable.then(RefCell::default);
+    }
+
+    // read_keys returns the keys read so far, empty if read tracking is disabled,
+    // the keys yielded by `range` are recorded as they are iterated.
+    pub fn read_keys(&self) -> BTreeSet<Vec<u8>> {
+        self.reads
+            .as_ref()
+            .map(|reads| reads.borrow().clone())
+            .unwrap_or_default()
+    }
+
+    // dirty_keys returns the keys written or removed, in order.
+    pub fn dirty_keys(&self) -> impl Iterator<Item = &[u8]> {
+        self.tree.keys().map(Vec::as_slice)
+    }
+
+    // pending_len returns the number of pending changes, including the deletions.
+    pub fn pending_len(&self) -> usize {
+        self.tree.len()
+    }
+
+    // pending_bytes estimates the size of the pending changes, a deletion only counts the key.
+    pub fn pending_bytes(&self) -> usize {
+        self.tree
+            .iter()
+            .map(|(key, value)| key.len() + value.as_ref().map_or(0, Vec::len))
+            .sum()
+    }
+
+    // entry gets the entry of the key for in-place read-modify-write, a deleted key is vacant,
+    // and a key only in the parent store is copied into the overlay, since it may be modified.
+    pub fn entry(&mut self, key: Vec<u8>) -> Entry<'_> {
+        self.record_read(&key);
+        if !self.tree.contains_key(&key) {
+            if let Some(value) = self.parent.get(&key) {
+                self.tree.insert(key.clone(), Some(value.to_vec()));
+            }
+        }
+        match self.tree.get(&key) {
+            Some(Some(_)) => Entry::Occupied(self.tree.get_mut(&key).unwrap().as_mut().unwrap()),
+            _ => Entry::Vacant(VacantEntry {
+                key,
+                tree: &mut self.tree,
+            }),
+        }
+    }
+
+    fn record_read(&self, key: &[u8]) {
+        if let Some(reads) = &self.reads {
+            reads.borrow_mut().insert(key.to_vec());
         }
     }
 
@@ -22,10 +86,114 @@ impl<'a, S: KVStore> Overlay<'a, S> {
     pub fn flush(&mut self) {
         self.parent.write_batch(std::mem::take(&mut self.tree));
     }
+
+    // discard drops all the changes without touching the parent store.
+    pub fn discard(&mut self) {
+        self.tree.clear();
+    }
+
+    // merge applies the pending changes of a sibling overlay on top of the ones of this overlay, for
+    // the keys changed by both, the value or the deletion of `other` wins, like it's applied after
+    // this one. The read keys of `other` are not merged.
+    pub fn merge<P>(&mut self, other: Overlay<P>) {
+        self.tree.extend(other.tree);
+    }
+
+    // compact drops the tombstones of the keys not in the parent store, which shadow nothing,
+    // it costs a `get` on the parent store per tombstone.
+    pub fn compact(&mut self) {
+        let parent = &self.parent;
+        self.tree
+            .retain(|key, value| value.is_some() || parent.get(key).is_some());
+    }
+
+    // clear makes the store appear empty, the pending changes are dropped, and all the keys in the
+    // parent store are shadowed with tombstones, so they are removed from the parent on flush,
+    // the keys added to the parent afterwards are visible through the overlay.
+    pub fn clear(&mut self) {
+        self.tree = self
+            .parent
+            .range_dyn(Bound::Unbounded, Bound::Unbounded)
+            .map(|(key, _)| (key, None))
+            .collect();
+    }
+
+    // take_changes returns the pending changes and leaves the overlay empty,
+    // a `None` value represents deletion.
+    pub fn take_changes(&mut self) -> BTreeMap<Vec<u8>, Option<Vec<u8>>> {
+        std::mem::take(&mut self.tree)
+    }
 }
 
-impl<S: KVStore> KVStore for Overlay<'_, S> {
+pub enum Entry<'a> {
+    Occupied(&'a mut Vec<u8>),
+    Vacant(VacantEntry<'a>),
+}
+
+pub struct VacantEntry<'a> {
+    key: Vec<u8>,
+    tree: &'a mut BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl<'a> VacantEntry<'a> {
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    pub fn insert(self, value: Vec<u8>) -> &'a mut Vec<u8> {
+        self.tree.entry(self.key).or_default().insert(value)
+    }
+}
+
+impl<'a> Entry<'a> {
+    pub fn or_insert(self, default: Vec<u8>) -> &'a mut Vec<u8> {
+        self.or_insert_with(|| default)
+    }
+
+    pub fn or_insert_with(self, f: impl FnOnce() -> Vec<u8>) -> &'a mut Vec<u8> {
+        match self {
+            Entry::Occupied(value) => value,
+            Entry::Vacant(entry) => entry.insert(f()),
+        }
+    }
+
+    pub fn or_default(self) -> &'a mut Vec<u8> {
+        self.or_insert_with(Vec::new)
+    }
+
+    pub fn and_modify(mut self, f: impl FnOnce(&mut Vec<u8>)) -> Self {
+        if let Entry::Occupied(value) = &mut self {
+            f(value);
+        }
+        self
+    }
+}
+
+impl<S: KVStoreExt> Overlay<S> {
+    // range_bounds is `range` with owned endpoints, so the callers don't need a `Clone` range,
+    // the endpoints are cloned once for the parent.
+    pub fn range_bounds(
+        &self,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+    ) -> impl DoubleEndedIterator<Item = (&[u8], &[u8])> {
+        self.range((start, end))
+    }
+
+    // prefix_range iterates the merged entries whose keys start with the prefix, same as
+    // `prefix_iter`, the keys removed in the overlay are skipped.
+    pub fn prefix_range(&self, prefix: &[u8]) -> impl DoubleEndedIterator<Item = (&[u8], &[u8])> {
+        let end = match prefix_end(prefix) {
+            Some(end) => Bound::Excluded(end),
+            None => Bound::Unbounded,
+        };
+        self.range_bounds(Bound::Included(prefix.to_vec()), end)
+    }
+}
+
+impl<S: KVStore> KVStore for Overlay<S> {
     fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        self.record_read(key);
         match self.tree.get(key) {
             Some(value) => value.as_deref(),
             None => self.parent.get(key),
@@ -40,6 +208,20 @@ impl<S: KVStore> KVStore for Overlay<'_, S> {
         self.tree.insert(key.to_vec(), None);
     }
 
+    fn range_dyn(&self, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>) -> DynIterator<'_> {
+        Box::new(
+            MergeIter::new(
+                self.tree
+                    .range((start.clone(), end.clone()))
+                    .map(|(k, v)| (k.clone(), v.clone())),
+                self.parent.range_dyn(start, end),
+            )
+            .inspect(|(k, _)| self.record_read(k)),
+        )
+    }
+}
+
+impl<S: KVStoreExt> KVStoreExt for Overlay<S> {
     fn range<R>(&self, bounds: R) -> impl DoubleEndedIterator<Item = (&[u8], &[u8])>
     where
         R: RangeBounds<Vec<u8>> + Clone,
@@ -50,13 +232,14 @@ impl<S: KVStore> KVStore for Overlay<'_, S> {
                 .map(|(k, v)| (k.as_slice(), v.as_deref())),
             self.parent.range(bounds),
         )
+        .inspect(|(k, _)| self.record_read(k))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::MemTree;
+    use crate::{IAVLTree, MemTree};
 
     #[test]
     fn test_overlay() {
@@ -77,6 +260,325 @@ mod tests {
         assert_eq!(parent.get(b"removed"), None);
     }
 
+    #[test]
+    fn test_overlay_parent() {
+        let mut parent = MemTree::new();
+        parent.set(b"key1".to_vec(), b"value1".to_vec());
+
+        // owned parent
+        let mut overlay = Overlay::new(parent);
+        overlay.set(b"key2".to_vec(), b"value2".to_vec());
+        assert_eq!(overlay.range(..).count(), 2);
+        overlay.flush();
+        let mut parent = overlay.parent;
+        assert_eq!(parent.get(b"key2"), Some(b"value2" as &[u8]));
+
+        // borrowed parent, and an overlay borrowing another one
+        let mut overlay = Overlay::new(&mut parent);
+        let mut nested = Overlay::new(&mut overlay);
+        nested.remove(b"key1");
+        assert_eq!(nested.range(..).count(), 1);
+        nested.flush();
+        overlay.flush();
+        assert_eq!(parent.get(b"key1"), None);
+        assert_eq!(parent.get(b"key2"), Some(b"value2" as &[u8]));
+    }
+
+    #[test]
+    fn test_overlay_merge() {
+        let mut parent = IAVLTree::new();
+        parent.set(b"key1".to_vec(), b"value1".to_vec());
+        parent.set(b"key2".to_vec(), b"value2".to_vec());
+
+        let write1 = |kv: &mut dyn KVStore| {
+            kv.set(b"key1".to_vec(), b"a".to_vec());
+            kv.set(b"key3".to_vec(), b"a".to_vec());
+            kv.remove(b"key2");
+        };
+        let write2 = |kv: &mut dyn KVStore| {
+            kv.set(b"key2".to_vec(), b"b".to_vec());
+            kv.remove(b"key3");
+            kv.set(b"key4".to_vec(), b"b".to_vec());
+        };
+
+        let mut expected = parent.clone();
+        write1(&mut expected);
+        write2(&mut expected);
+
+        // the siblings own copies of the parent
+        let mut overlay1 = Overlay::new(parent.clone());
+        let mut overlay2 = Overlay::new(parent.clone());
+        write1(&mut overlay1);
+        write2(&mut overlay2);
+        overlay1.merge(overlay2);
+
+        let mut merged = Overlay::new(&mut parent);
+        merged.merge(overlay1);
+        merged.flush();
+        assert_eq!(
+            parent.range(..).collect::<Vec<_>>(),
+            expected.range(..).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_overlay_entry() {
+        let mut parent = MemTree::new();
+        parent.set(b"parent".to_vec(), b"value".to_vec());
+        parent.set(b"removed".to_vec(), b"value".to_vec());
+
+        let mut overlay = Overlay::new(&mut parent);
+        overlay.set(b"local".to_vec(), b"value".to_vec());
+        overlay.remove(b"removed");
+        for key in [b"parent" as &[u8], b"local", b"removed", b"missing"] {
+            overlay
+                .entry(key.to_vec())
+                .and_modify(|v| v.push(b'!'))
+                .or_insert(b"new".to_vec());
+        }
+        assert_eq!(overlay.get(b"parent"), Some(b"value!".as_ref()));
+        assert_eq!(overlay.get(b"local"), Some(b"value!".as_ref()));
+        assert_eq!(overlay.get(b"removed"), Some(b"new".as_ref()));
+        assert_eq!(overlay.get(b"missing"), Some(b"new".as_ref()));
+
+        // a vacant entry left untouched is not written
+        assert!(matches!(overlay.entry(b"other".to_vec()), Entry::Vacant(_)));
+        assert_eq!(overlay.pending_len(), 4);
+
+        overlay.flush();
+        assert_eq!(parent.get(b"parent"), Some(b"value!".as_ref()));
+        assert_eq!(parent.get(b"removed"), Some(b"new".as_ref()));
+    }
+
+    #[test]
+    fn test_overlay_clear() {
+        let mut parent = MemTree::new();
+        parent.set(b"key1".to_vec(), b"value1".to_vec());
+        parent.set(b"key2".to_vec(), b"value2".to_vec());
+
+        let mut overlay = Overlay::new(&mut parent);
+        overlay.set(b"key3".to_vec(), b"value3".to_vec());
+        overlay.clear();
+        for key in [b"key1", b"key2", b"key3"] {
+            assert_eq!(overlay.get(key), None);
+        }
+        assert_eq!(overlay.range(..).count(), 0);
+        assert_eq!(overlay.pending_len(), 2);
+
+        overlay.set(b"key2".to_vec(), b"new".to_vec());
+        overlay.flush();
+        assert_eq!(
+            parent.range(..).collect::<Vec<_>>(),
+            vec![(b"key2" as &[u8], b"new" as &[u8])]
+        );
+    }
+
+    #[test]
+    fn test_overlay_clear_large() {
+        let parent = (0u32..200_000)
+            .map(|i| (i.to_be_bytes().to_vec(), vec![1]))
+            .collect::<MemTree>();
+        let mut overlay = Overlay::new(parent);
+        overlay.clear();
+        // the tombstones are skipped without recursion
+        assert_eq!(overlay.range(..).count(), 0);
+        assert_eq!(overlay.range(..).next_back(), None);
+        assert_eq!(
+            overlay
+                .range_dyn(Bound::Unbounded, Bound::Unbounded)
+                .count(),
+            0
+        );
+
+        overlay.set(200_000u32.to_be_bytes().to_vec(), vec![2]);
+        overlay.set(100_000u32.to_be_bytes().to_vec(), vec![2]);
+        let keys = overlay
+            .range(..)
+            .map(|(k, _)| k.to_vec())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            keys,
+            vec![
+                100_000u32.to_be_bytes().to_vec(),
+                200_000u32.to_be_bytes().to_vec()
+            ]
+        );
+        assert!(overlay
+            .range(..)
+            .rev()
+            .map(|(k, _)| k.to_vec())
+            .eq(keys.into_iter().rev()));
+    }
+
+    #[test]
+    fn test_overlay_compact() {
+        let mut parent = MemTree::new();
+        parent.set(b"key1".to_vec(), b"value1".to_vec());
+
+        let mut overlay = Overlay::new(&mut parent);
+        overlay.set(b"key2".to_vec(), b"value2".to_vec());
+        overlay.remove(b"key2");
+        overlay.remove(b"key1");
+        overlay.set(b"key3".to_vec(), b"value3".to_vec());
+        assert_eq!(overlay.pending_len(), 3);
+
+        overlay.compact();
+        assert_eq!(
+            overlay.dirty_keys().collect::<Vec<_>>(),
+            vec![b"key1" as &[u8], b"key3"]
+        );
+        assert_eq!(overlay.get(b"key1"), None);
+        assert_eq!(overlay.get(b"key2"), None);
+    }
+
+    #[test]
+    fn test_overlay_remove_taking() {
+        let mut parent = MemTree::new();
+        parent.set(b"key1".to_vec(), b"value1".to_vec());
+
+        // the default implementation
+        let mut overlay = Overlay::new(&mut parent);
+        assert_eq!(overlay.remove_taking(b"key1"), Some(b"value1".to_vec()));
+        assert_eq!(overlay.get(b"key1"), None);
+        assert_eq!(overlay.remove_taking(b"key1"), None);
+    }
+
+    #[test]
+    fn test_overlay_discard() {
+        let mut parent = MemTree::new();
+        parent.set(b"key1".to_vec(), b"value1".to_vec());
+        parent.set(b"key2".to_vec(), b"value2".to_vec());
+
+        let mut overlay = Overlay::new(&mut parent);
+        overlay.set(b"key1".to_vec(), b"new_value1".to_vec());
+        overlay.set(b"key3".to_vec(), b"value3".to_vec());
+        overlay.remove(b"key2");
+        assert_eq!(overlay.get(b"key1"), Some(b"new_value1" as &[u8]));
+
+        overlay.discard();
+        assert_eq!(overlay.get(b"key1"), Some(b"value1" as &[u8]));
+        assert_eq!(overlay.get(b"key2"), Some(b"value2" as &[u8]));
+        assert_eq!(overlay.get(b"key3"), None);
+
+        // the overlay is reusable after discard
+        overlay.set(b"key3".to_vec(), b"value3".to_vec());
+        overlay.remove(b"key1");
+        assert_eq!(
+            overlay.take_changes(),
+            BTreeMap::from([
+                (b"key1".to_vec(), None),
+                (b"key3".to_vec(), Some(b"value3".to_vec())),
+            ])
+        );
+        assert!(overlay.tree.is_empty());
+        overlay.flush();
+
+        assert_eq!(
+            parent.range(..).collect::<Vec<_>>(),
+            vec![
+                (b"key1" as &[u8], b"value1" as &[u8]),
+                (b"key2" as &[u8], b"value2" as &[u8]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_overlay_access_keys() {
+        let mut parent = MemTree::new();
+        for key in [b"key1", b"key2", b"key3", b"key4"] {
+            parent.set(key.to_vec(), b"value".to_vec());
+        }
+
+        let mut overlay = Overlay::new(&mut parent);
+        overlay.get(b"key1");
+        assert!(overlay.read_keys().is_empty());
+
+        overlay.track_reads(true);
+        overlay.set(b"key5".to_vec(), b"value".to_vec());
+        overlay.remove(b"key2");
+        overlay.get(b"key1");
+        overlay.get(b"missing");
+        // only the yielded keys are recorded
+        overlay.range(b"key3".to_vec()..).next();
+        assert_eq!(
+            overlay.read_keys(),
+            BTreeSet::from([b"key1".to_vec(), b"key3".to_vec(), b"missing".to_vec()])
+        );
+        overlay
+            .range_dyn(Bound::Unbounded, Bound::Unbounded)
+            .for_each(drop);
+        assert_eq!(
+            overlay.read_keys(),
+            BTreeSet::from([
+                b"key1".to_vec(),
+                b"key3".to_vec(),
+                b"key4".to_vec(),
+                b"key5".to_vec(),
+                b"missing".to_vec()
+            ])
+        );
+        assert_eq!(
+            overlay.dirty_keys().collect::<Vec<_>>(),
+            vec![b"key2" as &[u8], b"key5"]
+        );
+
+        overlay.track_reads(false);
+        overlay.get(b"key1");
+        assert!(overlay.read_keys().is_empty());
+    }
+
+    #[test]
+    fn test_overlay_flush_batch() {
+        let mut batched = IAVLTree::new();
+        let mut sequential = IAVLTree::new();
+        for i in 0u32..100 {
+            batched.set(i.to_be_bytes().to_vec(), i.to_be_bytes().to_vec());
+            sequential.set(i.to_be_bytes().to_vec(), i.to_be_bytes().to_vec());
+        }
+        batched.save_version();
+        sequential.save_version();
+
+        let mut overlay = Overlay::new(&mut batched);
+        for i in (50u32..150).rev() {
+            if i % 3 == 0 {
+                overlay.remove(&i.to_be_bytes());
+            } else {
+                overlay.set(i.to_be_bytes().to_vec(), b"new".to_vec());
+            }
+        }
+        let changes = overlay.tree.clone();
+        overlay.flush();
+
+        for (key, value) in changes {
+            match value {
+                Some(value) => sequential.set(key, value),
+                None => sequential.remove(&key),
+            }
+        }
+        assert_eq!(batched.save_version(), sequential.save_version());
+    }
+
+    #[test]
+    fn test_overlay_pending_size() {
+        let mut parent = MemTree::new();
+        let mut overlay = Overlay::new(&mut parent);
+        assert_eq!((overlay.pending_len(), overlay.pending_bytes()), (0, 0));
+
+        overlay.set(b"key1".to_vec(), b"value1".to_vec());
+        overlay.set(b"key2".to_vec(), b"value2".to_vec());
+        overlay.set(b"key3".to_vec(), b"value3".to_vec());
+        assert_eq!((overlay.pending_len(), overlay.pending_bytes()), (3, 30));
+
+        // the tombstone only counts the key
+        overlay.remove(b"key2");
+        assert_eq!((overlay.pending_len(), overlay.pending_bytes()), (3, 24));
+        overlay.remove(b"key4");
+        assert_eq!((overlay.pending_len(), overlay.pending_bytes()), (4, 28));
+
+        overlay.flush();
+        assert_eq!((overlay.pending_len(), overlay.pending_bytes()), (0, 0));
+    }
+
     #[test]
     fn test_overlay_range() {
         let mut parent = MemTree::new();
@@ -114,4 +616,129 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn test_overlay_range_dyn() {
+        let mut parent = IAVLTree::new();
+        parent.set(b"key1".to_vec(), b"value1".to_vec());
+        parent.set(b"key2".to_vec(), b"value2".to_vec());
+        parent.set(b"key3".to_vec(), b"value3".to_vec());
+
+        let mut overlay = Overlay::new(&mut parent);
+        overlay.set(b"key2".to_vec(), b"new_value2".to_vec());
+        overlay.remove(b"key3");
+
+        let expected = overlay
+            .range(..)
+            .map(|(k, v)| (k.to_vec(), v.to_vec()))
+            .collect::<Vec<_>>();
+        let store: &dyn KVStore = &overlay;
+        assert_eq!(
+            store
+                .range_dyn(Bound::Unbounded, Bound::Unbounded)
+                .collect::<Vec<_>>(),
+            expected
+        );
+        assert_eq!(
+            store
+                .range_dyn(Bound::Unbounded, Bound::Included(b"key2".to_vec()))
+                .rev()
+                .collect::<Vec<_>>(),
+            vec![
+                (b"key2".to_vec(), b"new_value2".to_vec()),
+                (b"key1".to_vec(), b"value1".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_overlay_range_bounds() {
+        let mut parent = MemTree::new();
+        parent.set(b"key1".to_vec(), b"value1".to_vec());
+        parent.set(b"key2".to_vec(), b"value2".to_vec());
+        parent.set(b"key3".to_vec(), b"value3".to_vec());
+
+        let mut overlay = Overlay::new(&mut parent);
+        overlay.set(b"key4".to_vec(), b"value4".to_vec());
+        overlay.remove(b"key2");
+
+        assert_eq!(
+            overlay
+                .range_bounds(Bound::Excluded(b"key1".to_vec()), Bound::Unbounded)
+                .collect::<Vec<_>>(),
+            vec![
+                (b"key3" as &[u8], b"value3" as &[u8]),
+                (b"key4" as &[u8], b"value4" as &[u8]),
+            ]
+        );
+        assert_eq!(
+            overlay
+                .range_bounds(Bound::Unbounded, Bound::Included(b"key3".to_vec()))
+                .rev()
+                .collect::<Vec<_>>(),
+            vec![
+                (b"key3" as &[u8], b"value3" as &[u8]),
+                (b"key1" as &[u8], b"value1" as &[u8]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_overlay_prefix_range() {
+        let mut parent = MemTree::new();
+        parent.set(b"a".to_vec(), b"0".to_vec());
+        parent.set(b"ab1".to_vec(), b"1".to_vec());
+        parent.set(b"ab2".to_vec(), b"2".to_vec());
+        parent.set(b"ac".to_vec(), b"3".to_vec());
+
+        let mut overlay = Overlay::new(&mut parent);
+        overlay.set(b"ab3".to_vec(), b"4".to_vec());
+        overlay.set(b"b".to_vec(), b"5".to_vec());
+        overlay.remove(b"ab1");
+
+        assert_eq!(
+            overlay.prefix_range(b"ab").collect::<Vec<_>>(),
+            vec![(b"ab2" as &[u8], b"2" as &[u8]), (b"ab3", b"4")]
+        );
+        assert_eq!(
+            overlay
+                .prefix_range(b"a")
+                .rev()
+                .map(|(k, _)| k)
+                .collect::<Vec<_>>(),
+            vec![b"ac" as &[u8], b"ab3", b"ab2", b"a"]
+        );
+        assert_eq!(overlay.prefix_range(b"").count(), 5);
+        assert_eq!(overlay.prefix_range(b"ab1").count(), 0);
+    }
+
+    #[test]
+    fn test_overlay_compare_and_swap() {
+        let mut parent = MemTree::new();
+        parent.set(b"key1".to_vec(), b"value1".to_vec());
+        let mut overlay = Overlay::new(&mut parent);
+
+        // mismatched expected value is a no-op
+        assert!(!overlay.compare_and_swap(b"key1", Some(b"other"), Some(b"new".to_vec())));
+        assert!(!overlay.compare_and_swap(b"key1", None, Some(b"new".to_vec())));
+        assert!(overlay.tree.is_empty());
+
+        // matching the value in the parent
+        assert!(overlay.compare_and_swap(b"key1", Some(b"value1"), Some(b"value2".to_vec())));
+        assert_eq!(overlay.get(b"key1"), Some(b"value2" as &[u8]));
+
+        // absent to present
+        assert!(!overlay.compare_and_swap(b"key2", Some(b"value1"), Some(b"new".to_vec())));
+        assert!(overlay.compare_and_swap(b"key2", None, Some(b"new".to_vec())));
+        assert_eq!(overlay.get(b"key2"), Some(b"new" as &[u8]));
+
+        // present to tombstone, then the key is absent
+        assert!(overlay.compare_and_swap(b"key1", Some(b"value2"), None));
+        assert_eq!(overlay.tree.get(b"key1".as_slice()), Some(&None));
+        assert!(overlay.compare_and_swap(b"key1", None, Some(b"value3".to_vec())));
+
+        overlay.flush();
+        assert_eq!(parent.get(b"key1"), Some(b"value3" as &[u8]));
+        assert_eq!(parent.get(b"key2"), Some(b"new" as &[u8]));
+    }
 }
@@ -0,0 +1,793 @@
+use crypto_common::Output;
+use sha2::{Digest, Sha256};
+use std::cmp::Ordering;
+use std::ops::RangeBounds;
+
+use super::iterator::{end_bound_contains, start_bound_contains};
+use super::node::{hash_inner, hash_leaf, HashScheme, Node, NodeRef};
+
+// ProofInnerNode is one step on the path from a leaf to the root, exactly one of the
+// `left` and `right` hashes is set, it's the sibling of the child on the path.
+#[derive(Debug, Clone)]
+pub struct ProofInnerNode<D: Digest = Sha256> {
+    pub height: u8,
+    pub size: u64,
+    pub version: u64,
+    pub left: Option<Output<D>>,
+    pub right: Option<Output<D>>,
+}
+
+impl<D: Digest> ProofInnerNode<D> {
+    // hash computes the hash of the inner node given the hash of the child on the path,
+    // returns `None` if the sibling hashes are malformed.
+    pub fn hash(&self, scheme: HashScheme, child: &[u8]) -> Option<Output<D>> {
+        match (&self.left, &self.right) {
+            (Some(left), None) => Some(hash_inner::<D>(
+                scheme,
+                self.height,
+                self.size,
+                self.version,
+                left,
+                child,
+            )),
+            (None, Some(right)) => Some(hash_inner::<D>(
+                scheme,
+                self.height,
+                self.size,
+                self.version,
+                child,
+                right,
+            )),
+            _ => None,
+        }
+    }
+}
+
+// ExistenceProof proves a key-value pair exists in the tree with a specific root hash.
+#[derive(Debug, Clone)]
+pub struct ExistenceProof<D: Digest = Sha256> {
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+    // version of the leaf node
+    pub version: u64,
+    // inner nodes from the parent of the leaf up to the root
+    pub path: Vec<ProofInnerNode<D>>,
+    // the hash scheme of the tree, chosen by the prover, the verifiers reject a different one.
+    pub scheme: HashScheme,
+}
+
+impl<D: Digest> ExistenceProof<D> {
+    // calculate_root folds the path on top of the leaf hash to get the root hash.
+    pub fn calculate_root(&self) -> Option<Output<D>> {
+        let mut hash = hash_leaf::<D>(self.scheme, self.version, &self.key, &self.value);
+        for inner in &self.path {
+            hash = inner.hash(self.scheme, &hash)?;
+        }
+        Some(hash)
+    }
+
+    // index returns the index of the leaf and the size of the whole tree,
+    // derived from the sizes of the inner nodes along the path.
+    pub fn index(&self) -> (u64, u64) {
+        let mut index = 0;
+        let mut size = 1;
+        for inner in &self.path {
+            if inner.left.is_some() {
+                index += inner.size.saturating_sub(size);
+            }
+            size = inner.size;
+        }
+        (index, size)
+    }
+
+    // max_version returns the latest version of the nodes on the path, the versions are part of
+    // the hashes, so it's bound by the root hash, the proof of a node written after a version `v`
+    // has a `max_version` larger than `v`.
+    pub fn max_version(&self) -> u64 {
+        self.path
+            .iter()
+            .map(|inner| inner.version)
+            .fold(self.version, u64::max)
+    }
+}
+
+// verify_membership checks the key-value pair exists in the tree with the given root hash and
+// hash scheme, it only recomputes the hashes along the proof path, no access to the tree is needed.
+pub fn verify_membership<D: Digest>(
+    root: &Output<D>,
+    key: &[u8],
+    value: &[u8],
+    proof: &ExistenceProof<D>,
+    scheme: HashScheme,
+) -> bool {
+    proof.scheme == scheme
+        && proof.key == key
+        && proof.value == value
+        && proof.calculate_root().as_ref() == Some(root)
+}
+
+// NonExistenceProof proves a key don't exist in the tree by proving the existence of
+// its immediate neighbors, either of them is missing if the key sorts before the first leaf
+// or after the last leaf, both are missing if the tree is empty.
+#[derive(Debug, Clone)]
+pub struct NonExistenceProof<D: Digest = Sha256> {
+    pub key: Vec<u8>,
+    pub left: Option<ExistenceProof<D>>,
+    pub right: Option<ExistenceProof<D>>,
+}
+
+impl<D: Digest> NonExistenceProof<D> {
+    // verify checks the proof is about the queried key, and the neighbors are adjacent leaves
+    // of the tree with the given root hash, the key in the proof is chosen by the prover.
+    pub fn verify(&self, root: &Output<D>, key: &[u8], scheme: HashScheme) -> bool {
+        if self.key != key {
+            return false;
+        }
+        match (&self.left, &self.right) {
+            (None, None) => root == &D::digest(b""),
+            (Some(left), None) => {
+                let (index, size) = left.index();
+                left.key < self.key
+                    && index + 1 == size
+                    && verify_membership(root, &left.key, &left.value, left, scheme)
+            }
+            (None, Some(right)) => {
+                let (index, _) = right.index();
+                self.key < right.key
+                    && index == 0
+                    && verify_membership(root, &right.key, &right.value, right, scheme)
+            }
+            (Some(left), Some(right)) => {
+                let (left_index, _) = left.index();
+                let (right_index, _) = right.index();
+                left.key < self.key
+                    && self.key < right.key
+                    && left_index + 1 == right_index
+                    && verify_membership(root, &left.key, &left.value, left, scheme)
+                    && verify_membership(root, &right.key, &right.value, right, scheme)
+            }
+        }
+    }
+}
+
+// KeyProof proves either the existence or the non-existence of a key.
+#[derive(Debug, Clone)]
+pub enum KeyProof<D: Digest = Sha256> {
+    Exist(ExistenceProof<D>),
+    NonExist(NonExistenceProof<D>),
+}
+
+impl<D: Digest> KeyProof<D> {
+    // verify checks the key has the value in the tree with the given root hash, or is missing
+    // from it if the value is `None`, a proof for another key never verifies.
+    pub fn verify(
+        &self,
+        root: &Output<D>,
+        key: &[u8],
+        value: Option<&[u8]>,
+        scheme: HashScheme,
+    ) -> bool {
+        match self {
+            KeyProof::Exist(proof) => {
+                value.is_some_and(|value| verify_membership(root, key, value, proof, scheme))
+            }
+            KeyProof::NonExist(proof) => value.is_none() && proof.verify(root, key, scheme),
+        }
+    }
+}
+
+// key_proof descends to the key and builds the proof along the way, if the key don't exist, the
+// leaf reached is one of the neighbors, only the other one takes another descent.
+// the hashes of the whole subtree must be computed beforehand.
+pub fn key_proof<D: Digest>(root: &Node<D>, key: &[u8], scheme: HashScheme) -> KeyProof<D> {
+    let mut path = Vec::new();
+    let mut node = root;
+    let mut index = 0;
+    while !node.is_leaf() {
+        let left = node.left.as_ref().unwrap();
+        let right = node.right.as_ref().unwrap();
+        let mut inner = ProofInnerNode {
+            height: node.height,
+            size: node.size,
+            version: node.version,
+            left: None,
+            right: None,
+        };
+        if key < node.key.as_slice() {
+            inner.right = right.cached_hash().cloned();
+            node = left;
+        } else {
+            inner.left = left.cached_hash().cloned();
+            index += left.size;
+            node = right;
+        }
+        path.push(inner);
+    }
+
+    path.reverse();
+    let proof = ExistenceProof {
+        key: node.key.clone(),
+        value: node.value.clone(),
+        version: node.version,
+        path,
+        scheme,
+    };
+    match node.key.as_slice().cmp(key) {
+        Ordering::Equal => KeyProof::Exist(proof),
+        Ordering::Less => KeyProof::NonExist(NonExistenceProof {
+            key: key.to_vec(),
+            left: Some(proof),
+            right: existence_proof(root, index + 1, scheme),
+        }),
+        Ordering::Greater => KeyProof::NonExist(NonExistenceProof {
+            key: key.to_vec(),
+            left: index
+                .checked_sub(1)
+                .and_then(|i| existence_proof(root, i, scheme)),
+            right: Some(proof),
+        }),
+    }
+}
+
+// RangeProof proves the leaves within a range, the existence proofs of them, and of the immediate
+// neighbors out of the range, the verifier checks the indexes are contiguous, so no leaf is omitted.
+// either neighbor is missing if the range reaches the edge of the tree.
+#[derive(Debug, Clone)]
+pub struct RangeProof<D: Digest = Sha256> {
+    pub left: Option<ExistenceProof<D>>,
+    pub proofs: Vec<ExistenceProof<D>>,
+    pub right: Option<ExistenceProof<D>>,
+}
+
+impl<D: Digest> RangeProof<D> {
+    // verify checks the proofs are all the leaves within the bounds in the tree with the root hash.
+    pub fn verify<R: RangeBounds<Vec<u8>>>(
+        &self,
+        root: &Output<D>,
+        bounds: R,
+        scheme: HashScheme,
+    ) -> bool {
+        let leaves = self
+            .left
+            .iter()
+            .chain(&self.proofs)
+            .chain(&self.right)
+            .collect::<Vec<_>>();
+        if leaves.is_empty() {
+            return root == &D::digest(b"");
+        }
+        if !leaves
+            .iter()
+            .all(|proof| proof.scheme == scheme && proof.calculate_root().as_ref() == Some(root))
+        {
+            return false;
+        }
+        let (first, size) = leaves[0].index();
+        let (last, _) = leaves[leaves.len() - 1].index();
+        let contiguous = leaves
+            .windows(2)
+            .all(|w| w[0].index().0 + 1 == w[1].index().0);
+        contiguous
+            && (self.left.is_some() || first == 0)
+            && (self.right.is_some() || last + 1 == size)
+            && self.proofs.iter().all(|proof| bounds.contains(&proof.key))
+            && self
+                .left
+                .as_ref()
+                .is_none_or(|left| !start_bound_contains(bounds.start_bound(), &left.key))
+            && self
+                .right
+                .as_ref()
+                .is_none_or(|right| !end_bound_contains(bounds.end_bound(), &right.key))
+    }
+}
+
+// MultiProofNode is a node of the tree pruned to the paths of the proven leaves, the subtrees off
+// the paths are replaced by their hashes, so the siblings shared by the paths appear only once.
+#[derive(Debug, Clone)]
+pub enum MultiProofNode<D: Digest = Sha256> {
+    Hash(Output<D>),
+    Leaf {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        version: u64,
+    },
+    Inner {
+        height: u8,
+        size: u64,
+        version: u64,
+        left: Box<MultiProofNode<D>>,
+        right: Box<MultiProofNode<D>>,
+    },
+}
+
+impl<D: Digest> MultiProofNode<D> {
+    pub fn hash(&self, scheme: HashScheme) -> Output<D> {
+        match self {
+            MultiProofNode::Hash(hash) => hash.clone(),
+            MultiProofNode::Leaf {
+                key,
+                value,
+                version,
+            } => hash_leaf::<D>(scheme, *version, key, value),
+            MultiProofNode::Inner {
+                height,
+                size,
+                version,
+                left,
+                right,
+            } => hash_inner::<D>(
+                scheme,
+                *height,
+                *size,
+                *version,
+                &left.hash(scheme),
+                &right.hash(scheme),
+            ),
+        }
+    }
+
+    fn collect_leaves<'a>(&'a self, leaves: &mut Vec<(&'a [u8], &'a [u8])>) {
+        match self {
+            MultiProofNode::Hash(_) => {}
+            MultiProofNode::Leaf { key, value, .. } => leaves.push((key, value)),
+            MultiProofNode::Inner { left, right, .. } => {
+                left.collect_leaves(leaves);
+                right.collect_leaves(leaves);
+            }
+        }
+    }
+}
+
+// MultiProof proves the existence of a set of leaves in one pruned tree, the keys not in the tree
+// are omitted, `root` is `None` if the tree is empty.
+#[derive(Debug, Clone)]
+pub struct MultiProof<D: Digest = Sha256> {
+    pub root: Option<MultiProofNode<D>>,
+    // the hash scheme of the tree, chosen by the prover, the verifiers reject a different one.
+    pub scheme: HashScheme,
+}
+
+impl<D: Digest> MultiProof<D> {
+    // verify checks the proof hashes to the root with the expected hash scheme.
+    pub fn verify(&self, root: &Output<D>, scheme: HashScheme) -> bool {
+        match &self.root {
+            Some(node) => self.scheme == scheme && &node.hash(scheme) == root,
+            None => root == &D::digest(b""),
+        }
+    }
+
+    // leaves returns the proven key-value pairs in order.
+    pub fn leaves(&self) -> Vec<(&[u8], &[u8])> {
+        let mut leaves = Vec::new();
+        if let Some(node) = &self.root {
+            node.collect_leaves(&mut leaves);
+        }
+        leaves
+    }
+}
+
+// verify_multi_proof checks the entries are exactly the leaves of the proof, in strictly ascending
+// order of keys, and the proof hashes to the root, so the entries exist in the tree.
+pub fn verify_multi_proof<D: Digest>(
+    root: &Output<D>,
+    entries: &[(&[u8], &[u8])],
+    proof: &MultiProof<D>,
+    scheme: HashScheme,
+) -> bool {
+    entries.windows(2).all(|pair| pair[0].0 < pair[1].0)
+        && proof.leaves() == entries
+        && proof.verify(root, scheme)
+}
+
+// multi_proof descends to all the keys at once, the keys must be sorted and deduplicated,
+// the hashes of the whole subtree must be computed beforehand.
+pub fn multi_proof<D: Digest>(node: &NodeRef<D>, keys: &[&[u8]]) -> MultiProofNode<D> {
+    if keys.is_empty() || (node.is_leaf() && keys.binary_search(&node.key.as_slice()).is_err()) {
+        return MultiProofNode::Hash(node.cached_hash().unwrap().clone());
+    }
+    if node.is_leaf() {
+        return MultiProofNode::Leaf {
+            key: node.key.clone(),
+            value: node.value.clone(),
+            version: node.version,
+        };
+    }
+    let mid = keys.partition_point(|key| *key < node.key.as_slice());
+    MultiProofNode::Inner {
+        height: node.height,
+        size: node.size,
+        version: node.version,
+        left: Box::new(multi_proof(node.left.as_ref().unwrap(), &keys[..mid])),
+        right: Box::new(multi_proof(node.right.as_ref().unwrap(), &keys[mid..])),
+    }
+}
+
+// existence_proof builds the proof for the leaf at `index`,
+// the hashes of the whole subtree must be computed beforehand.
+pub fn existence_proof<D: Digest>(
+    root: &Node<D>,
+    mut index: u64,
+    scheme: HashScheme,
+) -> Option<ExistenceProof<D>> {
+    let mut path = Vec::new();
+    let mut node = root;
+    while !node.is_leaf() {
+        let left = node.left.as_ref().unwrap();
+        let right = node.right.as_ref().unwrap();
+        let mut inner = ProofInnerNode {
+            height: node.height,
+            size: node.size,
+            version: node.version,
+            left: None,
+            right: None,
+        };
+        if index < left.size {
+            inner.right = right.cached_hash().cloned();
+            node = left;
+        } else {
+            inner.left = left.cached_hash().cloned();
+            index -= left.size;
+            node = right;
+        }
+        path.push(inner);
+    }
+
+    if index != 0 {
+        return None;
+    }
+
+    path.reverse();
+    Some(ExistenceProof {
+        key: node.key.clone(),
+        value: node.value.clone(),
+        version: node.version,
+        path,
+        scheme,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{IAVLTree, KVStore};
+    use std::ops::Bound;
+
+    // SCHEME is the scheme of the trees built in the tests.
+    const SCHEME: HashScheme = HashScheme {
+        leaf_prefix: None,
+        inner_prefix: None,
+    };
+
+    fn build_tree() -> IAVLTree {
+        let mut tree = IAVLTree::new();
+        for i in (0u32..20).step_by(2) {
+            tree.set(i.to_be_bytes().to_vec(), i.to_be_bytes().to_vec());
+        }
+        tree.save_version();
+        tree
+    }
+
+    #[test]
+    fn test_membership_proof() {
+        let mut tree = build_tree();
+        let root = *tree.root_hash();
+
+        let proof = tree.get_membership_proof(&4u32.to_be_bytes()).unwrap();
+        assert_eq!(proof.value, 4u32.to_be_bytes());
+        assert_eq!(proof.calculate_root(), Some(root));
+        assert_eq!(proof.index(), (2, 10));
+
+        assert!(tree.get_membership_proof(&5u32.to_be_bytes()).is_none());
+    }
+
+    #[test]
+    fn test_verify_membership() {
+        let mut tree = build_tree();
+        let root = *tree.root_hash();
+
+        let key = 4u32.to_be_bytes();
+        let mut proof = tree.get_membership_proof(&key).unwrap();
+        drop(tree);
+        assert!(verify_membership(&root, &key, &key, &proof, SCHEME));
+
+        // wrong key or value
+        assert!(!verify_membership(
+            &root,
+            &key,
+            &6u32.to_be_bytes(),
+            &proof,
+            SCHEME
+        ));
+        assert!(!verify_membership(
+            &root,
+            &6u32.to_be_bytes(),
+            &key,
+            &proof,
+            SCHEME
+        ));
+
+        // tampered value in the proof
+        proof.value[3] ^= 1;
+        let value = proof.value.clone();
+        assert!(!verify_membership(&root, &key, &value, &proof, SCHEME));
+    }
+
+    #[test]
+    fn test_non_membership_proof() {
+        let mut tree = build_tree();
+        let root = *tree.root_hash();
+
+        let key = 5u32.to_be_bytes();
+        let proof = tree.get_non_membership_proof(&key);
+        assert_eq!(proof.left.as_ref().unwrap().key, 4u32.to_be_bytes());
+        assert_eq!(proof.right.as_ref().unwrap().key, 6u32.to_be_bytes());
+        assert!(proof.verify(&root, &key, SCHEME));
+        assert!(!proof.verify(&[0u8; 32].into(), &key, SCHEME));
+
+        // the proof is bound to the queried key, even if the other key is also missing.
+        let other = tree.get_non_membership_proof(&0xffu32.to_be_bytes());
+        assert!(other.verify(&root, &0xffu32.to_be_bytes(), SCHEME));
+        assert!(!other.verify(&root, &key, SCHEME));
+
+        // the proof don't hold anymore after the key is inserted.
+        tree.set(key.to_vec(), key.to_vec());
+        let new_root = *tree.save_version();
+        assert!(!proof.verify(&new_root, &key, SCHEME));
+        assert!(!tree
+            .get_non_membership_proof(&key)
+            .verify(&new_root, &key, SCHEME));
+    }
+
+    #[test]
+    fn test_get_with_proof() {
+        let mut tree = build_tree();
+        let root = *tree.root_hash();
+
+        let key = 4u32.to_be_bytes();
+        let (value, proof) = tree.get_with_proof(&key);
+        assert_eq!(value, Some(key.to_vec()));
+        assert!(matches!(proof, KeyProof::Exist(_)));
+        assert!(proof.verify(&root, &key, value.as_deref(), SCHEME));
+        assert!(!proof.verify(&root, &key, Some(b"other"), SCHEME));
+        assert!(!proof.verify(&root, &key, None, SCHEME));
+
+        for key in [5u32, 0xff, 100] {
+            let key = key.to_be_bytes();
+            let (value, proof) = tree.get_with_proof(&key);
+            assert_eq!(value, None);
+            assert!(matches!(proof, KeyProof::NonExist(_)));
+            assert!(proof.verify(&root, &key, None, SCHEME));
+            assert!(!proof.verify(&root, &key, Some(&key), SCHEME));
+        }
+        let (_, proof) = tree.get_with_proof(b"");
+        assert!(proof.verify(&root, b"", None, SCHEME));
+        assert!(!proof.verify(&[0u8; 32].into(), b"", None, SCHEME));
+
+        // a valid proof of another key don't prove anything about the queried one.
+        let (value, proof) = tree.get_with_proof(&6u32.to_be_bytes());
+        assert!(!proof.verify(&root, &key, value.as_deref(), SCHEME));
+        let (_, proof) = tree.get_with_proof(&0xffu32.to_be_bytes());
+        assert!(!proof.verify(&root, &key, None, SCHEME));
+        assert!(!proof.verify(&root, &5u32.to_be_bytes(), None, SCHEME));
+
+        let mut tree = IAVLTree::new();
+        let root = *tree.root_hash();
+        let (value, proof) = tree.get_with_proof(b"key");
+        assert!(value.is_none() && proof.verify(&root, b"key", None, SCHEME));
+    }
+
+    #[test]
+    fn test_multi_proof() {
+        let mut tree = IAVLTree::new();
+        for i in 0u32..1000 {
+            tree.set(i.to_be_bytes().to_vec(), i.to_be_bytes().to_vec());
+        }
+        let root = *tree.root_hash();
+
+        // neighboring keys share most of their paths, the missing ones are omitted
+        let mut keys = (500u32..510)
+            .map(|i| i.to_be_bytes().to_vec())
+            .collect::<Vec<_>>();
+        keys.push(5000u32.to_be_bytes().to_vec());
+        let proof = tree.get_multi_proof(&keys);
+        assert!(proof.verify(&root, SCHEME));
+        assert!(!proof.verify(&[0u8; 32].into(), SCHEME));
+        assert_eq!(
+            proof.leaves(),
+            keys[..10]
+                .iter()
+                .map(|key| (key.as_slice(), key.as_slice()))
+                .collect::<Vec<_>>()
+        );
+
+        fn count_hashes(node: &MultiProofNode) -> usize {
+            match node {
+                MultiProofNode::Hash(_) => 1,
+                MultiProofNode::Leaf { .. } => 0,
+                MultiProofNode::Inner { left, right, .. } => {
+                    count_hashes(left) + count_hashes(right)
+                }
+            }
+        }
+        let individual = keys[..10]
+            .iter()
+            .map(|key| tree.get_membership_proof(key).unwrap().path.len())
+            .sum::<usize>();
+        assert!(count_hashes(proof.root.as_ref().unwrap()) * 3 < individual);
+
+        // tampered leaf
+        let mut proof = proof;
+        if let Some(MultiProofNode::Inner { left, .. }) = &mut proof.root {
+            **left = MultiProofNode::Hash([0u8; 32].into());
+        }
+        assert!(!proof.verify(&root, SCHEME));
+
+        let mut tree = IAVLTree::new();
+        let root = *tree.root_hash();
+        let proof = tree.get_multi_proof(&keys);
+        assert!(proof.verify(&root, SCHEME) && proof.leaves().is_empty());
+    }
+
+    #[test]
+    fn test_range_proof() {
+        let mut tree = build_tree();
+        let root = *tree.root_hash();
+        let key = |i: u32| i.to_be_bytes().to_vec();
+
+        // 4, 6, 8, 10, 12
+        let bounds = key(3)..=key(12);
+        let proof = tree.get_range_proof(bounds.clone());
+        assert_eq!(proof.proofs.len(), 5);
+        assert_eq!(proof.left.as_ref().unwrap().key, key(2));
+        assert_eq!(proof.right.as_ref().unwrap().key, key(14));
+        assert!(proof.verify(&root, bounds.clone(), SCHEME));
+        // the bounds don't match the proof
+        assert!(!proof.verify(&root, key(3)..key(12), SCHEME));
+        assert!(!proof.verify(&root, key(2)..=key(12), SCHEME));
+
+        // a leaf is omitted
+        let mut omitted = proof.clone();
+        omitted.proofs.remove(2);
+        assert!(!omitted.verify(&root, bounds.clone(), SCHEME));
+        // a boundary neighbor is omitted
+        let mut omitted = proof.clone();
+        omitted.right = None;
+        assert!(!omitted.verify(&root, bounds, SCHEME));
+
+        // the edges of the tree, and an empty range
+        for bounds in [
+            (Bound::Unbounded, Bound::Excluded(key(4))),
+            (Bound::Included(key(15)), Bound::Unbounded),
+            (Bound::Unbounded, Bound::Unbounded),
+            (Bound::Included(key(5)), Bound::Excluded(key(6))),
+            (Bound::Included(key(100)), Bound::Unbounded),
+        ] {
+            let proof = tree.get_range_proof(bounds.clone());
+            assert_eq!(proof.proofs.len(), tree.range(bounds.clone()).count());
+            assert!(proof.verify(&root, bounds, SCHEME));
+        }
+    }
+
+    #[test]
+    fn test_non_membership_proof_edges() {
+        let mut tree = build_tree();
+        let root = *tree.root_hash();
+
+        // before the first leaf
+        let proof = tree.get_non_membership_proof(b"");
+        assert!(proof.left.is_none());
+        assert!(proof.verify(&root, b"", SCHEME));
+
+        // after the last leaf
+        let proof = tree.get_non_membership_proof(&100u32.to_be_bytes());
+        assert!(proof.right.is_none());
+        assert!(proof.verify(&root, &100u32.to_be_bytes(), SCHEME));
+
+        // existing key
+        let proof = tree.get_non_membership_proof(&4u32.to_be_bytes());
+        assert!(!proof.verify(&root, &4u32.to_be_bytes(), SCHEME));
+
+        // skipping a leaf in the gap
+        let mut proof = tree.get_non_membership_proof(&5u32.to_be_bytes());
+        proof.right = tree.get_membership_proof(&8u32.to_be_bytes());
+        assert!(!proof.verify(&root, &5u32.to_be_bytes(), SCHEME));
+
+        // empty tree
+        let mut tree = IAVLTree::new();
+        let root = *tree.root_hash();
+        let proof = tree.get_non_membership_proof(b"key");
+        assert!(proof.left.is_none() && proof.right.is_none());
+        assert!(proof.verify(&root, b"key", SCHEME));
+    }
+
+    #[test]
+    fn test_verify_multi_proof() {
+        let mut tree = IAVLTree::new();
+        for i in 0u32..100 {
+            tree.set(i.to_be_bytes().to_vec(), (i * 2).to_be_bytes().to_vec());
+        }
+        let root = *tree.root_hash();
+        let items =
+            [3u32, 40, 41, 99].map(|i| (i.to_be_bytes().to_vec(), (i * 2).to_be_bytes().to_vec()));
+        let keys = items.iter().map(|(key, _)| key.clone()).collect::<Vec<_>>();
+        let proof = tree.get_multi_proof(&keys);
+        let entries = items
+            .iter()
+            .map(|(key, value)| (key.as_slice(), value.as_slice()))
+            .collect::<Vec<_>>();
+        assert!(verify_multi_proof(&root, &entries, &proof, SCHEME));
+
+        // swapped values
+        let mut swapped = entries.clone();
+        (swapped[1].1, swapped[2].1) = (entries[2].1, entries[1].1);
+        assert!(!verify_multi_proof(&root, &swapped, &proof, SCHEME));
+
+        // out of order, or a subset of the proven leaves
+        let mut reordered = entries.clone();
+        reordered.swap(0, 1);
+        assert!(!verify_multi_proof(&root, &reordered, &proof, SCHEME));
+        assert!(!verify_multi_proof(&root, &entries[1..], &proof, SCHEME));
+
+        // the sibling of the subtree with the first key is omitted
+        let mut omitted = proof.clone();
+        if let Some(MultiProofNode::Inner { left, right, .. }) = omitted.root.take() {
+            assert!(matches!(*right, MultiProofNode::Inner { .. }));
+            omitted.root = Some(*left);
+        }
+        assert!(!verify_multi_proof(&root, &entries[..1], &omitted, SCHEME));
+        assert!(!verify_multi_proof(&root, &entries, &omitted, SCHEME));
+    }
+
+    #[test]
+    fn test_proof_versions() {
+        let mut tree = IAVLTree::new();
+        for i in 0u32..50 {
+            tree.set(i.to_be_bytes().to_vec(), b"v1".to_vec());
+        }
+        tree.save_version();
+        for i in (0u32..50).step_by(7) {
+            tree.set(i.to_be_bytes().to_vec(), b"v2".to_vec());
+        }
+        tree.save_version();
+        let root = *tree.root_hash();
+
+        for i in 0u32..50 {
+            let key = i.to_be_bytes();
+            let proof = tree.get_membership_proof(&key).unwrap();
+            assert_eq!(proof.calculate_root(), Some(root));
+
+            // the versions of the nodes along the path, from the root
+            let mut versions = Vec::new();
+            let mut node = &**tree.root().unwrap();
+            while !node.is_leaf() {
+                versions.push(node.version);
+                node = match key.as_slice() < node.key.as_slice() {
+                    true => node.left.as_ref().unwrap(),
+                    false => node.right.as_ref().unwrap(),
+                };
+            }
+            assert_eq!(proof.version, node.version);
+            assert_eq!(
+                proof
+                    .path
+                    .iter()
+                    .rev()
+                    .map(|inner| inner.version)
+                    .collect::<Vec<_>>(),
+                versions
+            );
+            assert_eq!(proof.max_version(), *versions.iter().max().unwrap());
+            if i % 7 == 0 {
+                assert_eq!((proof.version, proof.max_version()), (2, 2));
+            }
+
+            // the version is part of the hash
+            let mut tampered = proof.clone();
+            tampered.path[0].version += 1;
+            assert_ne!(tampered.calculate_root(), Some(root));
+        }
+        let proof = tree.get_membership_proof(&1u32.to_be_bytes()).unwrap();
+        assert_eq!(proof.version, 1);
+    }
+}
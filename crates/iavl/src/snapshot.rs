@@ -0,0 +1,214 @@
+use crypto_common::Output;
+use integer_encoding::{VarIntReader, VarIntWriter};
+use sha2::{Digest, Sha256};
+use std::io::{self, Read, Write};
+use std::ops::RangeBounds;
+use std::sync::OnceLock;
+
+use super::error::DbError;
+use super::iterator::TreeIterator;
+use super::node::{HashScheme, Node, NodeRef};
+
+// Snapshot is a read-only view of the tree at the time it's taken, it shares the nodes with the
+// tree, which copies them on write, so the later writes to the tree don't affect it, it can be
+// sent to other threads to serve queries.
+pub struct Snapshot<D: Digest = Sha256> {
+    root: Option<NodeRef<D>>,
+    // computed on first use, since the working tree may not be hashed yet.
+    hash: OnceLock<Output<D>>,
+    scheme: HashScheme,
+}
+
+impl<D: Digest> Snapshot<D> {
+    pub(crate) fn new(root: Option<NodeRef<D>>, scheme: HashScheme) -> Self {
+        Snapshot {
+            root,
+            hash: OnceLock::new(),
+            scheme,
+        }
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        self.root.as_ref()?.get_with_index(key).0
+    }
+
+    pub fn range<R>(&self, bounds: R) -> TreeIterator<'_, R, D>
+    where
+        R: RangeBounds<Vec<u8>>,
+    {
+        TreeIterator::new(self.root.as_deref(), bounds)
+    }
+
+    pub fn root_hash(&self) -> &Output<D> {
+        self.hash.get_or_init(|| match &self.root {
+            Some(root) => root.compute_hash(self.scheme),
+            None => D::digest(b""),
+        })
+    }
+}
+
+// A snapshot is encoded as:
+// - version, big endian u64
+// - root hash, length prefixed
+// - number of nodes, varint
+// - the nodes in post-order, each one as height, version, length prefixed key, and length
+//   prefixed value for leaf node.
+//
+// The shape of the tree and the versions of the nodes are part of the root hash, so they are
+// exported along with the leaves, the sizes and hashes are recomputed on import.
+
+// export_tree writes the snapshot of a saved version, the hashes must be computed beforehand.
+pub fn export_tree<D: Digest>(
+    version: u64,
+    root: Option<&NodeRef<D>>,
+    root_hash: &Output<D>,
+    w: &mut impl Write,
+) -> io::Result<()> {
+    w.write_all(&version.to_be_bytes())?;
+    write_bytes(w, root_hash)?;
+    match root {
+        Some(root) => {
+            w.write_varint(2 * root.size - 1)?;
+            export_node(root, w)
+        }
+        None => w.write_varint(0u64).map(|_| ()),
+    }
+}
+
+fn export_node<D: Digest>(node: &Node<D>, w: &mut impl Write) -> io::Result<()> {
+    if !node.is_leaf() {
+        export_node(node.left.as_ref().unwrap(), w)?;
+        export_node(node.right.as_ref().unwrap(), w)?;
+    }
+    w.write_all(&[node.height])?;
+    w.write_varint(node.version)?;
+    write_bytes(w, &node.key)?;
+    if node.is_leaf() {
+        write_bytes(w, &node.value)?;
+    }
+    Ok(())
+}
+
+// import_tree reads the snapshot and rebuilds the tree, returns the version, the root node and
+// the exported root hash, the caller should compare it with the recomputed one.
+#[allow(clippy::type_complexity)]
+pub fn import_tree<D: Digest>(
+    r: &mut impl Read,
+) -> Result<(u64, Option<NodeRef<D>>, Vec<u8>), DbError> {
+    let mut buf = [0u8; 8];
+    r.read_exact(&mut buf)?;
+    let version = u64::from_be_bytes(buf);
+    let root_hash = read_bytes(r)?;
+    let count: u64 = r.read_varint()?;
+
+    // the nodes pending to be attached to the parent, along with the smallest keys of them,
+    // the keys of the inner nodes are not covered by the hashes, so they are checked here.
+    let mut stack: Vec<(NodeRef<D>, Vec<u8>)> = Vec::new();
+    let mut last_key: Option<Vec<u8>> = None;
+    for _ in 0..count {
+        let mut height = [0u8; 1];
+        r.read_exact(&mut height)?;
+        let height = height[0];
+        let node_version: u64 = r.read_varint()?;
+        if node_version > version {
+            return Err(DbError::Decode(format!(
+                "node version {} is newer than the snapshot version {}",
+                node_version, version
+            )));
+        }
+        let key = read_bytes(r)?;
+
+        if height == 0 {
+            if last_key.as_ref().is_some_and(|last| *last >= key) {
+                return Err(DbError::Decode(
+                    "leaves are not sorted in snapshot".to_string(),
+                ));
+            }
+            last_key = Some(key.clone());
+            let node = Node::leaf(key.clone(), read_bytes(r)?, node_version);
+            stack.push((NodeRef::new(node), key));
+        } else {
+            if stack.len() < 2 {
+                return Err(DbError::Decode(
+                    "missing children of inner node".to_string(),
+                ));
+            }
+            let (right, right_min) = stack.pop().unwrap();
+            let (left, left_min) = stack.pop().unwrap();
+            if key != right_min {
+                return Err(DbError::Decode("invalid key of inner node".to_string()));
+            }
+            let node = Node::branch(key, left, right, node_version);
+            if node.height != height {
+                return Err(DbError::Decode(format!(
+                    "invalid height of inner node: {}, expect: {}",
+                    height, node.height
+                )));
+            }
+            stack.push((NodeRef::new(node), left_min));
+        }
+    }
+
+    let root = stack.pop().map(|(node, _)| node);
+    if !stack.is_empty() {
+        return Err(DbError::Decode("dangling nodes in snapshot".to_string()));
+    }
+    Ok((version, root, root_hash))
+}
+
+fn write_bytes(w: &mut impl Write, bytes: &[u8]) -> io::Result<()> {
+    w.write_varint(bytes.len())?;
+    w.write_all(bytes)
+}
+
+fn read_bytes(r: &mut impl Read) -> Result<Vec<u8>, DbError> {
+    let len: usize = r.read_varint()?;
+    let mut bytes = Vec::new();
+    r.take(len as u64).read_to_end(&mut bytes)?;
+    if bytes.len() != len {
+        return Err(DbError::Decode("unexpected end of snapshot".to_string()));
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{IAVLTree, KVStore};
+
+    #[test]
+    fn test_snapshot() {
+        let mut tree = IAVLTree::new();
+        for i in 0u32..100 {
+            tree.set(i.to_be_bytes().to_vec(), i.to_be_bytes().to_vec());
+        }
+        let hash = *tree.root_hash();
+        let snapshot = tree.snapshot();
+
+        for i in 0u32..100 {
+            tree.set(i.to_be_bytes().to_vec(), b"new".to_vec());
+        }
+        tree.remove(&0u32.to_be_bytes());
+        assert_ne!(*tree.root_hash(), hash);
+
+        let handle = std::thread::spawn(move || {
+            assert_eq!(*snapshot.root_hash(), hash);
+            assert_eq!(
+                snapshot.get(&0u32.to_be_bytes()),
+                Some(&0u32.to_be_bytes()[..])
+            );
+            assert!(snapshot
+                .range(..)
+                .map(|(k, v)| (k.to_vec(), v.to_vec()))
+                .eq((0u32..100).map(|i| (i.to_be_bytes().to_vec(), i.to_be_bytes().to_vec()))));
+        });
+        handle.join().unwrap();
+
+        // the hashes are computed lazily if the tree was not hashed
+        tree.set(b"key".to_vec(), b"value".to_vec());
+        let mut copy = tree.clone();
+        let snapshot = tree.snapshot();
+        tree.remove(b"key");
+        assert_ne!(snapshot.root_hash(), tree.root_hash());
+        assert_eq!(snapshot.root_hash(), copy.root_hash());
+    }
+}
@@ -0,0 +1,48 @@
+use std::cell::Cell;
+use std::ops::{Bound, RangeBounds};
+
+use super::types::{DynIterator, KVStore, KVStoreExt};
+use super::MemTree;
+
+// CountingStore counts the reads of the inner store, the point reads and the items pulled from
+// the ranges separately.
+#[derive(Default)]
+pub struct CountingStore {
+    pub inner: MemTree,
+    pub reads: Cell<usize>,
+    pub pulled: Cell<usize>,
+}
+
+impl KVStore for CountingStore {
+    fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        self.reads.set(self.reads.get() + 1);
+        self.inner.get(key)
+    }
+
+    fn set(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.inner.set(key, value)
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        self.inner.remove(key)
+    }
+
+    fn range_dyn(&self, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>) -> DynIterator<'_> {
+        Box::new(
+            self.inner
+                .range_dyn(start, end)
+                .inspect(|_| self.pulled.set(self.pulled.get() + 1)),
+        )
+    }
+}
+
+impl KVStoreExt for CountingStore {
+    fn range<R>(&self, bounds: R) -> impl DoubleEndedIterator<Item = (&[u8], &[u8])>
+    where
+        R: RangeBounds<Vec<u8>> + Clone,
+    {
+        self.inner
+            .range(bounds)
+            .inspect(|_| self.pulled.set(self.pulled.get() + 1))
+    }
+}
@@ -1,284 +1,2391 @@
 use crypto_common::Output;
 use sha2::{Digest, Sha256};
-use std::cmp::Ordering;
-use std::sync::LazyLock;
+#[cfg(feature = "metrics")]
+use std::cell::Cell;
+use std::cmp::{self, Ordering};
+use std::collections::BTreeMap;
+use std::iter::Peekable;
+use std::mem;
+use std::ops::{Bound, RangeBounds, RangeFull};
+use std::sync::Arc;
 
-use super::iterator::TreeIterator;
-use super::node::Node;
-use super::types::KVStore;
+use super::db::Changeset;
+use super::diff::DiffIter;
+use super::iterator::{NodeIter, TreeIterator, VersionedIterator};
+use super::node::{hash_inner, hash_leaf, HashScheme, Node, NodeRef};
+use super::proof::{
+    existence_proof, key_proof, multi_proof, ExistenceProof, KeyProof, MultiProof,
+    NonExistenceProof, RangeProof,
+};
+use super::snapshot::Snapshot;
+use super::types::{ChangeItem, DynIterator, KVStore, KVStoreExt};
 
-static EMPTY_HASH: LazyLock<Output<Sha256>> = LazyLock::new(|| Sha256::digest(b""));
-
-#[derive(Default)]
-pub struct IAVLTree {
-    root: Option<Box<Node>>,
+// IAVLTree is generic over the digest used to compute the merkle hashes, defaults to sha256.
+pub struct IAVLTree<D: Digest = Sha256> {
+    root: Option<NodeRef<D>>,
     version: u64,
+    // the roots of the saved versions, unchanged subtrees are shared between them.
+    versions: BTreeMap<u64, Option<NodeRef<D>>>,
+    // the root hash of the empty tree
+    empty_hash: Output<D>,
+    lifecycle: Lifecycle,
+    // the limits of the key and value lengths checked on write, `None` means unlimited.
+    max_key_len: Option<usize>,
+    max_value_len: Option<usize>,
+    // the limit of the tree height checked on insert, `None` means unlimited.
+    max_height: Option<u8>,
+    // the domain separation of the leaf and inner node hashes.
+    scheme: HashScheme,
+    // the number of rotations done by the writes, for tuning.
+    #[cfg(feature = "metrics")]
+    rotations: u64,
+}
+
+// Lifecycle tracks whether the next version is open for writes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Lifecycle {
+    // the next version is opened implicitly by the writes, until `begin_version` is called.
+    Implicit,
+    Open,
+    Closed,
+}
+
+// clone is cheap, the nodes are shared with the copy and copied on write, so mutating one of
+// them never affects the other, and the cached hashes of the unchanged subtrees are reused.
+impl<D: Digest> Clone for IAVLTree<D> {
+    fn clone(&self) -> Self {
+        IAVLTree {
+            root: self.root.clone(),
+            version: self.version,
+            versions: self.versions.clone(),
+            empty_hash: self.empty_hash.clone(),
+            lifecycle: self.lifecycle,
+            max_key_len: self.max_key_len,
+            max_value_len: self.max_value_len,
+            max_height: self.max_height,
+            scheme: self.scheme,
+            #[cfg(feature = "metrics")]
+            rotations: self.rotations,
+        }
+    }
+}
+
+impl Default for IAVLTree {
+    fn default() -> Self {
+        Self::with_digest()
+    }
 }
 
 impl IAVLTree {
     pub fn new() -> Self {
         Self::default()
     }
+}
+
+impl<D: Digest> IAVLTree<D> {
+    // with_digest creates an empty tree using the digest `D`.
+    pub fn with_digest() -> Self {
+        IAVLTree {
+            root: None,
+            version: 0,
+            versions: BTreeMap::new(),
+            empty_hash: D::digest(b""),
+            lifecycle: Lifecycle::Implicit,
+            max_key_len: None,
+            max_value_len: None,
+            max_height: None,
+            scheme: HashScheme::default(),
+            #[cfg(feature = "metrics")]
+            rotations: 0,
+        }
+    }
+
+    // with_hash_scheme creates an empty tree hashing the nodes with the scheme, the root hashes
+    // differ from the default scheme, the verifiers must expect the same scheme.
+    pub fn with_hash_scheme(scheme: HashScheme) -> Self {
+        IAVLTree {
+            scheme,
+            ..Self::with_digest()
+        }
+    }
+
+    // with_initial_version creates an empty tree whose first saved version is `initial_version`,
+    // like the `initial_version` of the Cosmos IAVL, 0 is the same as 1.
+    pub fn with_initial_version(initial_version: u64) -> Self {
+        IAVLTree {
+            version: initial_version.saturating_sub(1),
+            ..Self::with_digest()
+        }
+    }
+
+    // replay builds a tree by applying the changesets in memory, like the WAL replay of `IAVLDB`,
+    // the versions of the changesets must be consecutive from 1, and the root hashes must match
+    // if set.
+    pub fn replay(entries: impl IntoIterator<Item = Changeset>) -> Result<Self, String> {
+        let mut tree = Self::with_digest();
+        for entry in entries {
+            if entry.version != tree.version + 1 {
+                return Err(format!(
+                    "expect changeset of version {}, got {}",
+                    tree.version + 1,
+                    entry.version
+                ));
+            }
+            tree.write_batch(entry.changes);
+            let root_hash = tree.save_version();
+            if entry
+                .root_hash
+                .is_some_and(|expect| expect != root_hash.as_slice())
+            {
+                return Err(format!("root hash mismatch at version {}", entry.version));
+            }
+        }
+        Ok(tree)
+    }
+
+    // load creates a tree from the root of a persisted version.
+    pub(crate) fn load(version: u64, root: Option<NodeRef<D>>) -> Self {
+        IAVLTree {
+            root: root.clone(),
+            version,
+            versions: BTreeMap::from([(version, root)]),
+            empty_hash: D::digest(b""),
+            lifecycle: Lifecycle::Implicit,
+            max_key_len: None,
+            max_value_len: None,
+            max_height: None,
+            scheme: HashScheme::default(),
+            #[cfg(feature = "metrics")]
+            rotations: 0,
+        }
+    }
+
+    // root returns the root node of the working tree.
+    pub(crate) fn root(&self) -> Option<&NodeRef<D>> {
+        self.root.as_ref()
+    }
+
+    // from_sorted builds the tree from key-value pairs sorted by key without duplications,
+    // the result is identical to inserting them one by one and saving as `version`, panics if
+    // `version` is 0, the first saved version is 1.
+    pub fn from_sorted<I>(iter: I, version: u64) -> Self
+    where
+        I: IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+    {
+        assert!(version > 0, "version 0 can't be saved");
+        let mut tree = Self::with_digest();
+        tree.version = version - 1;
+        tree.write_batch(iter.into_iter().map(|(key, value)| (key, Some(value))));
+        tree.save_version();
+        tree
+    }
+
+    pub fn root_hash(&mut self) -> &Output<D> {
+        match self.root.as_mut() {
+            Some(root) => {
+                if root.cached_hash().is_none() {
+                    NodeRef::make_mut(root).update_hash(self.scheme);
+                }
+                root.cached_hash().unwrap()
+            }
+            None => &self.empty_hash,
+        }
+    }
+
+    // set_limits sets the limits of the key and value lengths, `None` means unlimited,
+    // the existing entries are not checked.
+    pub fn set_limits(&mut self, max_key_len: Option<usize>, max_value_len: Option<usize>) {
+        self.max_key_len = max_key_len;
+        self.max_value_len = max_value_len;
+    }
+
+    // set_max_height sets the limit of the tree height, `None` means unlimited, the inserts which
+    // would make the tree higher fail, the existing tree is not checked.
+    pub fn set_max_height(&mut self, max_height: Option<u8>) {
+        self.max_height = max_height;
+    }
+
+    fn check_limits(&self, key: &[u8], value: &[u8]) -> Result<(), String> {
+        if let Some(max) = self.max_key_len.filter(|max| key.len() > *max) {
+            return Err(format!("key too long: {} > {}", key.len(), max));
+        }
+        if let Some(max) = self.max_value_len.filter(|max| value.len() > *max) {
+            return Err(format!("value too long: {} > {}", value.len(), max));
+        }
+        Ok(())
+    }
+
+    // try_set inserts or updates the key, returns an error without changing the tree if the key
+    // or value exceeds the limits, or the tree would exceed the max height, `set` panics in that
+    // case.
+    pub fn try_set(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), String> {
+        self.check_limits(&key, &value)?;
+        let version = self.working_version();
+        // an insert grows the tree by one level at most, so the old root is only kept to restore
+        // when the tree is at the max height already.
+        let max_height = self.max_height;
+        let old_root = self
+            .root
+            .as_ref()
+            .filter(|root| max_height.is_some_and(|max| root.height >= max))
+            .cloned();
+        #[cfg(feature = "metrics")]
+        let start = ROTATIONS.get();
+        if let Some(root) = self.root.take() {
+            let (node, _) = insert_recursive(root, key, value, version);
+            self.root = Some(node);
+        } else {
+            self.root = Some(NodeRef::new(Node::leaf(key, value, version)));
+        }
+        #[cfg(feature = "metrics")]
+        {
+            self.rotations += ROTATIONS.get() - start;
+        }
+        if let (Some(old_root), Some(max)) = (old_root, max_height) {
+            let height = self.root.as_ref().unwrap().height;
+            if height > max {
+                self.root = Some(old_root);
+                return Err(format!("tree too high: {} > {}", height, max));
+            }
+        }
+        Ok(())
+    }
+
+    // remove_range removes the keys within the bounds, returns the number of keys removed. the
+    // keys are removed one by one, so the root hash is the same as removing them individually,
+    // which splitting and joining the tree can't guarantee, the shape of the tree would differ.
+    pub fn remove_range<R>(&mut self, bounds: R) -> u64
+    where
+        R: RangeBounds<Vec<u8>>,
+    {
+        let keys = TreeIterator::new(self.root.as_deref(), bounds)
+            .map(|(key, _)| key.to_vec())
+            .collect::<Vec<_>>();
+        for key in &keys {
+            self.remove(key);
+        }
+        keys.len() as u64
+    }
+
+    // split partitions the working tree into the keys `< key` and `>= key`, the nodes along the
+    // path to the key are rebuilt at the working version, the others are shared, O(log n). the
+    // halves keep the settings and the version of the tree, but not the saved versions.
+    pub fn split(self, key: &[u8]) -> (Self, Self) {
+        let version = self.working_version();
+        let (left, right) = match &self.root {
+            Some(root) => split_node(root.clone(), key, version),
+            None => (None, None),
+        };
+        (self.with_root(left), self.with_root(right))
+    }
+
+    // join concatenates two trees, all the keys of `left` must precede the ones of `right`, the
+    // nodes along the spine of the higher tree are rebuilt at the working version, O(log n). the
+    // result keeps the settings of `left` and the later version of the two.
+    pub fn join(left: Self, right: Self) -> Self {
+        if let (Some(l), Some(r)) = (&left.root, &right.root) {
+            assert!(
+                max_key(l) < min_key(r),
+                "the keys of the left tree must precede the right tree"
+            );
+        }
+        let mut tree = left.with_root(None);
+        tree.version = cmp::max(left.version, right.version);
+        tree.root = join_nodes(left.root, right.root, tree.working_version());
+        tree
+    }
+
+    // with_root creates a tree of the same settings and version with the root, the saved versions
+    // are not kept.
+    fn with_root(&self, root: Option<NodeRef<D>>) -> Self {
+        IAVLTree {
+            root,
+            version: self.version,
+            versions: BTreeMap::new(),
+            empty_hash: self.empty_hash.clone(),
+            lifecycle: self.lifecycle,
+            max_key_len: self.max_key_len,
+            max_value_len: self.max_value_len,
+            max_height: self.max_height,
+            scheme: self.scheme,
+            #[cfg(feature = "metrics")]
+            rotations: 0,
+        }
+    }
+
+    // rotation_count returns the number of rotations done by the writes to the tree.
+    #[cfg(feature = "metrics")]
+    pub fn rotation_count(&self) -> u64 {
+        self.rotations
+    }
+
+    // begin_version opens the next version for writes and returns it, once called, the writes and
+    // `save_version` are only allowed between `begin_version` and `save_version`, so each version
+    // is started explicitly.
+    pub fn begin_version(&mut self) -> u64 {
+        assert!(
+            self.lifecycle != Lifecycle::Open,
+            "version {} is already open",
+            self.version + 1
+        );
+        self.lifecycle = Lifecycle::Open;
+        self.version + 1
+    }
+
+    // working_version returns the version of the nodes written, panics if no version is open.
+    fn working_version(&self) -> u64 {
+        assert!(
+            self.lifecycle != Lifecycle::Closed,
+            "no open version, call begin_version first"
+        );
+        self.version + 1
+    }
 
-    pub fn root_hash(&mut self) -> &Output<Sha256> {
-        self.root.as_mut().map_or(&EMPTY_HASH, |n| n.update_hash())
+    // root_hash_immutable computes the root hash without caching the hashes, so it only needs a
+    // shared borrow, the nodes changed since the last `root_hash` are hashed again on every call,
+    // prefer `root_hash` unless the tree can't be borrowed mutably.
+    pub fn root_hash_immutable(&self) -> Output<D> {
+        match &self.root {
+            Some(root) => root.compute_hash(self.scheme),
+            None => self.empty_hash.clone(),
+        }
+    }
+
+    // snapshot returns a read-only view of the working tree, unaffected by the later writes.
+    pub fn snapshot(&self) -> Snapshot<D> {
+        Snapshot::new(self.root.clone(), self.scheme)
     }
 
-    pub fn save_version(&mut self) -> &Output<Sha256> {
-        self.version += 1;
+    // save_version snapshots the current root as a new version.
+    pub fn save_version(&mut self) -> &Output<D> {
+        self.version = self.working_version();
+        if self.lifecycle == Lifecycle::Open {
+            self.lifecycle = Lifecycle::Closed;
+        }
+        self.root_hash();
+        self.versions.insert(self.version, self.root.clone());
         self.root_hash()
     }
 
+    // commit saves the version like `save_version`, and returns the version along with the root hash.
+    pub fn commit(&mut self) -> (u64, Output<D>) {
+        let hash = self.save_version().clone();
+        (self.version, hash)
+    }
+
+    // version returns the last saved version, 0 if nothing is saved yet.
     pub fn version(&self) -> u64 {
         self.version
     }
 
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        self.get(key).is_some()
+    }
+
+    // update_in_place updates the value of an existing key with `f`, returns false without
+    // changing the tree if the key don't exist. `f` runs on a copy of the value, the path is only
+    // copied and hashed again if the value is changed, it's an error if the new value exceeds
+    // the limit, the tree is unchanged then.
+    pub fn update_in_place(
+        &mut self,
+        key: &[u8],
+        f: impl FnOnce(&mut Vec<u8>),
+    ) -> Result<bool, String> {
+        let version = self.working_version();
+        let Some(old) = self.get(key) else {
+            return Ok(false);
+        };
+        let mut value = old.to_vec();
+        f(&mut value);
+        if value == old {
+            return Ok(true);
+        }
+        self.check_limits(key, &value)?;
+        let mut node = NodeRef::make_mut(self.root.as_mut().unwrap());
+        loop {
+            node.mutate(version);
+            if node.is_leaf() {
+                node.value = value;
+                return Ok(true);
+            }
+            let child = if key < node.key.as_slice() {
+                node.left.as_mut()
+            } else {
+                node.right.as_mut()
+            };
+            node = NodeRef::make_mut(child.unwrap());
+        }
+    }
+
+    // write_batch_with_old applies the operations one by one, and returns the value of each key
+    // right before the operation, in the batch order, they can be used to build the inverse changeset.
+    pub fn write_batch_with_old(
+        &mut self,
+        batch: impl IntoIterator<Item = ChangeItem>,
+    ) -> Vec<ChangeItem> {
+        batch
+            .into_iter()
+            .map(|(key, value)| {
+                let old = self.get(&key).map(<[u8]>::to_vec);
+                match value {
+                    Some(value) => self.set(key.clone(), value),
+                    None => self.remove(&key),
+                }
+                (key, old)
+            })
+            .collect()
+    }
+
+    // clear removes all the keys from the working tree, the saved versions are kept.
+    pub fn clear(&mut self) {
+        self.working_version();
+        self.root = None;
+    }
+
+    // len returns the number of leaves in the working tree.
+    pub fn len(&self) -> u64 {
+        self.root.as_ref().map_or(0, |n| n.size)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.root.is_none()
+    }
+
+    // height returns the height of the working tree, 0 for a single leaf, and for an empty tree
+    // too, use `is_empty` to tell them apart.
+    pub fn height(&self) -> u8 {
+        self.root.as_ref().map_or(0, |n| n.height)
+    }
+
     pub fn get_by_index(&self, index: u64) -> Option<(&[u8], &[u8])> {
         self.root.as_ref()?.get_by_index(index)
     }
 
+    // get_with_index returns the value of the key, and the number of keys less than it,
+    // which is the index of the key if it exists, or the index it'd be inserted at.
     pub fn get_with_index(&self, key: &[u8]) -> (Option<&[u8]>, u64) {
         match self.root.as_ref() {
             Some(root) => root.get_with_index(key),
             None => (None, 0),
         }
     }
+
+    // rank returns the number of keys strictly less than the key, the key don't need to exist.
+    pub fn rank(&self, key: &[u8]) -> u64 {
+        self.get_with_index(key).1
+    }
+
+    // select returns the key-value pair at the index in the sorted order, the inverse of `rank`.
+    pub fn select(&self, index: u64) -> Option<(&[u8], &[u8])> {
+        self.get_by_index(index)
+    }
+
+    // count_range returns the number of keys within the bounds in O(log n), without iterating them.
+    pub fn count_range<R>(&self, bounds: R) -> u64
+    where
+        R: RangeBounds<Vec<u8>>,
+    {
+        let Some(root) = self.root.as_ref() else {
+            return 0;
+        };
+        // the number of keys less than the key, or equal to it if `inclusive`.
+        let rank = |key: &[u8], inclusive: bool| {
+            let (value, index) = root.get_with_index(key);
+            index + (inclusive && value.is_some()) as u64
+        };
+        let start = match bounds.start_bound() {
+            Bound::Included(key) => rank(key, false),
+            Bound::Excluded(key) => rank(key, true),
+            Bound::Unbounded => 0,
+        };
+        let end = match bounds.end_bound() {
+            Bound::Included(key) => rank(key, true),
+            Bound::Excluded(key) => rank(key, false),
+            Bound::Unbounded => root.size,
+        };
+        end.saturating_sub(start)
+    }
+
+    // saved_root returns the root node of a saved version.
+    pub(crate) fn saved_root(&self, version: u64) -> Result<Option<&NodeRef<D>>, String> {
+        self.versions
+            .get(&version)
+            .map(Option::as_ref)
+            .ok_or_else(|| format!("version {} is not saved", version))
+    }
+
+    // get_versioned returns the value of the key at a saved version.
+    pub fn get_versioned(&self, version: u64, key: &[u8]) -> Option<&[u8]> {
+        self.versions.get(&version)?.as_ref()?.get_with_index(key).0
+    }
+
+    // diff returns the changes from a saved version to another one, in the order of the keys,
+    // the subtrees shared by both versions are skipped.
+    pub fn diff(&self, from: u64, to: u64) -> Result<DiffIter<'_, D>, String> {
+        let from = self.saved_root(from)?.map(|root| &**root);
+        let to = self.saved_root(to)?.map(|root| &**root);
+        Ok(DiffIter::new(from, to))
+    }
+
+    // heap_size estimates the memory used by the working tree, the nodes only referenced by the
+    // old versions are not counted.
+    pub fn heap_size(&self) -> usize {
+        mem::size_of::<Self>() + self.root.as_ref().map_or(0, NodeRef::heap_size)
+    }
+
+    // check_invariants verifies the structure of the working tree, returns the first violation:
+    // the heights and sizes of the inner nodes, the AVL balance, the inner keys being the smallest
+    // keys of the right subtrees, the leaves being sorted, and the cached hashes being correct.
+    pub fn check_invariants(&self) -> Result<(), String> {
+        if let Some(root) = &self.root {
+            check_node(root, self.scheme, &mut None)?;
+        }
+        Ok(())
+    }
+
+    // debug_string renders the working tree for debugging, one node per line indented by the
+    // depth, the left child first, the long values are truncated.
+    pub fn debug_string(&self) -> String {
+        let mut out = String::new();
+        match &self.root {
+            Some(root) => debug_node(root, 0, &mut out),
+            None => out.push_str("<empty>\n"),
+        }
+        out
+    }
+
+    // prune_to discards the saved versions below `min_version`, the nodes not reachable from the
+    // retained versions are dropped with them, it's an error to prune the latest version.
+    pub fn prune_to(&mut self, min_version: u64) -> Result<(), String> {
+        if min_version > self.version {
+            return Err(format!(
+                "can't prune the latest version {}, min version: {}",
+                self.version, min_version
+            ));
+        }
+        self.versions = self.versions.split_off(&min_version);
+        Ok(())
+    }
+
+    // range iterates the key-value pairs within the bounds in order.
+    pub fn range<R>(&self, bounds: R) -> TreeIterator<'_, R, D>
+    where
+        R: RangeBounds<Vec<u8>>,
+    {
+        TreeIterator::new(self.root.as_deref(), bounds)
+    }
+
+    // node_iter visits all the nodes of the working tree including the inner ones in pre-order,
+    // for the tools inspecting the tree shape.
+    pub fn node_iter(&self) -> NodeIter<'_, D> {
+        NodeIter::new(self.root.as_deref())
+    }
+
+    // range_with_version is like `range`, and also yields the version each leaf is written at.
+    pub fn range_with_version<R>(&self, bounds: R) -> VersionedIterator<'_, R, D>
+    where
+        R: RangeBounds<Vec<u8>>,
+    {
+        VersionedIterator(self.range(bounds))
+    }
+
+    // get_membership_proof returns the existence proof for the key, `None` if the key don't exist.
+    pub fn get_membership_proof(&mut self, key: &[u8]) -> Option<ExistenceProof<D>> {
+        self.root_hash();
+        let root = self.root.as_ref()?;
+        let (value, index) = root.get_with_index(key);
+        value?;
+        existence_proof(root, index, self.scheme)
+    }
+
+    // get_with_proof returns the value of the key, along with the proof of its existence or
+    // non-existence, in a single descent if the key exists.
+    pub fn get_with_proof(&mut self, key: &[u8]) -> (Option<Vec<u8>>, KeyProof<D>) {
+        self.root_hash();
+        let Some(root) = self.root.as_ref() else {
+            let proof = NonExistenceProof {
+                key: key.to_vec(),
+                left: None,
+                right: None,
+            };
+            return (None, KeyProof::NonExist(proof));
+        };
+        let proof = key_proof(root, key, self.scheme);
+        let value = match &proof {
+            KeyProof::Exist(proof) => Some(proof.value.clone()),
+            KeyProof::NonExist(_) => None,
+        };
+        (value, proof)
+    }
+
+    // get_multi_proof returns the existence proof of all the keys in one traversal, the siblings
+    // shared by the paths are included once, the keys not in the tree are omitted.
+    pub fn get_multi_proof(&mut self, keys: &[Vec<u8>]) -> MultiProof<D> {
+        self.root_hash();
+        let mut keys = keys.iter().map(Vec::as_slice).collect::<Vec<_>>();
+        keys.sort();
+        keys.dedup();
+        MultiProof {
+            root: self.root.as_ref().map(|root| multi_proof(root, &keys)),
+            scheme: self.scheme,
+        }
+    }
+
+    // get_range_proof returns the existence proofs of the leaves within the bounds, and of the
+    // immediate neighbors out of them.
+    pub fn get_range_proof<R>(&mut self, bounds: R) -> RangeProof<D>
+    where
+        R: RangeBounds<Vec<u8>>,
+    {
+        self.root_hash();
+        let mut proof = RangeProof {
+            left: None,
+            proofs: Vec::new(),
+            right: None,
+        };
+        let Some(root) = self.root.as_ref() else {
+            return proof;
+        };
+        // the index of the first leaf within the bounds
+        let first = match bounds.start_bound() {
+            Bound::Included(key) => root.get_with_index(key).1,
+            Bound::Excluded(key) => {
+                let (value, index) = root.get_with_index(key);
+                index + value.is_some() as u64
+            }
+            Bound::Unbounded => 0,
+        };
+        let count = TreeIterator::new(Some(&**root), bounds).count() as u64;
+        if first > 0 {
+            proof.left = existence_proof(root, first - 1, self.scheme);
+        }
+        proof.proofs = (first..first + count)
+            .filter_map(|index| existence_proof(root, index, self.scheme))
+            .collect();
+        proof.right = existence_proof(root, first + count, self.scheme);
+        proof
+    }
+
+    // get_non_membership_proof returns the existence proofs of the immediate neighbors of the key,
+    // the proof won't verify if the key exists in the tree.
+    pub fn get_non_membership_proof(&mut self, key: &[u8]) -> NonExistenceProof<D> {
+        self.root_hash();
+        let mut proof = NonExistenceProof {
+            key: key.to_vec(),
+            left: None,
+            right: None,
+        };
+        if let Some(root) = self.root.as_ref() {
+            let (_, index) = root.get_with_index(key);
+            if index > 0 {
+                proof.left = existence_proof(root, index - 1, self.scheme);
+            }
+            proof.right = existence_proof(root, index, self.scheme);
+        }
+        proof
+    }
 }
 
-impl KVStore for IAVLTree {
+impl<D: Digest> KVStore for IAVLTree<D> {
     fn get(&self, key: &[u8]) -> Option<&[u8]> {
         self.root.as_ref()?.get_with_index(key).0
     }
 
-    fn set(&mut self, key: Vec<u8>, value: Vec<u8>) {
-        if let Some(root) = self.root.take() {
-            let (node, _) = insert_recursive(root, key, value, self.version + 1);
-            self.root = Some(node);
-        } else {
-            self.root = Some(Box::new(Node::leaf(key, value, self.version + 1)));
+    fn get_sorted(&self, keys: &[&[u8]]) -> Vec<Option<&[u8]>> {
+        debug_assert!(keys.is_sorted(), "the keys must be sorted");
+        let mut values = Vec::with_capacity(keys.len());
+        match &self.root {
+            Some(root) => get_sorted(root, keys, &mut values),
+            None => values.resize(keys.len(), None),
         }
+        values
+    }
+
+    fn set(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.try_set(key, value)
+            .unwrap_or_else(|err| panic!("failed to set: {}", err));
     }
 
     fn remove(&mut self, key: &[u8]) {
+        self.remove_taking(key);
+    }
+
+    // set_if_absent descends once, the nodes on the path are only copied if the key is inserted,
+    // it panics like `set` if the limits are exceeded.
+    fn set_if_absent(&mut self, key: Vec<u8>, value: Vec<u8>) -> bool {
+        self.check_limits(&key, &value)
+            .unwrap_or_else(|err| panic!("failed to set: {}", err));
+        let version = self.working_version();
+        let Some(root) = &self.root else {
+            self.root = Some(NodeRef::new(Node::leaf(key, value, version)));
+            return true;
+        };
+        #[cfg(feature = "metrics")]
+        let start = ROTATIONS.get();
+        let Some(root) = insert_absent(root, key, value, version) else {
+            return false;
+        };
+        #[cfg(feature = "metrics")]
+        {
+            self.rotations += ROTATIONS.get() - start;
+        }
+        if let Some(max) = self.max_height.filter(|max| root.height > *max) {
+            panic!("failed to set: tree too high: {} > {}", root.height, max);
+        }
+        self.root = Some(root);
+        true
+    }
+
+    // remove_taking reads the value in the existence check done before removing anyway.
+    fn remove_taking(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        let version = self.working_version();
+        let value = self.get(key)?.to_vec();
+        #[cfg(feature = "metrics")]
+        let start = ROTATIONS.get();
         if let Some(root) = self.root.take() {
-            let (_, root, _) = remove_recursive(root, key, self.version + 1);
+            let (_, root, _) = remove_recursive(root, key, version);
             self.root = root;
         }
+        #[cfg(feature = "metrics")]
+        {
+            self.rotations += ROTATIONS.get() - start;
+        }
+        Some(value)
+    }
+
+    fn range_dyn(&self, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>) -> DynIterator<'_> {
+        Box::new(IAVLTree::range(self, (start, end)).map(|(k, v)| (k.to_vec(), v.to_vec())))
+    }
+
+    // write_batch builds the whole tree in one pass if the tree is empty and the batch only
+    // inserts sorted keys, otherwise the operations are applied one by one, because the shape of
+    // the tree, thus the root hash, depends on the order of the operations.
+    fn write_batch(&mut self, batch: impl IntoIterator<Item = ChangeItem>) {
+        let mut batch = batch.into_iter().collect::<Vec<_>>();
+        let sorted_inserts = batch.windows(2).all(|w| w[0].0 < w[1].0)
+            && batch.iter().all(|(key, value)| {
+                value
+                    .as_ref()
+                    .is_some_and(|value| self.check_limits(key, value).is_ok())
+            });
+        // the max height is checked by the inserts one by one.
+        if self.root.is_none() && !batch.is_empty() && sorted_inserts && self.max_height.is_none() {
+            let version = self.working_version();
+            let size = batch.len();
+            let mut leaves = batch
+                .drain(..)
+                .map(|(key, value)| NodeRef::new(Node::leaf(key, value.unwrap(), version)))
+                .peekable();
+            self.root = Some(build_sorted(&mut leaves, size, version));
+            return;
+        }
+
+        for (key, value) in batch {
+            match value {
+                Some(value) => self.set(key, value),
+                None => self.remove(&key),
+            }
+        }
+    }
+}
+
+impl<'a, D: Digest> IntoIterator for &'a IAVLTree<D> {
+    type Item = (&'a [u8], &'a [u8]);
+    type IntoIter = TreeIterator<'a, RangeFull, D>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.range(..)
     }
+}
 
+impl<D: Digest> KVStoreExt for IAVLTree<D> {
     fn range<R>(&self, bounds: R) -> impl DoubleEndedIterator<Item = (&[u8], &[u8])>
     where
-        R: std::ops::RangeBounds<Vec<u8>>,
+        R: RangeBounds<Vec<u8>>,
     {
-        TreeIterator::new(self.root.as_deref(), bounds)
+        IAVLTree::range(self, bounds)
     }
 }
 
-// it returns if it's an update or insertion, if update, the tree height and balance is not changed.
-fn insert_recursive(
-    mut node: Box<Node>,
-    key: Vec<u8>,
-    value: Vec<u8>,
+fn debug_node<D: Digest>(node: &Node<D>, depth: usize, out: &mut String) {
+    const MAX_VALUE_LEN: usize = 16;
+
+    let indent = "  ".repeat(depth);
+    if node.is_leaf() {
+        let value = &node.value[..node.value.len().min(MAX_VALUE_LEN)];
+        let ellipsis = if node.value.len() > MAX_VALUE_LEN {
+            "..."
+        } else {
+            ""
+        };
+        out.push_str(&format!(
+            "{}{} height=0 size=1 version={} value={}{}\n",
+            indent,
+            node.key.escape_ascii(),
+            node.version,
+            value.escape_ascii(),
+            ellipsis
+        ));
+    } else {
+        out.push_str(&format!(
+            "{}{} height={} size={} version={}\n",
+            indent,
+            node.key.escape_ascii(),
+            node.height,
+            node.size,
+            node.version
+        ));
+        debug_node(node.left.as_ref().unwrap(), depth + 1, out);
+        debug_node(node.right.as_ref().unwrap(), depth + 1, out);
+    }
+}
+
+// check_node checks the invariants of the subtree and returns the smallest key in it,
+// `last_key` is the largest leaf key visited so far.
+fn check_node<'a, D: Digest>(
+    node: &'a Node<D>,
+    scheme: HashScheme,
+    last_key: &mut Option<&'a [u8]>,
+) -> Result<&'a [u8], String> {
+    if node.is_leaf() {
+        if node.size != 1 {
+            return Err(format!("leaf {:x?} has size {}", node.key, node.size));
+        }
+        if last_key.is_some_and(|last| last >= node.key.as_slice()) {
+            return Err(format!("leaf {:x?} is out of order", node.key));
+        }
+        *last_key = Some(&node.key);
+        if node.hash.as_ref().is_some_and(|hash| {
+            *hash != hash_leaf::<D>(scheme, node.version, &node.key, &node.value)
+        }) {
+            return Err(format!("leaf {:x?} has an invalid hash", node.key));
+        }
+        return Ok(&node.key);
+    }
+
+    let (Some(left), Some(right)) = (&node.left, &node.right) else {
+        return Err(format!("inner node {:x?} misses children", node.key));
+    };
+    let min_key = check_node(left, scheme, last_key)?;
+    let right_min_key = check_node(right, scheme, last_key)?;
+    if node.key != right_min_key {
+        return Err(format!(
+            "inner node {:x?} don't match the smallest key of the right subtree {:x?}",
+            node.key, right_min_key
+        ));
+    }
+    if node.height != cmp::max(left.height, right.height) + 1 {
+        return Err(format!(
+            "inner node {:x?} has height {}, expect {}",
+            node.key,
+            node.height,
+            cmp::max(left.height, right.height) + 1
+        ));
+    }
+    if node.size != left.size + right.size {
+        return Err(format!(
+            "inner node {:x?} has size {}, expect {}",
+            node.key,
+            node.size,
+            left.size + right.size
+        ));
+    }
+    if node.balance_factor().abs() > 1 {
+        return Err(format!(
+            "inner node {:x?} is unbalanced: {}",
+            node.key,
+            node.balance_factor()
+        ));
+    }
+    if let Some(hash) = &node.hash {
+        let (Some(left_hash), Some(right_hash)) = (left.cached_hash(), right.cached_hash()) else {
+            return Err(format!(
+                "inner node {:x?} is hashed before the children",
+                node.key
+            ));
+        };
+        if *hash
+            != hash_inner::<D>(
+                scheme,
+                node.height,
+                node.size,
+                node.version,
+                left_hash,
+                right_hash,
+            )
+        {
+            return Err(format!("inner node {:x?} has an invalid hash", node.key));
+        }
+    }
+    Ok(min_key)
+}
+
+// build_sorted builds a subtree from the next `size` leaves, in the same shape as appending them
+// one by one: the left subtree is a perfect tree with the largest power of two leaves that is
+// less than two thirds of `size`.
+fn build_sorted<I, D>(leaves: &mut Peekable<I>, size: usize, version: u64) -> NodeRef<D>
+where
+    I: Iterator<Item = NodeRef<D>>,
+    D: Digest,
+{
+    if size == 1 {
+        return leaves.next().unwrap();
+    }
+
+    let mut left_size = 1;
+    while left_size * 3 < size {
+        left_size *= 2;
+    }
+
+    let left = build_sorted(leaves, left_size, version);
+    let key = leaves.peek().unwrap().key.clone();
+    let right = build_sorted(leaves, size - left_size, version);
+    NodeRef::new(Node::branch(key, left, right, version))
+}
+
+// split_node splits the subtree into the keys `< key` and `>= key`, a subtree entirely on one side
+// is returned unchanged.
+fn split_node<D: Digest>(
+    node: NodeRef<D>,
+    key: &[u8],
     version: u64,
-) -> (Box<Node>, bool) {
+) -> (Option<NodeRef<D>>, Option<NodeRef<D>>) {
     if node.is_leaf() {
-        match key.cmp(&node.key) {
-            Ordering::Less => (
-                Box::new(Node::branch_bottom(
-                    Box::new(Node::leaf(key, value, version)),
-                    node,
-                    version,
-                )),
-                false,
-            ),
-            Ordering::Greater => (
-                Box::new(Node::branch_bottom(
-                    node,
-                    Box::new(Node::leaf(key, value, version)),
-                    version,
-                )),
-                false,
-            ),
-            Ordering::Equal => {
-                node.mutate(version);
-                node.value = value;
-                (node, true)
-            }
+        return if node.key.as_slice() < key {
+            (Some(node), None)
+        } else {
+            (None, Some(node))
+        };
+    }
+    let left = node.left.clone().unwrap();
+    let right = node.right.clone().unwrap();
+    if key < node.key.as_slice() {
+        let (ll, lr) = split_node(left, key, version);
+        if ll.is_none() {
+            return (None, Some(node));
+        }
+        (ll, join_nodes(lr, Some(right), version))
+    } else {
+        let (rl, rr) = split_node(right, key, version);
+        if rr.is_none() {
+            return (Some(node), None);
+        }
+        (join_nodes(Some(left), rl, version), rr)
+    }
+}
+
+// join_nodes joins two subtrees, the keys of `left` all precede the ones of `right`.
+fn join_nodes<D: Digest>(
+    left: Option<NodeRef<D>>,
+    right: Option<NodeRef<D>>,
+    version: u64,
+) -> Option<NodeRef<D>> {
+    match (left, right) {
+        (None, node) | (node, None) => node,
+        (Some(left), Some(right)) => Some(join_recursive(left, right, version)),
+    }
+}
+
+// join_recursive descends the spine of the higher subtree until the heights are within one, joins
+// them with a new inner node there, and rebalances on the way back up like the insertion.
+fn join_recursive<D: Digest>(
+    mut left: NodeRef<D>,
+    mut right: NodeRef<D>,
+    version: u64,
+) -> NodeRef<D> {
+    if left.height > right.height + 1 {
+        let n = NodeRef::make_mut(&mut left);
+        n.mutate(version);
+        n.right = Some(join_recursive(n.right.take().unwrap(), right, version));
+        n.update_height_size();
+        balance(left, version)
+    } else if right.height > left.height + 1 {
+        let n = NodeRef::make_mut(&mut right);
+        n.mutate(version);
+        n.left = Some(join_recursive(left, n.left.take().unwrap(), version));
+        n.update_height_size();
+        balance(right, version)
+    } else {
+        let key = min_key(&right).to_vec();
+        NodeRef::new(Node::branch(key, left, right, version))
+    }
+}
+
+fn min_key<D: Digest>(mut node: &Node<D>) -> &[u8] {
+    while let Some(left) = &node.left {
+        node = left;
+    }
+    &node.key
+}
+
+fn max_key<D: Digest>(mut node: &Node<D>) -> &[u8] {
+    while let Some(right) = &node.right {
+        node = right;
+    }
+    &node.key
+}
+
+// it returns if it's an update or insertion, if update, the tree height and balance is not changed.
+fn insert_recursive<D: Digest>(
+    mut node: NodeRef<D>,
+    key: Vec<u8>,
+    value: Vec<u8>,
+    version: u64,
+) -> (NodeRef<D>, Insert) {
+    if node.is_leaf() {
+        match key.cmp(&node.key) {
+            Ordering::Less => (
+                NodeRef::new(Node::branch_bottom(
+                    NodeRef::new(Node::leaf(key, value, version)),
+                    node,
+                    version,
+                )),
+                Insert::Added,
+            ),
+            Ordering::Greater => (
+                NodeRef::new(Node::branch_bottom(
+                    node,
+                    NodeRef::new(Node::leaf(key, value, version)),
+                    version,
+                )),
+                Insert::Added,
+            ),
+            // setting the current value keeps the version and the hash of the leaf.
+            Ordering::Equal if value == node.value => (node, Insert::Unchanged),
+            Ordering::Equal => {
+                let n = NodeRef::make_mut(&mut node);
+                n.mutate(version);
+                n.value = value;
+                (node, Insert::Updated)
+            }
+        }
+    } else {
+        let is_left = key < node.key;
+        // the child is taken out of the node owned uniquely so it's mutated in place, the node
+        // shared with the saved versions is only copied after the child is changed.
+        let owned = match &mut node {
+            NodeRef::Loaded(n) => Arc::get_mut(n),
+            NodeRef::Stored(_) => None,
+        };
+        let (child, taken) = match owned {
+            Some(n) if is_left => (n.left.take(), true),
+            Some(n) => (n.right.take(), true),
+            None if is_left => (node.left.clone(), false),
+            None => (node.right.clone(), false),
+        };
+        let (child, insert) = insert_recursive(child.unwrap(), key, value, version);
+        if insert == Insert::Unchanged && !taken {
+            return (node, insert);
+        }
+
+        let n = NodeRef::make_mut(&mut node);
+        if is_left {
+            n.left = Some(child);
+        } else {
+            n.right = Some(child);
+        }
+        match insert {
+            Insert::Unchanged => {}
+            Insert::Updated => n.mutate(version),
+            Insert::Added => {
+                n.mutate(version);
+                n.update_height_size();
+                node = balance(node, version);
+            }
+        }
+        (node, insert)
+    }
+}
+
+// Insert is how the subtree is changed by `insert_recursive`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Insert {
+    Added,
+    Updated,
+    Unchanged,
+}
+
+// get_sorted descends once for the sorted keys, they are split between the children like
+// `multi_proof`, so the keys sharing a path visit the nodes on it once.
+fn get_sorted<'a, D: Digest>(
+    node: &'a Node<D>,
+    keys: &[&[u8]],
+    values: &mut Vec<Option<&'a [u8]>>,
+) {
+    if keys.is_empty() {
+        return;
+    }
+    if node.is_leaf() {
+        values.extend(
+            keys.iter()
+                .map(|key| (*key == node.key.as_slice()).then_some(node.value.as_slice())),
+        );
+        return;
+    }
+    let mid = keys.partition_point(|key| *key < node.key.as_slice());
+    get_sorted(node.left.as_ref().unwrap(), &keys[..mid], values);
+    get_sorted(node.right.as_ref().unwrap(), &keys[mid..], values);
+}
+
+// insert_absent inserts the key if it don't exist, the nodes on the path are copied on the way back
+// up, returns `None` without changing any node if the key exists.
+fn insert_absent<D: Digest>(
+    node: &NodeRef<D>,
+    key: Vec<u8>,
+    value: Vec<u8>,
+    version: u64,
+) -> Option<NodeRef<D>> {
+    if node.is_leaf() {
+        let (left, right) = match key.cmp(&node.key) {
+            Ordering::Less => (NodeRef::new(Node::leaf(key, value, version)), node.clone()),
+            Ordering::Greater => (node.clone(), NodeRef::new(Node::leaf(key, value, version))),
+            Ordering::Equal => return None,
+        };
+        return Some(NodeRef::new(Node::branch_bottom(left, right, version)));
+    }
+
+    let is_left = key < node.key;
+    let child = if is_left { &node.left } else { &node.right };
+    let child = insert_absent(child.as_ref().unwrap(), key, value, version)?;
+    let mut node = node.clone();
+    let n = NodeRef::make_mut(&mut node);
+    n.mutate(version);
+    if is_left {
+        n.left = Some(child);
+    } else {
+        n.right = Some(child);
+    }
+    n.update_height_size();
+    Some(balance(node, version))
+}
+
+// remove_recursive returns:
+// - (false, Some(origNode), None)
+//   key not found, nothing changed in subtree
+// - (true,  None,           None)
+//   leaf is removed, to replace parent with the other leaf or none
+// - (true,  Some(new node), None))
+//   subtree changed, don't update branch key
+// - (true,  Some(new node), Some(newKey))
+//   subtree changed, update branch key
+//
+// the caller should make sure the key exists, otherwise the nodes on the path are copied for nothing.
+fn remove_recursive<D: Digest>(
+    mut node: NodeRef<D>,
+    key: &[u8],
+    version: u64,
+) -> (bool, Option<NodeRef<D>>, Option<Vec<u8>>) {
+    if node.is_leaf() {
+        if key == node.key {
+            (true, None, None)
+        } else {
+            (false, Some(node), None)
+        }
+    } else if key < &node.key {
+        let n = NodeRef::make_mut(&mut node);
+        let (found, new_left, new_key) = remove_recursive(n.left.take().unwrap(), key, version);
+        if !found {
+            n.left = new_left;
+            return (false, Some(node), None);
+        }
+
+        if let Some(new_left) = new_left {
+            n.mutate(version);
+            n.left = Some(new_left);
+            n.update_height_size();
+            node = balance(node, version);
+            (true, Some(node), new_key)
+        } else {
+            (true, n.right.take(), Some(mem::take(&mut n.key)))
+        }
+    } else {
+        let n = NodeRef::make_mut(&mut node);
+        let (found, new_right, new_key) = remove_recursive(n.right.take().unwrap(), key, version);
+        if !found {
+            n.right = new_right;
+            return (false, Some(node), None);
+        }
+
+        if let Some(new_right) = new_right {
+            n.mutate(version);
+            n.right = Some(new_right);
+            if let Some(new_key) = new_key {
+                n.key = new_key;
+            }
+            n.update_height_size();
+            node = balance(node, version);
+            (true, Some(node), None)
+        } else {
+            (true, n.left.take(), None)
+        }
+    }
+}
+
+#[cfg(feature = "metrics")]
+thread_local! {
+    // ROTATIONS counts the rotations done on the current thread, the writes of the trees add the
+    // difference to their own counter, so `balance` don't need a reference to the tree.
+    static ROTATIONS: Cell<u64> = const { Cell::new(0) };
+}
+
+#[inline(always)]
+fn count_rotation() {
+    #[cfg(feature = "metrics")]
+    ROTATIONS.set(ROTATIONS.get() + 1);
+}
+
+fn balance<D: Digest>(mut node: NodeRef<D>, version: u64) -> NodeRef<D> {
+    let balance_factor = node.balance_factor();
+
+    if balance_factor > 1 {
+        let n = NodeRef::make_mut(&mut node);
+        n.mutate(version);
+        if n.left.as_ref().unwrap().balance_factor() >= 0 {
+            rotate_right(node, version)
+        } else {
+            n.left = n.left.take().map(|mut left| {
+                NodeRef::make_mut(&mut left).mutate(version);
+                rotate_left(left, version)
+            });
+            rotate_right(node, version)
+        }
+    } else if balance_factor < -1 {
+        let n = NodeRef::make_mut(&mut node);
+        n.mutate(version);
+        if n.right.as_ref().unwrap().balance_factor() <= 0 {
+            rotate_left(node, version)
+        } else {
+            let right = n.right.take().unwrap();
+            n.right = Some(rotate_right(right, version));
+            rotate_left(node, version)
+        }
+    } else {
+        node
+    }
+}
+
+fn rotate_right<D: Digest>(mut a: NodeRef<D>, version: u64) -> NodeRef<D> {
+    count_rotation();
+    let a_mut = NodeRef::make_mut(&mut a);
+    let mut b = a_mut.left.take().unwrap();
+    let b_mut = NodeRef::make_mut(&mut b);
+    let t2 = b_mut.right.take();
+
+    a_mut.left = t2;
+    a_mut.update_height_size();
+
+    b_mut.mutate(version);
+    b_mut.right = Some(a);
+    b_mut.update_height_size();
+
+    b
+}
+
+fn rotate_left<D: Digest>(mut a: NodeRef<D>, version: u64) -> NodeRef<D> {
+    count_rotation();
+    let a_mut = NodeRef::make_mut(&mut a);
+    let mut b = a_mut.right.take().unwrap();
+    let b_mut = NodeRef::make_mut(&mut b);
+    let t2 = b_mut.left.take();
+
+    a_mut.right = t2;
+    a_mut.update_height_size();
+
+    b_mut.mutate(version);
+    b_mut.left = Some(a);
+    b_mut.update_height_size();
+
+    b
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proof::verify_membership;
+    use crate::MemTree;
+    use hexhex::hex_literal;
+    use sha2::Sha512;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_basic_operations() {
+        let mut tree = IAVLTree::new();
+        assert_eq!(tree.root_hash(), &Sha256::digest(b""));
+
+        tree.set(b"key1".to_vec(), b"value1".to_vec());
+        assert_eq!(tree.get(b"key1"), Some(b"value1".as_ref()));
+        let root1 = tree.save_version().to_vec();
+
+        tree.set(b"key2".to_vec(), b"value2".to_vec());
+        assert_eq!(tree.get(b"key2"), Some(b"value2".as_ref()));
+
+        let root2 = tree.save_version().to_vec();
+        assert_ne!(root1, root2);
+
+        tree.remove(b"key2");
+        assert_eq!(tree.get(b"key2"), None);
+
+        let root3 = tree.save_version().to_vec();
+        assert_eq!(root1, root3);
+    }
+
+    #[test]
+    fn test_len() {
+        let mut tree = IAVLTree::new();
+        assert!(tree.is_empty());
+        assert_eq!(tree.len(), 0);
+
+        for i in 0u32..10 {
+            tree.set(i.to_be_bytes().to_vec(), i.to_be_bytes().to_vec());
+        }
+        // update don't change the size
+        tree.set(0u32.to_be_bytes().to_vec(), b"value".to_vec());
+        assert_eq!(tree.len(), 10);
+        assert!(tree.contains_key(&0u32.to_be_bytes()));
+
+        tree.remove(&0u32.to_be_bytes());
+        tree.remove(&100u32.to_be_bytes());
+        assert_eq!(tree.len(), 9);
+        assert!(!tree.contains_key(&0u32.to_be_bytes()));
+
+        for i in 1u32..10 {
+            tree.remove(&i.to_be_bytes());
+        }
+        assert!(tree.is_empty());
+        assert_eq!(tree.len(), 0);
+    }
+
+    #[test]
+    fn test_update_value() {
+        let mut tree = IAVLTree::new();
+        tree.set(b"key".to_vec(), b"value1".to_vec());
+        let hash1 = tree.save_version().to_vec();
+
+        tree.set(b"key".to_vec(), b"value2".to_vec());
+        let hash2 = tree.save_version().to_vec();
+
+        assert_ne!(hash1, hash2);
+        assert_eq!(tree.get(b"key"), Some(b"value2".as_ref()));
+    }
+
+    #[test]
+    fn test_update_in_place() {
+        let mut tree = IAVLTree::new();
+        let mut expected = IAVLTree::new();
+        for i in 0u32..100 {
+            tree.set(i.to_be_bytes().to_vec(), i.to_be_bytes().to_vec());
+            expected.set(i.to_be_bytes().to_vec(), i.to_be_bytes().to_vec());
+        }
+        tree.save_version();
+        expected.save_version();
+
+        assert!(tree
+            .update_in_place(&42u32.to_be_bytes(), |value| value.push(1))
+            .unwrap());
+        expected.set(42u32.to_be_bytes().to_vec(), vec![0, 0, 0, 42, 1]);
+        assert_eq!(
+            tree.get(&42u32.to_be_bytes()),
+            Some([0, 0, 0, 42, 1].as_ref())
+        );
+        assert_eq!(tree.save_version(), expected.save_version());
+        // the old version is not affected
+        assert_eq!(
+            tree.get_versioned(1, &42u32.to_be_bytes()),
+            Some(42u32.to_be_bytes().as_ref())
+        );
+
+        let root = *tree.root_hash();
+        assert!(!tree
+            .update_in_place(&100u32.to_be_bytes(), |_| unreachable!())
+            .unwrap());
+        assert_eq!(*tree.root_hash(), root);
+        assert!(!IAVLTree::new()
+            .update_in_place(b"key", |_| unreachable!())
+            .unwrap());
+    }
+
+    #[test]
+    fn test_write_batch_with_old() {
+        let mut tree = IAVLTree::new();
+        tree.set(b"key1".to_vec(), b"value1".to_vec());
+        tree.set(b"key2".to_vec(), b"value2".to_vec());
+
+        let old = tree.write_batch_with_old([
+            (b"key1".to_vec(), Some(b"new1".to_vec())),
+            (b"key3".to_vec(), Some(b"value3".to_vec())),
+            (b"key2".to_vec(), None),
+            (b"key1".to_vec(), Some(b"newer1".to_vec())),
+            (b"key4".to_vec(), None),
+        ]);
+        assert_eq!(
+            old,
+            vec![
+                (b"key1".to_vec(), Some(b"value1".to_vec())),
+                (b"key3".to_vec(), None),
+                (b"key2".to_vec(), Some(b"value2".to_vec())),
+                (b"key1".to_vec(), Some(b"new1".to_vec())),
+                (b"key4".to_vec(), None),
+            ]
+        );
+        assert_eq!(tree.get(b"key1"), Some(b"newer1".as_ref()));
+        assert_eq!(tree.get(b"key2"), None);
+
+        // applying the old values in reverse order undoes the batch
+        tree.write_batch(old.into_iter().rev());
+        assert_eq!(
+            tree.range(..).collect::<Vec<_>>(),
+            vec![(b"key1" as &[u8], b"value1" as &[u8]), (b"key2", b"value2")]
+        );
+    }
+
+    #[test]
+    fn test_version() {
+        let mut tree = IAVLTree::new();
+        assert_eq!(tree.version(), 0);
+
+        tree.set(b"key1".to_vec(), b"value1".to_vec());
+        assert_eq!(tree.version(), 0);
+        tree.save_version();
+        assert_eq!(tree.version(), 1);
+
+        // empty versions are counted too
+        tree.save_version();
+        assert_eq!(tree.version(), 2);
+    }
+
+    #[test]
+    fn test_begin_version() {
+        let mut tree = IAVLTree::new();
+        assert_eq!(tree.begin_version(), 1);
+        tree.set(b"key1".to_vec(), b"value1".to_vec());
+        let hash1 = *tree.save_version();
+
+        assert_eq!(tree.begin_version(), 2);
+        tree.set(b"key2".to_vec(), b"value2".to_vec());
+        tree.root_hash();
+        tree.remove(b"key1");
+        tree.save_version();
+        assert_eq!(tree.version(), 2);
+        assert_eq!(tree.get(b"key2"), Some(b"value2".as_ref()));
+        assert_eq!(tree.get_versioned(1, b"key1"), Some(b"value1".as_ref()));
+
+        // an explicitly opened empty version
+        tree.begin_version();
+        tree.save_version();
+        assert_eq!(tree.version(), 3);
+        assert_ne!(tree.root_hash(), &hash1);
+    }
+
+    #[test]
+    #[should_panic(expected = "no open version")]
+    fn test_write_after_save_version() {
+        let mut tree = IAVLTree::new();
+        tree.begin_version();
+        tree.set(b"key1".to_vec(), b"value1".to_vec());
+        tree.save_version();
+        // without begin_version, the node would be tagged with version 2 silently
+        tree.set(b"key1".to_vec(), b"value2".to_vec());
+    }
+
+    #[test]
+    #[should_panic(expected = "no open version")]
+    fn test_save_version_twice() {
+        let mut tree = IAVLTree::new();
+        tree.begin_version();
+        tree.save_version();
+        tree.save_version();
+    }
+
+    #[test]
+    fn test_clone() {
+        let mut tree = IAVLTree::new();
+        for i in 0u32..100 {
+            tree.set(i.to_be_bytes().to_vec(), i.to_be_bytes().to_vec());
+        }
+        let hash = *tree.save_version();
+
+        let mut clone = tree.clone();
+        assert_eq!(clone.version(), 1);
+        assert_eq!(clone.root_hash(), &hash);
+        clone.set(1u32.to_be_bytes().to_vec(), b"new".to_vec());
+        clone.remove(&2u32.to_be_bytes());
+        clone.set(200u32.to_be_bytes().to_vec(), b"new".to_vec());
+        assert!(clone
+            .update_in_place(&3u32.to_be_bytes(), |v| v.push(0))
+            .unwrap());
+        assert_ne!(*clone.save_version(), hash);
+
+        assert_eq!(tree.version(), 1);
+        assert_eq!(tree.root_hash(), &hash);
+        assert_eq!(
+            tree.get(&1u32.to_be_bytes()),
+            Some(1u32.to_be_bytes().as_ref())
+        );
+        assert_eq!(
+            tree.get(&2u32.to_be_bytes()),
+            Some(2u32.to_be_bytes().as_ref())
+        );
+        assert_eq!(tree.get(&200u32.to_be_bytes()), None);
+        assert_eq!(tree.len(), 100);
+    }
+
+    #[test]
+    fn test_diff() {
+        use crate::Change;
+
+        let mut tree = IAVLTree::new();
+        for i in 0u32..100 {
+            tree.set(i.to_be_bytes().to_vec(), i.to_be_bytes().to_vec());
+        }
+        tree.save_version();
+
+        tree.set(200u32.to_be_bytes().to_vec(), b"new".to_vec());
+        tree.set(10u32.to_be_bytes().to_vec(), b"updated".to_vec());
+        tree.remove(&50u32.to_be_bytes());
+        // rewritten with the same value
+        tree.set(70u32.to_be_bytes().to_vec(), 70u32.to_be_bytes().to_vec());
+        tree.save_version();
+
+        let changes = tree.diff(1, 2).unwrap().collect::<Vec<_>>();
+        assert_eq!(
+            changes,
+            vec![
+                (
+                    10u32.to_be_bytes().to_vec(),
+                    Change::Updated(10u32.to_be_bytes().to_vec(), b"updated".to_vec())
+                ),
+                (
+                    50u32.to_be_bytes().to_vec(),
+                    Change::Deleted(50u32.to_be_bytes().to_vec())
+                ),
+                (
+                    200u32.to_be_bytes().to_vec(),
+                    Change::Inserted(b"new".to_vec())
+                ),
+            ]
+        );
+
+        // reversed direction
+        let changes = tree.diff(2, 1).unwrap().map(|(_, c)| c).collect::<Vec<_>>();
+        assert_eq!(changes.len(), 3);
+        assert!(matches!(changes[1], Change::Inserted(_)));
+        assert!(matches!(changes[2], Change::Deleted(_)));
+
+        assert_eq!(tree.diff(2, 2).unwrap().count(), 0);
+        assert!(tree.diff(1, 3).is_err());
+
+        // to and from an empty version
+        tree.write_batch((0u32..100).map(|i| (i.to_be_bytes().to_vec(), None)));
+        tree.remove(&200u32.to_be_bytes());
+        tree.save_version();
+        let changes = tree.diff(2, 3).unwrap().collect::<Vec<_>>();
+        assert_eq!(changes.len(), 100);
+        assert!(changes.iter().all(|(_, c)| matches!(c, Change::Deleted(_))));
+        assert_eq!(tree.diff(3, 1).unwrap().count(), 100);
+    }
+
+    #[test]
+    fn test_check_invariants() {
+        let mut tree = IAVLTree::new();
+        assert_eq!(tree.check_invariants(), Ok(()));
+        for i in 0u32..2000 {
+            let hash = Sha256::digest(i.to_be_bytes());
+            let key = hash[..2].to_vec();
+            if hash[2] < 64 {
+                tree.remove(&key);
+            } else {
+                tree.set(key, i.to_be_bytes().to_vec());
+            }
+            if i % 500 == 0 {
+                tree.save_version();
+            }
+            if i % 20 == 0 {
+                tree.check_invariants().unwrap();
+            }
+        }
+        tree.save_version();
+        tree.check_invariants().unwrap();
+
+        // corrupt the size of the root
+        let root = NodeRef::make_mut(tree.root.as_mut().unwrap());
+        root.size += 1;
+        let err = tree.check_invariants().unwrap_err();
+        assert!(err.contains("has size"), "{}", err);
+    }
+
+    #[test]
+    fn test_heap_size() {
+        let mut tree = IAVLTree::new();
+        let mut last = tree.heap_size();
+        for i in 0u32..100 {
+            tree.set(i.to_be_bytes().to_vec(), i.to_be_bytes().to_vec());
+            let size = tree.heap_size();
+            assert!(size > last);
+            last = size;
+        }
+        tree.save_version();
+
+        for i in 0u32..50 {
+            tree.remove(&i.to_be_bytes());
+        }
+        tree.save_version();
+        tree.prune_to(tree.version()).unwrap();
+        assert!(tree.heap_size() < last);
+    }
+
+    #[test]
+    fn test_clear() {
+        let mut tree = IAVLTree::new();
+        tree.set(b"key1".to_vec(), b"value1".to_vec());
+        tree.set(b"key2".to_vec(), b"value2".to_vec());
+        tree.save_version();
+
+        tree.clear();
+        assert_eq!(tree.get(b"key1"), None);
+        assert_eq!(tree.range(..).count(), 0);
+        assert_eq!(tree.save_version(), &Sha256::digest(b""));
+        assert_eq!(tree.version(), 2);
+        assert_eq!(tree.get_versioned(1, b"key1"), Some(b"value1".as_ref()));
+    }
+
+    #[test]
+    fn test_count_range() {
+        let mut tree = IAVLTree::new();
+        assert_eq!(tree.count_range(..), 0);
+        for i in (0u32..2000).step_by(2) {
+            tree.set(i.to_be_bytes().to_vec(), i.to_be_bytes().to_vec());
+        }
+
+        let key = |i: u32| i.to_be_bytes().to_vec();
+        let cases = [
+            (Bound::Unbounded, Bound::Unbounded),
+            (Bound::Included(key(10)), Bound::Excluded(key(20))),
+            (Bound::Included(key(11)), Bound::Included(key(20))),
+            (Bound::Excluded(key(10)), Bound::Included(key(21))),
+            (Bound::Unbounded, Bound::Excluded(key(0))),
+            (Bound::Excluded(key(1998)), Bound::Unbounded),
+            (Bound::Included(key(500)), Bound::Excluded(key(100))),
+            (Bound::Included(key(3000)), Bound::Unbounded),
+        ];
+        for bounds in cases {
+            assert_eq!(
+                tree.count_range(bounds.clone()),
+                tree.range(bounds.clone()).count() as u64,
+                "{:?}",
+                bounds
+            );
+        }
+    }
+
+    #[test]
+    fn test_rank_select() {
+        let mut tree = IAVLTree::new();
+        assert_eq!(tree.rank(b"key"), 0);
+        assert_eq!(tree.select(0), None);
+        for i in (10u32..110).step_by(10) {
+            tree.set(i.to_be_bytes().to_vec(), i.to_be_bytes().to_vec());
+        }
+
+        for (n, i) in (10u32..110).step_by(10).enumerate() {
+            let key = i.to_be_bytes();
+            // present key
+            assert_eq!(tree.rank(&key), n as u64);
+            assert_eq!(tree.select(n as u64).unwrap().0, key);
+            // absent key between the leaves
+            assert_eq!(tree.rank(&(i + 5).to_be_bytes()), n as u64 + 1);
+        }
+        // below and above all the leaves
+        assert_eq!(tree.rank(&0u32.to_be_bytes()), 0);
+        assert_eq!(tree.rank(b""), 0);
+        assert_eq!(tree.rank(&1000u32.to_be_bytes()), 10);
+        assert_eq!(tree.select(10), None);
+    }
+
+    #[test]
+    fn test_remove_taking() {
+        let mut tree = IAVLTree::new();
+        tree.set(b"key1".to_vec(), b"value1".to_vec());
+        tree.set(b"key2".to_vec(), b"value2".to_vec());
+
+        assert_eq!(tree.remove_taking(b"key1"), Some(b"value1".to_vec()));
+        assert_eq!(tree.get(b"key1"), None);
+        assert_eq!(tree.remove_taking(b"key1"), None);
+        assert_eq!(tree.len(), 1);
+    }
+
+    #[test]
+    fn test_root_hash_immutable() {
+        let mut tree = IAVLTree::new();
+        assert_eq!(tree.root_hash_immutable(), Sha256::digest(b""));
+        for i in 0u32..1000 {
+            tree.set(i.to_be_bytes().to_vec(), i.to_be_bytes().to_vec());
+        }
+        tree.save_version();
+        // part of the nodes are hashed
+        for i in (0u32..1000).step_by(97) {
+            tree.set(i.to_be_bytes().to_vec(), b"new".to_vec());
+        }
+        let hash = tree.root_hash_immutable();
+        assert!(tree.root.as_ref().unwrap().hash.is_none());
+        assert_eq!(tree.root_hash(), &hash);
+        assert_eq!(tree.root_hash_immutable(), hash);
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut tree = IAVLTree::new();
+        tree.set_limits(Some(4), Some(6));
+        tree.try_set(b"key1".to_vec(), b"value1".to_vec()).unwrap();
+        let hash = *tree.root_hash();
+
+        assert!(tree.try_set(b"key12".to_vec(), b"value".to_vec()).is_err());
+        assert!(tree.try_set(b"key1".to_vec(), b"value12".to_vec()).is_err());
+        assert_eq!(tree.get(b"key1"), Some(b"value1".as_ref()));
+        assert_eq!(tree.len(), 1);
+        assert_eq!(tree.root_hash(), &hash);
+
+        // the value updated in place is checked too
+        tree.set_limits(None, Some(2));
+        assert!(tree
+            .update_in_place(b"key1", |value| value.resize(101, 0))
+            .is_err());
+        assert_eq!(tree.get(b"key1"), Some(b"value1".as_ref()));
+        assert_eq!(tree.root_hash(), &hash);
+
+        tree.set_limits(None, None);
+        tree.set(b"key12".to_vec(), b"value12".to_vec());
+        assert_eq!(tree.len(), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "value too long")]
+    fn test_limits_write_batch() {
+        let mut tree = IAVLTree::new();
+        tree.set_limits(None, Some(6));
+        tree.write_batch([
+            (b"key1".to_vec(), Some(b"value1".to_vec())),
+            (b"key2".to_vec(), Some(b"value12".to_vec())),
+        ]);
+    }
+
+    #[test]
+    fn test_replay() {
+        let entries = (1u64..=3)
+            .map(|version| Changeset {
+                version,
+                changes: (0u64..10)
+                    .map(|i| {
+                        let key = (version * 3 + i).to_be_bytes().to_vec();
+                        (key.clone(), (i % 4 != 0).then_some(key))
+                    })
+                    .collect(),
+                root_hash: None,
+            })
+            .collect::<Vec<_>>();
+
+        let mut tree1: IAVLTree = IAVLTree::replay(entries.clone()).unwrap();
+        let mut tree2: IAVLTree = IAVLTree::replay(entries.clone()).unwrap();
+        assert_eq!(tree1.version(), 3);
+        assert_eq!(tree2.version(), 3);
+        assert_eq!(tree1.root_hash(), tree2.root_hash());
+
+        assert!(IAVLTree::<Sha256>::replay(entries.clone().into_iter().skip(1)).is_err());
+
+        let mut entries = entries;
+        entries[2].root_hash = Some(tree1.root_hash().to_vec());
+        assert!(IAVLTree::<Sha256>::replay(entries.clone()).is_ok());
+        entries[1].root_hash = Some(tree1.root_hash().to_vec());
+        assert!(IAVLTree::<Sha256>::replay(entries).is_err());
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn test_rotation_count() {
+        // ascending inserts always grow the right spine, each insert rotates once, except the first
+        // one and the k ones making the tree one level taller.
+        for k in 1u32..=10 {
+            let mut tree = IAVLTree::new();
+            for i in 0u32..1 << k {
+                tree.set(i.to_be_bytes().to_vec(), vec![]);
+            }
+            assert_eq!(tree.rotation_count(), (1u64 << k) - u64::from(k) - 1);
+        }
+    }
+
+    #[test]
+    fn test_range_with_version() {
+        let mut tree = IAVLTree::new();
+        tree.set(b"a".to_vec(), b"1".to_vec());
+        tree.set(b"b".to_vec(), b"1".to_vec());
+        tree.set(b"c".to_vec(), b"1".to_vec());
+        tree.save_version();
+        tree.set(b"b".to_vec(), b"2".to_vec());
+        tree.set(b"d".to_vec(), b"2".to_vec());
+        tree.save_version();
+
+        assert_eq!(
+            tree.range_with_version(..).collect::<Vec<_>>(),
+            vec![
+                (b"a" as &[u8], b"1" as &[u8], 1),
+                (b"b", b"2", 2),
+                (b"c", b"1", 1),
+                (b"d", b"2", 2),
+            ]
+        );
+        assert_eq!(
+            tree.range_with_version(b"b".to_vec()..)
+                .rev()
+                .map(|(_, _, version)| version)
+                .collect::<Vec<_>>(),
+            vec![2, 1, 2]
+        );
+    }
+
+    #[test]
+    fn test_empty_key() {
+        let mut tree = IAVLTree::new();
+        for key in [b"b" as &[u8], b"", b"a", b"c"] {
+            tree.set(key.to_vec(), [b"v", key].concat());
+        }
+        assert_eq!(tree.get(b""), Some(b"v" as &[u8]));
+        assert_eq!(tree.rank(b""), 0);
+        assert_eq!(
+            tree.range(..).map(|(k, _)| k).collect::<Vec<_>>(),
+            vec![b"" as &[u8], b"a", b"b", b"c"]
+        );
+        assert_eq!(tree.range(..b"a".to_vec()).count(), 1);
+        assert_eq!(tree.range(b"".to_vec()..=b"".to_vec()).count(), 1);
+        assert_eq!(
+            tree.range((Bound::Excluded(vec![]), Bound::Unbounded))
+                .count(),
+            3
+        );
+        tree.check_invariants().unwrap();
+
+        let root = *tree.root_hash();
+        let (value, proof) = tree.get_with_proof(b"");
+        assert_eq!(value, Some(b"v".to_vec()));
+        assert!(matches!(proof, KeyProof::Exist(_)));
+        assert!(proof.verify(&root, b"", Some(b"v"), HashScheme::default()));
+        assert!(tree.get_range_proof(..b"b".to_vec()).verify(
+            &root,
+            ..b"b".to_vec(),
+            HashScheme::default()
+        ));
+
+        tree.save_version();
+        assert_eq!(tree.remove_taking(b""), Some(b"v".to_vec()));
+        assert_eq!(tree.get(b""), None);
+        assert_eq!(tree.range(..).count(), 3);
+        tree.check_invariants().unwrap();
+
+        let root = *tree.root_hash();
+        let (value, proof) = tree.get_with_proof(b"");
+        assert_eq!(value, None);
+        assert!(matches!(proof, KeyProof::NonExist(_)));
+        assert!(proof.verify(&root, b"", None, HashScheme::default()));
+        assert_eq!(tree.get_versioned(1, b""), Some(b"v" as &[u8]));
+    }
+
+    #[test]
+    fn test_max_height() {
+        let mut tree = IAVLTree::new();
+        tree.set_max_height(Some(3));
+        let mut i = 0u32;
+        let err = loop {
+            if let Err(err) = tree.try_set(i.to_be_bytes().to_vec(), vec![]) {
+                break err;
+            }
+            i += 1;
+        };
+        assert_eq!(err, "tree too high: 4 > 3");
+        assert_eq!(tree.len(), u64::from(i));
+        assert_eq!(tree.get(&i.to_be_bytes()), None);
+        assert!(tree.root().unwrap().height <= 3);
+        tree.check_invariants().unwrap();
+
+        // updates don't change the height
+        tree.try_set(0u32.to_be_bytes().to_vec(), b"new".to_vec())
+            .unwrap();
+    }
+
+    #[test]
+    fn test_commit() {
+        let mut tree = IAVLTree::new();
+        assert_eq!(tree.commit(), (1, *tree.root_hash()));
+        tree.set(b"key".to_vec(), b"value".to_vec());
+        let (version, hash) = tree.commit();
+        assert_eq!(version, tree.version());
+        assert_eq!(version, 2);
+        assert_eq!(hash, *tree.root_hash());
+        assert_eq!(
+            tree.saved_root(2).unwrap().unwrap().cached_hash(),
+            Some(&hash)
+        );
+    }
+
+    #[test]
+    fn test_height() {
+        let mut tree = IAVLTree::new();
+        assert_eq!(tree.height(), 0);
+        tree.set(b"key".to_vec(), b"value".to_vec());
+        assert_eq!(tree.height(), 0);
+
+        let mut tree = IAVLTree::new();
+        for i in 0u32..10000 {
+            // pseudo random order
+            let key = i.wrapping_mul(2654435761).to_be_bytes().to_vec();
+            tree.set(key, vec![]);
+            let n = tree.len() as f64;
+            assert!(f64::from(tree.height()) <= 1.44 * n.log2() + 1.0);
+        }
+        assert_eq!(tree.len(), 10000);
+    }
+
+    #[test]
+    fn test_into_iter() {
+        let mut tree = IAVLTree::new();
+        let mut expected = Vec::new();
+        for i in 0u32..100 {
+            let key = i.wrapping_mul(2654435761).to_be_bytes().to_vec();
+            tree.set(key.clone(), i.to_be_bytes().to_vec());
+            expected.push((key, i.to_be_bytes().to_vec()));
+        }
+        expected.sort();
+
+        let mut items = Vec::new();
+        for (key, value) in &tree {
+            items.push((key.to_vec(), value.to_vec()));
+        }
+        assert_eq!(items, expected);
+        assert_eq!((&tree).into_iter().count(), 100);
+    }
+
+    #[test]
+    fn test_debug_string() {
+        let mut tree = IAVLTree::new();
+        assert_eq!(tree.debug_string(), "<empty>\n");
+        tree.set(b"a".to_vec(), b"1".to_vec());
+        tree.set(b"b".to_vec(), vec![0xff; 20]);
+        tree.save_version();
+        tree.set(b"c".to_vec(), b"3".to_vec());
+        assert_eq!(
+            tree.debug_string(),
+            "b height=2 size=3 version=2
+  a height=0 size=1 version=1 value=1
+  c height=1 size=2 version=2
+    b height=0 size=1 version=1 value=\\xff\\xff\\xff\\xff\\xff\\xff\\xff\\xff\\xff\\xff\\xff\\xff\\xff\\xff\\xff\\xff...
+    c height=0 size=1 version=2 value=3
+"
+        );
+    }
+
+    #[test]
+    fn test_split_join() {
+        let mut tree = IAVLTree::new();
+        for i in 0u32..1000 {
+            tree.set(i.to_be_bytes().to_vec(), i.to_be_bytes().to_vec());
+        }
+        let hash = *tree.save_version();
+        let key = |i: u32| i.to_be_bytes().to_vec();
+
+        for at in [0u32, 1, 333, 500, 999, 1000] {
+            let (left, right) = tree.clone().split(&key(at));
+            left.check_invariants().unwrap();
+            right.check_invariants().unwrap();
+            assert!(left.range(..).map(|(k, _)| k.to_vec()).eq((0..at).map(key)));
+            assert!(right
+                .range(..)
+                .map(|(k, _)| k.to_vec())
+                .eq((at..1000).map(key)));
+            assert_eq!(left.root().map_or(0, |root| root.size), at as u64);
+            assert_eq!(left.version(), 1);
+
+            let mut joined = IAVLTree::join(left, right);
+            joined.check_invariants().unwrap();
+            assert!(joined.range(..).eq(tree.range(..)));
+            // the tree is unchanged if it's entirely on one side of the key
+            if at == 0 || at == 1000 {
+                assert_eq!(*joined.root_hash(), hash);
+            }
+        }
+
+        // the joined tree is balanced for the trees of different heights
+        let (small, large) = tree.clone().split(&key(10));
+        let joined = IAVLTree::join(small, large);
+        joined.check_invariants().unwrap();
+        assert!(joined.range(..).eq(tree.range(..)));
+        let (large, small) = tree.clone().split(&key(990));
+        IAVLTree::join(large, small).check_invariants().unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "must precede")]
+    fn test_join_unordered() {
+        let mut tree = IAVLTree::new();
+        for i in 0u32..10 {
+            tree.set(i.to_be_bytes().to_vec(), vec![]);
+        }
+        let (left, right) = tree.split(&5u32.to_be_bytes());
+        IAVLTree::join(right, left);
+    }
+
+    #[test]
+    fn test_remove_range() {
+        let mut tree = IAVLTree::new();
+        for i in 0u32..1000 {
+            tree.set(i.to_be_bytes().to_vec(), i.to_be_bytes().to_vec());
         }
-    } else {
-        node.mutate(version);
-        let updated = if key.cmp(&node.key) == Ordering::Less {
-            let (n1, updated) = insert_recursive(node.left.unwrap(), key, value, version);
-            node.left = Some(n1);
-            updated
-        } else {
-            let (n1, updated) = insert_recursive(node.right.unwrap(), key, value, version);
-            node.right = Some(n1);
-            updated
-        };
+        tree.save_version();
+        let mut expected = tree.clone();
 
-        if !updated {
-            node.update_height_size();
-            node = balance(node, version);
+        let bounds = 300u32.to_be_bytes().to_vec()..700u32.to_be_bytes().to_vec();
+        assert_eq!(tree.remove_range(bounds.clone()), 400);
+        for i in 300u32..700 {
+            expected.remove(&i.to_be_bytes());
         }
+        assert_eq!(tree.save_version(), expected.save_version());
+        assert_eq!(tree.range(..).count(), 600);
+        assert_eq!(tree.range(bounds.clone()).count(), 0);
 
-        (node, updated)
+        // nothing left in the range
+        assert_eq!(tree.remove_range(bounds), 0);
+        assert_eq!(tree.remove_range(..), 600);
+        assert!(tree.root().is_none());
     }
-}
 
-// remove_recursive returns:
-// - (false, Some(origNode), None)
-//   key not found, nothing changed in subtree
-// - (true,  None,           None)
-//   leaf is removed, to replace parent with the other leaf or none
-// - (true,  Some(new node), None))
-//   subtree changed, don't update branch key
-// - (true,  Some(new node), Some(newKey))
-//   subtree changed, update branch key
-fn remove_recursive(
-    mut node: Box<Node>,
-    key: &[u8],
-    version: u64,
-) -> (bool, Option<Box<Node>>, Option<Vec<u8>>) {
-    if node.is_leaf() {
-        if key == node.key {
-            (true, None, None)
-        } else {
-            (false, Some(node), None)
-        }
-    } else if key < &node.key {
-        let (found, new_left, new_key) = remove_recursive(node.left.take().unwrap(), key, version);
-        if !found {
-            node.left = new_left;
-            return (false, Some(node), None);
+    #[test]
+    fn test_noop_update() {
+        let mut tree = IAVLTree::new();
+        for i in 0u32..10 {
+            tree.set(i.to_be_bytes().to_vec(), b"value".to_vec());
         }
+        let hash = *tree.save_version();
 
-        if let Some(new_left) = new_left {
-            node.mutate(version);
-            node.left = Some(new_left);
-            node.update_height_size();
-            node = balance(node, version);
-            (true, Some(node), new_key)
-        } else {
-            (true, node.right, Some(node.key))
-        }
-    } else {
-        let (found, new_right, new_key) =
-            remove_recursive(node.right.take().unwrap(), key, version);
-        if !found {
-            node.right = new_right;
-            return (false, Some(node), None);
+        // setting the current value changes nothing, nor does updating it in place to itself
+        tree.set(5u32.to_be_bytes().to_vec(), b"value".to_vec());
+        assert!(tree
+            .update_in_place(&5u32.to_be_bytes(), |value| value.truncate(5))
+            .unwrap());
+        assert_eq!(*tree.root_hash(), hash);
+        assert!(tree
+            .range_with_version(..)
+            .all(|(_, _, version)| version == 1));
+        assert!(tree.node_iter().all(|node| node.version == 1));
+        // the root shared with the saved version is not copied
+        let (Some(NodeRef::Loaded(root)), Some(Some(NodeRef::Loaded(saved)))) =
+            (&tree.root, tree.versions.get(&1))
+        else {
+            panic!("root not loaded");
+        };
+        assert!(Arc::ptr_eq(root, saved));
+        assert_eq!(*tree.save_version(), hash);
+
+        // the same in the nodes mutated in place by the working version
+        tree.set(10u32.to_be_bytes().to_vec(), b"value".to_vec());
+        let hash = *tree.root_hash();
+        tree.set(10u32.to_be_bytes().to_vec(), b"value".to_vec());
+        tree.set(5u32.to_be_bytes().to_vec(), b"value".to_vec());
+        assert_eq!(*tree.root_hash(), hash);
+        assert_eq!(*tree.save_version(), hash);
+        tree.check_invariants().unwrap();
+
+        // a different value bumps the version of the path
+        tree.set(5u32.to_be_bytes().to_vec(), b"value1".to_vec());
+        assert_ne!(*tree.save_version(), hash);
+        let key = 5u32.to_be_bytes();
+        assert!(tree.range_with_version(..).all(|(k, _, version)| match k {
+            k if k == key => version == 4,
+            k if k == 10u32.to_be_bytes() => version == 3,
+            _ => version == 1,
+        }));
+    }
+
+    #[test]
+    fn test_get_sorted() {
+        let mut tree = IAVLTree::new();
+        assert_eq!(tree.get_sorted(&[b"a", b"b"]), vec![None, None]);
+        for i in (0u32..1000).step_by(3) {
+            tree.set(i.to_be_bytes().to_vec(), i.to_le_bytes().to_vec());
         }
+        tree.save_version();
 
-        if let Some(new_right) = new_right {
-            node.mutate(version);
-            node.right = Some(new_right);
-            if let Some(new_key) = new_key {
-                node.key = new_key;
+        let keys = [0u32, 1, 3, 3, 500, 501, 999, 1000]
+            .iter()
+            .map(|i| i.to_be_bytes())
+            .collect::<Vec<_>>();
+        let keys = keys.iter().map(|key| key.as_slice()).collect::<Vec<_>>();
+        let expected = keys.iter().map(|key| tree.get(key)).collect::<Vec<_>>();
+        assert_eq!(tree.get_sorted(&keys), expected);
+        assert!(tree.get_sorted(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_set_if_absent() {
+        let mut tree = IAVLTree::new();
+        let mut expected = IAVLTree::new();
+        let mut mem = MemTree::new();
+        for i in (0u32..200).map(|i| i * 7 % 100) {
+            let (key, value) = (i.to_be_bytes().to_vec(), i.to_le_bytes().to_vec());
+            let inserted = !expected.contains_key(&key);
+            if inserted {
+                expected.set(key.clone(), value.clone());
+            }
+            assert_eq!(tree.set_if_absent(key.clone(), value.clone()), inserted);
+            assert_eq!(mem.set_if_absent(key, value), inserted);
+            if i % 10 == 0 {
+                tree.save_version();
+                expected.save_version();
             }
-            node.update_height_size();
-            node = balance(node, version);
-            (true, Some(node), None)
-        } else {
-            (true, node.left, None)
         }
+        tree.check_invariants().unwrap();
+        assert_eq!(tree.root_hash(), expected.root_hash());
+        assert_eq!(tree.len(), 100);
+        assert_eq!(mem.tree.len(), 100);
+
+        // the existing value is kept, and no node is changed
+        tree.set(b"key".to_vec(), b"value1".to_vec());
+        let root = *tree.root_hash();
+        assert!(!tree.set_if_absent(b"key".to_vec(), b"value2".to_vec()));
+        assert_eq!(tree.get(b"key"), Some(b"value1" as &[u8]));
+        assert!(tree.root.as_ref().unwrap().cached_hash().is_some());
+        assert_eq!(*tree.root_hash(), root);
     }
-}
 
-fn balance(mut node: Box<Node>, version: u64) -> Box<Node> {
-    let balance_factor = node.balance_factor();
+    #[test]
+    fn test_hash_scheme() {
+        let scheme = HashScheme {
+            leaf_prefix: Some(0),
+            inner_prefix: Some(1),
+        };
+        let build = |scheme| {
+            let mut tree = IAVLTree::<Sha256>::with_hash_scheme(scheme);
+            for i in 0u32..20 {
+                tree.set(i.to_be_bytes().to_vec(), b"value".to_vec());
+            }
+            tree.save_version();
+            tree
+        };
+        let mut tree = build(scheme);
+        let root = *tree.root_hash();
+        assert_ne!(root, *build(HashScheme::default()).root_hash());
+        assert_eq!(root, *build(scheme).root_hash());
+        assert_eq!(root, tree.root_hash_immutable());
+        assert_eq!(root, *tree.snapshot().root_hash());
+        assert!(tree.check_invariants().is_ok());
 
-    if balance_factor > 1 {
-        node.mutate(version);
-        if node.left.as_ref().unwrap().balance_factor() >= 0 {
-            rotate_right(node, version)
-        } else {
-            node.left = node.left.map(|mut n| {
-                n.mutate(version);
-                rotate_left(n, version)
-            });
-            rotate_right(node, version)
-        }
-    } else if balance_factor < -1 {
-        node.mutate(version);
-        if node.right.as_ref().unwrap().balance_factor() <= 0 {
-            rotate_left(node, version)
-        } else {
-            let right = node.right.take().unwrap();
-            node.right = Some(rotate_right(right, version));
-            rotate_left(node, version)
-        }
-    } else {
-        node
+        let key = 5u32.to_be_bytes().to_vec();
+        let proof = tree.get_membership_proof(&key).unwrap();
+        assert!(verify_membership(&root, &key, b"value", &proof, scheme));
+        let mut wrong_scheme = proof.clone();
+        wrong_scheme.scheme = HashScheme::default();
+        assert!(!verify_membership(
+            &root,
+            &key,
+            b"value",
+            &wrong_scheme,
+            scheme
+        ));
+        assert!(tree
+            .get_multi_proof(std::slice::from_ref(&key))
+            .verify(&root, scheme));
+        assert!(tree
+            .get_non_membership_proof(b"x")
+            .verify(&root, b"x", scheme));
+
+        // the verifier expecting the default scheme rejects the proofs, though they hash to the
+        // root with the scheme they carry.
+        let default = HashScheme::default();
+        assert!(!verify_membership(&root, &key, b"value", &proof, default));
+        assert!(!tree
+            .get_multi_proof(std::slice::from_ref(&key))
+            .verify(&root, default));
+        assert!(!tree
+            .get_non_membership_proof(b"x")
+            .verify(&root, b"x", default));
+        assert!(!tree.get_range_proof(..).verify(&root, .., default));
+        assert!(tree.get_range_proof(..).verify(&root, .., scheme));
     }
-}
 
-fn rotate_right(mut a: Box<Node>, version: u64) -> Box<Node> {
-    let mut b = a.left.take().unwrap();
-    let t2 = b.right.take();
+    #[test]
+    fn test_versioned_get() {
+        let mut tree = IAVLTree::new();
+        for i in 0u32..10 {
+            tree.set(i.to_be_bytes().to_vec(), b"value1".to_vec());
+        }
+        let hash1 = tree.save_version().to_vec();
 
-    a.left = t2;
-    a.update_height_size();
+        tree.set(3u32.to_be_bytes().to_vec(), b"value2".to_vec());
+        tree.remove(&5u32.to_be_bytes());
+        tree.save_version();
 
-    b.mutate(version);
-    b.right = Some(a);
-    b.update_height_size();
+        assert_eq!(
+            tree.get_versioned(1, &3u32.to_be_bytes()),
+            Some(b"value1".as_ref())
+        );
+        assert_eq!(
+            tree.get_versioned(2, &3u32.to_be_bytes()),
+            Some(b"value2".as_ref())
+        );
+        assert_eq!(
+            tree.get_versioned(1, &5u32.to_be_bytes()),
+            Some(b"value1".as_ref())
+        );
+        assert_eq!(tree.get_versioned(2, &5u32.to_be_bytes()), None);
+        assert_eq!(tree.get_versioned(3, &3u32.to_be_bytes()), None);
 
-    b
-}
+        // pending changes don't affect the saved versions
+        tree.set(3u32.to_be_bytes().to_vec(), b"value3".to_vec());
+        assert_eq!(tree.get(&3u32.to_be_bytes()), Some(b"value3".as_ref()));
+        assert_eq!(
+            tree.get_versioned(2, &3u32.to_be_bytes()),
+            Some(b"value2".as_ref())
+        );
 
-fn rotate_left(mut a: Box<Node>, version: u64) -> Box<Node> {
-    let mut b = a.right.take().unwrap();
-    let t2 = b.left.take();
+        // the saved root keeps its hash
+        let root1 = tree.versions[&1].as_ref().unwrap();
+        assert_eq!(root1.hash.unwrap().to_vec(), hash1);
+    }
 
-    a.right = t2;
-    a.update_height_size();
+    #[test]
+    fn test_prune_versions() {
+        let mut tree = IAVLTree::new();
+        for i in 0u32..5 {
+            tree.set(b"key".to_vec(), i.to_be_bytes().to_vec());
+            tree.set(i.to_be_bytes().to_vec(), i.to_be_bytes().to_vec());
+            tree.save_version();
+        }
+        let root1 = match tree.versions[&1].as_ref().unwrap() {
+            NodeRef::Loaded(node) => Arc::downgrade(node),
+            NodeRef::Stored(_) => unreachable!(),
+        };
 
-    b.mutate(version);
-    b.left = Some(a);
-    b.update_height_size();
+        assert!(tree.prune_to(6).is_err());
+        tree.prune_to(3).unwrap();
 
-    b
-}
+        assert!(root1.upgrade().is_none());
+        for version in 1..=2 {
+            assert_eq!(tree.get_versioned(version, b"key"), None);
+        }
+        for version in 3..=5u32 {
+            assert_eq!(
+                tree.get_versioned(version.into(), b"key"),
+                Some((version - 1).to_be_bytes().as_ref())
+            );
+        }
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use hexhex::hex_literal;
+    #[test]
+    fn test_from_sorted() {
+        for n in (0u32..50).chain([1000]) {
+            let pairs = (0..n)
+                .map(|i| (i.to_be_bytes().to_vec(), i.to_le_bytes().to_vec()))
+                .collect::<Vec<_>>();
+
+            let mut tree = IAVLTree::new();
+            tree.version = 99;
+            for (key, value) in pairs.clone() {
+                tree.set(key, value);
+            }
+            let expected = tree.save_version().to_vec();
+
+            let mut bulk: IAVLTree = IAVLTree::from_sorted(pairs, 100);
+            assert_eq!(bulk.version(), 100);
+            assert_eq!(bulk.root_hash().to_vec(), expected);
+            assert_eq!(bulk.range(..).count(), n as usize);
+        }
+    }
 
     #[test]
-    fn test_basic_operations() {
+    #[should_panic(expected = "version 0 can't be saved")]
+    fn test_from_sorted_version_zero() {
+        IAVLTree::<Sha256>::from_sorted([(b"key".to_vec(), b"value".to_vec())], 0);
+    }
+
+    #[test]
+    fn test_write_batch() {
+        // building from a sorted batch is O(n), while inserting one by one is O(n log n)
+        // with rebalancing on each insertion, the root hashes must be the same.
+        let batch = (0u32..10000)
+            .map(|i| (i.to_be_bytes().to_vec(), Some(i.to_le_bytes().to_vec())))
+            .collect::<Vec<_>>();
+
         let mut tree = IAVLTree::new();
-        assert_eq!(tree.root_hash(), &*EMPTY_HASH);
+        for (key, value) in batch.clone() {
+            tree.set(key, value.unwrap());
+        }
+        let expected = tree.save_version().to_vec();
 
-        tree.set(b"key1".to_vec(), b"value1".to_vec());
-        assert_eq!(tree.get(b"key1"), Some(b"value1".as_ref()));
-        let root1 = tree.save_version().to_vec();
+        let mut batched = IAVLTree::new();
+        batched.write_batch(batch.clone());
+        assert_eq!(batched.save_version().to_vec(), expected);
 
-        tree.set(b"key2".to_vec(), b"value2".to_vec());
-        assert_eq!(tree.get(b"key2"), Some(b"value2".as_ref()));
+        // unsorted batch and batch on non-empty tree are applied one by one
+        let mut batch = batch;
+        batch.swap(0, 1);
+        batch.push((0u32.to_be_bytes().to_vec(), None));
+        let mut tree = IAVLTree::new();
+        for (key, value) in batch.clone() {
+            match value {
+                Some(value) => tree.set(key, value),
+                None => tree.remove(&key),
+            }
+        }
+        let expected = tree.save_version().to_vec();
 
-        let root2 = tree.save_version().to_vec();
-        assert_ne!(root1, root2);
+        let mut batched = IAVLTree::new();
+        batched.write_batch(batch);
+        assert_eq!(batched.save_version().to_vec(), expected);
 
-        tree.remove(b"key2");
-        assert_eq!(tree.get(b"key2"), None);
+        let mut tree = IAVLTree::new();
+        tree.set(b"key1".to_vec(), b"value1".to_vec());
+        tree.set(b"key2".to_vec(), b"value2".to_vec());
+        let expected = tree.save_version().to_vec();
 
-        let root3 = tree.save_version().to_vec();
-        assert_eq!(root1, root3);
+        let mut batched = IAVLTree::new();
+        batched.set(b"key1".to_vec(), b"value1".to_vec());
+        batched.write_batch(vec![(b"key2".to_vec(), Some(b"value2".to_vec()))]);
+        assert_eq!(batched.save_version().to_vec(), expected);
     }
 
+    #[cfg(feature = "rayon")]
     #[test]
-    fn test_update_value() {
+    fn test_parallel_hash() {
+        use crate::node::{hash_inner, hash_leaf, HashScheme};
+
+        fn serial_hash(node: &Node) -> Output<Sha256> {
+            if node.is_leaf() {
+                hash_leaf::<Sha256>(HashScheme::default(), node.version, &node.key, &node.value)
+            } else {
+                hash_inner::<Sha256>(
+                    HashScheme::default(),
+                    node.height,
+                    node.size,
+                    node.version,
+                    &serial_hash(node.left.as_ref().unwrap()),
+                    &serial_hash(node.right.as_ref().unwrap()),
+                )
+            }
+        }
+
+        // insert in pseudo-random order to build a 100k nodes tree
         let mut tree = IAVLTree::new();
-        tree.set(b"key".to_vec(), b"value1".to_vec());
-        let hash1 = tree.save_version().to_vec();
+        for i in 0u32..50000 {
+            let key = Sha256::digest(i.to_be_bytes());
+            tree.set(key.to_vec(), i.to_be_bytes().to_vec());
+        }
+        assert_eq!(tree.len(), 50000);
 
-        tree.set(b"key".to_vec(), b"value2".to_vec());
-        let hash2 = tree.save_version().to_vec();
+        let expected = serial_hash(tree.root.as_ref().unwrap());
+        assert_eq!(tree.save_version(), &expected);
 
-        assert_ne!(hash1, hash2);
-        assert_eq!(tree.get(b"key"), Some(b"value2".as_ref()));
+        // update part of the tree, the dirty subtrees are rehashed
+        for i in (0u32..50000).step_by(7) {
+            let key = Sha256::digest(i.to_be_bytes());
+            tree.set(key.to_vec(), i.to_le_bytes().to_vec());
+        }
+        let expected = serial_hash(tree.root.as_ref().unwrap());
+        assert_eq!(tree.save_version(), &expected);
     }
 
     #[test]
@@ -295,7 +2402,7 @@ mod tests {
             assert_eq!(value, &i.to_be_bytes());
             let (value, index) = tree.get_with_index(&i.to_be_bytes());
             assert_eq!(value.expect("value exists"), &i.to_be_bytes());
-            assert_eq!(index, i.into());
+            assert_eq!(index, u64::from(i));
         }
     }
 
@@ -348,6 +2455,49 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_tree_range_bounds() {
+        let mut tree = IAVLTree::new();
+        assert_eq!(tree.range(..).next(), None);
+        assert_eq!(tree.range(..).next_back(), None);
+        assert_eq!(tree.range(b"key1".to_vec()..).next(), None);
+
+        tree.set(b"key1".to_vec(), b"value1".to_vec());
+        tree.set(b"key2".to_vec(), b"value2".to_vec());
+        tree.set(b"key3".to_vec(), b"value3".to_vec());
+
+        assert_eq!(
+            tree.range(b"key2".to_vec()..).collect::<Vec<_>>(),
+            vec![
+                (b"key2".as_ref(), b"value2".as_ref()),
+                (b"key3".as_ref(), b"value3".as_ref()),
+            ]
+        );
+
+        assert_eq!(
+            tree.range(b"key2".to_vec()..).rev().collect::<Vec<_>>(),
+            vec![
+                (b"key3".as_ref(), b"value3".as_ref()),
+                (b"key2".as_ref(), b"value2".as_ref()),
+            ]
+        );
+
+        assert_eq!(
+            tree.range(..b"key2".to_vec()).collect::<Vec<_>>(),
+            vec![(b"key1".as_ref(), b"value1".as_ref())]
+        );
+
+        assert_eq!(
+            tree.range(..=b"key2".to_vec()).rev().collect::<Vec<_>>(),
+            vec![
+                (b"key2".as_ref(), b"value2".as_ref()),
+                (b"key1".as_ref(), b"value1".as_ref()),
+            ]
+        );
+
+        assert_eq!(tree.range(b"key4".to_vec()..).next(), None);
+    }
+
     struct KVPair {
         delete: bool,
         key: Vec<u8>,
@@ -370,27 +2520,7 @@ mod tests {
         }
     }
 
-    #[test]
-    fn test_hash_vector() {
-        let ref_hashes = [
-            hex_literal!("6032661ab0d201132db7a8fa1da6a0afe427e6278bd122c301197680ab79ca02"),
-            hex_literal!("457d81f933f53e5cfb90d813b84981aa2604d69939e10c94304d18287ded31f7"),
-            hex_literal!("c7ab142752add0374992261536e502851ce555d243270d3c3c6b77cf31b7945d"),
-            hex_literal!("e54da9407cbca3570d04ad5c3296056a0726467cb06272ffd8ef1b4ae87fb99d"),
-            hex_literal!("8b04490800d6b54fa569715a754b5fafe24fd720f677cab819394cf7ccf8cdec"),
-            hex_literal!("38abd5268374923e6727b14ac5a9bb6611e591d7e316d0a612904062f244e72f"),
-            hex_literal!("d91cf6388eeff3204474bb07b853ab0d7d39163912ac1e610e92f9b178c76922"),
-        ];
-        let ref_hashes_initial_version = [
-            hex_literal!("053bb7cf59993f3c4f3c95f76037bb597cfe2fe662a7c5a49ecb06acb3eaf672"),
-            hex_literal!("ac4d11d9d685c38401059dcc097b3780df1d34280a6d291d729d7e98f41f07c6"),
-            hex_literal!("49d572c2cf09b4de3167c3d61a38137b3b2f0caf2dfc431ef79ea7ca8e0d701e"),
-            hex_literal!("e13b7fdbf9ddc7537c70f00f2f8477e422a1a6e12768cf03edd2b329a310e875"),
-            hex_literal!("30f964138a9c8e4b8ee933c57982a58c99ec31948f2dbb3b0eafbfac2d578c13"),
-            hex_literal!("c379fbf3f3e83d0a92cfb7c6fc43566a8d1aad25a00de8cae61e7685176cb8bf"),
-            hex_literal!("5712608bf5ccb32dd3231bc6e2fc2df427083eca892c7e1766312190fc3ef715"),
-        ];
-
+    fn hash_vector_changesets() -> Vec<Vec<KVPair>> {
         let mut changesets = vec![
             vec![insert(b"hello", b"world")],
             vec![insert(b"hello", b"world1"), insert(b"hello1", b"world1")],
@@ -419,10 +2549,33 @@ mod tests {
             changes.push(delete(format!("hello{:02}", i).as_bytes()));
         }
         changesets.push(changes);
+        changesets
+    }
+
+    #[test]
+    fn test_hash_vector() {
+        let ref_hashes = [
+            hex_literal!("6032661ab0d201132db7a8fa1da6a0afe427e6278bd122c301197680ab79ca02"),
+            hex_literal!("457d81f933f53e5cfb90d813b84981aa2604d69939e10c94304d18287ded31f7"),
+            hex_literal!("c7ab142752add0374992261536e502851ce555d243270d3c3c6b77cf31b7945d"),
+            hex_literal!("e54da9407cbca3570d04ad5c3296056a0726467cb06272ffd8ef1b4ae87fb99d"),
+            hex_literal!("8b04490800d6b54fa569715a754b5fafe24fd720f677cab819394cf7ccf8cdec"),
+            hex_literal!("38abd5268374923e6727b14ac5a9bb6611e591d7e316d0a612904062f244e72f"),
+            hex_literal!("d91cf6388eeff3204474bb07b853ab0d7d39163912ac1e610e92f9b178c76922"),
+        ];
+        let ref_hashes_initial_version = [
+            hex_literal!("053bb7cf59993f3c4f3c95f76037bb597cfe2fe662a7c5a49ecb06acb3eaf672"),
+            hex_literal!("ac4d11d9d685c38401059dcc097b3780df1d34280a6d291d729d7e98f41f07c6"),
+            hex_literal!("49d572c2cf09b4de3167c3d61a38137b3b2f0caf2dfc431ef79ea7ca8e0d701e"),
+            hex_literal!("e13b7fdbf9ddc7537c70f00f2f8477e422a1a6e12768cf03edd2b329a310e875"),
+            hex_literal!("30f964138a9c8e4b8ee933c57982a58c99ec31948f2dbb3b0eafbfac2d578c13"),
+            hex_literal!("c379fbf3f3e83d0a92cfb7c6fc43566a8d1aad25a00de8cae61e7685176cb8bf"),
+            hex_literal!("5712608bf5ccb32dd3231bc6e2fc2df427083eca892c7e1766312190fc3ef715"),
+        ];
 
+        let changesets = hash_vector_changesets();
         let mut tree = IAVLTree::new();
-        let mut tree_initial_version = IAVLTree::new();
-        tree_initial_version.version = 100 - 1;
+        let mut tree_initial_version = IAVLTree::<Sha256>::with_initial_version(100);
         for (i, changes) in changesets.iter().enumerate() {
             for change in changes {
                 if change.delete {
@@ -440,4 +2593,47 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn test_hash_vector_sha512() {
+        let ref_hashes = [
+            hex_literal!(
+                "4de177c9716fdce24c36db19049e177e38c7752aefc645868ad4e5f0b5bb229035c13288f6476f8730b6bf02c0c3c900245742467c3b3e9e7eaef5135ab017a7"
+            ),
+            hex_literal!(
+                "4d5a1c1f67cc6238073f18ba8228c7c30d3eeb9de3a30c4583904a63d506e0c488d2f27c78aebc9447a29d1f8729e4ef61287e9730b89d26ad2e7d09bd07bd53"
+            ),
+            hex_literal!(
+                "45bf7a9133e0fd6968d93dd48218d5c5cc07aecc977dd7c579b9760370931658f2ef1955a71a2297fe3b2805e5dfa14333dfab7533c180bb4458ec6ff563fb1d"
+            ),
+            hex_literal!(
+                "737ec0c3d149caa41f707cb25a24835c334d355fe7d11e78f78f82aa8d141c4aac7ad8d27bde227652c45992793efe5d1e637245b6be26f7a52213ae3db8d3ba"
+            ),
+            hex_literal!(
+                "597a82893c7e90756ece9f6aba20358cf75a870fb7ee2fc5a22c12f2e321414508fdf2d1fced54a2d77ca51f85639f7b021bbf429057ec821355c38c776be8b8"
+            ),
+            hex_literal!(
+                "c6776066c35277853213321cdf8d3a1dfb40c668269369d193c225b955181ac3c0c697f6412eea96532a0595ab42105c71b8bdccaf4d4a1943ba010427413068"
+            ),
+            hex_literal!(
+                "928ce800737373738b1d3d9c8e5d4fdc5c607b339962b9795c29b3cc189e130eb4dd64dd018f19603f7afa2d14bcbe9fae51b375d2feff7cac3da012464b5679"
+            ),
+        ];
+
+        let mut tree = IAVLTree::<Sha512>::with_digest();
+        let mut tree_sha256 = IAVLTree::new();
+        for (i, changes) in hash_vector_changesets().into_iter().enumerate() {
+            for change in changes {
+                if change.delete {
+                    tree.remove(&change.key);
+                    tree_sha256.remove(&change.key);
+                } else {
+                    tree.set(change.key.clone(), change.value.clone());
+                    tree_sha256.set(change.key, change.value);
+                }
+            }
+            assert_eq!(tree.save_version().to_vec(), ref_hashes[i]);
+            assert_ne!(tree.root_hash()[..32], tree_sha256.save_version().to_vec());
+        }
+    }
 }
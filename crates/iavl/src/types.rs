@@ -1,18 +1,74 @@
-use std::ops::RangeBounds;
+use std::ops::{Bound, RangeBounds};
 
 pub type ChangeItem = (Vec<u8>, Option<Vec<u8>>);
 
+pub type DynIterator<'a> = Box<dyn DoubleEndedIterator<Item = (Vec<u8>, Vec<u8>)> + 'a>;
+
+/// The object-safe part of the store interface, can be used as `dyn KVStore`.
+/// The empty key is a valid key, ordered before all the others.
 pub trait KVStore {
     fn get(&self, key: &[u8]) -> Option<&[u8]>;
+
+    /// Get an owned copy of the value, prefer it when the value needs to outlive the borrow of
+    /// the store, like sending it to another thread or returning it from a lock guard.
+    fn get_owned(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.get(key).map(<[u8]>::to_vec)
+    }
+
+    /// Get the values of the keys sorted in ascending order, in the same order, the default
+    /// implementation looks up each of them, a tree descends once for the keys sharing a path.
+    fn get_sorted(&self, keys: &[&[u8]]) -> Vec<Option<&[u8]>> {
+        keys.iter().map(|key| self.get(key)).collect()
+    }
+
     fn set(&mut self, key: Vec<u8>, value: Vec<u8>);
     fn remove(&mut self, key: &[u8]);
-    fn range<R>(&self, bounds: R) -> impl DoubleEndedIterator<Item = (&[u8], &[u8])>
-    where
-        R: RangeBounds<Vec<u8>> + Clone;
+
+    /// Insert the key only if it don't exist, returns whether it's inserted, the existing value
+    /// is left unchanged.
+    fn set_if_absent(&mut self, key: Vec<u8>, value: Vec<u8>) -> bool {
+        if self.get(&key).is_some() {
+            return false;
+        }
+        self.set(key, value);
+        true
+    }
+
+    /// Apply the new value, or remove the key if `None`, only if the current value equals the
+    /// expected one, `None` expects the key to be absent, returns whether it's applied.
+    fn compare_and_swap(
+        &mut self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<Vec<u8>>,
+    ) -> bool {
+        if self.get(key) != expected {
+            return false;
+        }
+        match new {
+            Some(value) => self.set(key.to_vec(), value),
+            None => self.remove(key),
+        }
+        true
+    }
+
+    /// Remove the key and return the value before removal.
+    fn remove_taking(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        let value = self.get_owned(key);
+        self.remove(key);
+        value
+    }
+
+    /// Iterate the key-value pairs within the bounds, returns owned pairs in a boxed iterator,
+    /// use `KVStoreExt::range` to iterate borrowed pairs without the boxing.
+    fn range_dyn(&self, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>) -> DynIterator<'_>;
 
     /// Write a batch of operations to the store.
     /// The default implementation just applies each operation individually.
-    fn write_batch(&mut self, batch: impl IntoIterator<Item = ChangeItem>) {
+    fn write_batch(&mut self, batch: impl IntoIterator<Item = ChangeItem>)
+    where
+        Self: Sized,
+    {
         for (key, value) in batch {
             match value {
                 Some(value) => self.set(key, value),
@@ -21,3 +77,123 @@ pub trait KVStore {
         }
     }
 }
+
+/// The generic part of the store interface.
+pub trait KVStoreExt: KVStore {
+    fn range<R>(&self, bounds: R) -> impl DoubleEndedIterator<Item = (&[u8], &[u8])>
+    where
+        R: RangeBounds<Vec<u8>> + Clone;
+
+    /// Iterate the keys within the bounds.
+    fn keys<R>(&self, bounds: R) -> impl DoubleEndedIterator<Item = &[u8]>
+    where
+        R: RangeBounds<Vec<u8>> + Clone,
+    {
+        self.range(bounds).map(|(key, _)| key)
+    }
+
+    /// Iterate the values within the bounds, in the order of the keys.
+    fn values<R>(&self, bounds: R) -> impl DoubleEndedIterator<Item = &[u8]>
+    where
+        R: RangeBounds<Vec<u8>> + Clone,
+    {
+        self.range(bounds).map(|(_, value)| value)
+    }
+
+    /// Iterate the key-value pairs whose keys start with the prefix.
+    fn prefix_iter(&self, prefix: &[u8]) -> impl DoubleEndedIterator<Item = (&[u8], &[u8])> {
+        let end = match prefix_end(prefix) {
+            Some(end) => Bound::Excluded(end),
+            None => Bound::Unbounded,
+        };
+        self.range((Bound::Included(prefix.to_vec()), end))
+    }
+}
+
+/// Forwards to the borrowed store, so a `&mut S` can be passed where a store is taken by value.
+impl<S: KVStore> KVStore for &mut S {
+    fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        (**self).get(key)
+    }
+
+    fn get_owned(&self, key: &[u8]) -> Option<Vec<u8>> {
+        (**self).get_owned(key)
+    }
+
+    fn get_sorted(&self, keys: &[&[u8]]) -> Vec<Option<&[u8]>> {
+        (**self).get_sorted(keys)
+    }
+
+    fn set(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        (**self).set(key, value)
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        (**self).remove(key)
+    }
+
+    fn set_if_absent(&mut self, key: Vec<u8>, value: Vec<u8>) -> bool {
+        (**self).set_if_absent(key, value)
+    }
+
+    fn compare_and_swap(
+        &mut self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<Vec<u8>>,
+    ) -> bool {
+        (**self).compare_and_swap(key, expected, new)
+    }
+
+    fn remove_taking(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        (**self).remove_taking(key)
+    }
+
+    fn range_dyn(&self, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>) -> DynIterator<'_> {
+        (**self).range_dyn(start, end)
+    }
+
+    fn write_batch(&mut self, batch: impl IntoIterator<Item = ChangeItem>) {
+        (**self).write_batch(batch)
+    }
+}
+
+impl<S: KVStoreExt> KVStoreExt for &mut S {
+    fn range<R>(&self, bounds: R) -> impl DoubleEndedIterator<Item = (&[u8], &[u8])>
+    where
+        R: RangeBounds<Vec<u8>> + Clone,
+    {
+        (**self).range(bounds)
+    }
+
+    fn keys<R>(&self, bounds: R) -> impl DoubleEndedIterator<Item = &[u8]>
+    where
+        R: RangeBounds<Vec<u8>> + Clone,
+    {
+        (**self).keys(bounds)
+    }
+
+    fn values<R>(&self, bounds: R) -> impl DoubleEndedIterator<Item = &[u8]>
+    where
+        R: RangeBounds<Vec<u8>> + Clone,
+    {
+        (**self).values(bounds)
+    }
+
+    fn prefix_iter(&self, prefix: &[u8]) -> impl DoubleEndedIterator<Item = (&[u8], &[u8])> {
+        (**self).prefix_iter(prefix)
+    }
+}
+
+// prefix_end returns the smallest key larger than all the keys with the prefix,
+// `None` if the prefix is empty or all `0xff` bytes, which has no upper bound.
+pub(crate) fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}